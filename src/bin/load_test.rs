@@ -0,0 +1,167 @@
+// Load generator: plays N headless clients against a server over UDP and prints
+// what they received, to measure how many players one instance supports.
+//
+//     cargo run --release --bin load_test -- --clients 200 --duration 30 --server 127.0.0.1:3000
+//
+// The server takes at most MAX_PLAYERS_PER_IP players per address. Against a server
+// on IPv4 loopback every client binds an address of its own in 127.0.0.0/8, so any
+// number of them get in; against any other server they all share the address of
+// this machine, and asking for more clients than the limit fails right away. Run
+// the load test on the server's host to go beyond it.
+
+use slither_io_server::client::headless::{self, ClientConfig, ClientStats};
+use slither_io_server::game::constants as CONST;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::process;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration};
+
+struct Options {
+    clients: usize,
+    duration: u64,
+    server: SocketAddr,
+    // ms between two clients joining
+    ramp: u64,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: load_test [--clients N] [--duration SECONDS] [--server ADDR:PORT] [--ramp MS]");
+    process::exit(1);
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        clients: 10,
+        duration: 30,
+        server: "127.0.0.1:3000".parse().unwrap(),
+        ramp: 20,
+    };
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        let [flag, value] = pair else { usage() };
+        match flag.as_str() {
+            "--clients" => options.clients = value.parse().unwrap_or_else(|_| usage()),
+            "--duration" => options.duration = value.parse().unwrap_or_else(|_| usage()),
+            "--server" => options.server = value.parse().unwrap_or_else(|_| usage()),
+            "--ramp" => options.ramp = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    if !has_own_addresses(&options.server) && options.clients > CONST::MAX_PLAYERS_PER_IP {
+        eprintln!(
+            "Error: the server takes at most {} players per IP, and clients share one against {}. \
+             Run the load test on the server's host against 127.0.0.1.",
+            CONST::MAX_PLAYERS_PER_IP,
+            options.server.ip()
+        );
+        process::exit(1);
+    }
+    options
+}
+
+// Whether every client can have an address of its own, out of reach of the limit
+// of players per IP
+fn has_own_addresses(server: &SocketAddr) -> bool {
+    matches!(server.ip(), IpAddr::V4(ip) if ip.is_loopback())
+}
+
+// The server limits players per IP. On loopback every client gets an address of its
+// own in 127.0.0.0/8; elsewhere they all share one and the limit applies.
+fn bind_address(server: &SocketAddr, client: usize) -> SocketAddr {
+    match server.ip() {
+        IpAddr::V4(_) if has_own_addresses(server) => {
+            let n = client as u32 + 2;
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, (n >> 16) as u8, (n >> 8) as u8, n as u8)), 0)
+        }
+        IpAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn print_summary(options: &Options, stats: &[ClientStats], failed: usize) {
+    let seconds = options.duration as f64;
+    let connected = stats.iter().filter(|s| s.connected).count();
+    let bytes_received: u64 = stats.iter().map(|s| s.bytes_received).sum();
+    let bytes_sent: u64 = stats.iter().map(|s| s.bytes_sent).sum();
+    let datagrams: u64 = stats.iter().map(|s| s.datagrams_received).sum();
+    let messages: u64 = stats.iter().map(|s| s.messages_received).sum();
+    let snapshots: u64 = stats.iter().map(|s| s.snapshots_received).sum();
+    let deaths: u64 = stats.iter().map(|s| s.deaths).sum();
+
+    let mut rtts: Vec<f64> = stats.iter().flat_map(|s| s.rtt_samples.iter().copied()).collect();
+    rtts.sort_by(f64::total_cmp);
+    let mut connect_times: Vec<f64> = stats.iter().filter_map(|s| s.connect_time).collect();
+    connect_times.sort_by(f64::total_cmp);
+
+    let per_client = |total: u64| total as f64 / f64::max(1.0, connected as f64) / seconds;
+
+    println!("Clients:     {} connected, {} never got their snake, {} failed to start", connected, stats.len() - connected, failed);
+    println!("Connect:     p50 {:.1} ms, p95 {:.1} ms", percentile(&connect_times, 0.5), percentile(&connect_times, 0.95));
+    println!("Received:    {} bytes, {} datagrams, {} messages, {} snapshots", bytes_received, datagrams, messages, snapshots);
+    println!("Per client:  {:.0} B/s down, {:.0} B/s up, {:.1} snapshots/s", per_client(bytes_received), per_client(bytes_sent), per_client(snapshots));
+    println!(
+        "Round trip:  p50 {:.1} ms, p95 {:.1} ms, p99 {:.1} ms, max {:.1} ms ({} pings)",
+        percentile(&rtts, 0.5),
+        percentile(&rtts, 0.95),
+        percentile(&rtts, 0.99),
+        rtts.last().copied().unwrap_or(0.0),
+        rtts.len()
+    );
+    println!("Deaths:      {}", deaths);
+}
+
+fn main() {
+    let options = parse_options();
+    println!(
+        "Running {} client(s) against {} for {} s",
+        options.clients,
+        options.server,
+        options.duration
+    );
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let mut clients = JoinSet::new();
+            for i in 0..options.clients {
+                let config = ClientConfig {
+                    server: options.server,
+                    bind: bind_address(&options.server, i),
+                    name: format!("Load{}", i),
+                    duration: Duration::from_secs(options.duration),
+                    input_delay: Duration::from_millis(50),
+                    ping_delay: Duration::from_millis(1000),
+                    boost_chance: 0.01,
+                };
+                clients.spawn(headless::run(config));
+                time::sleep(Duration::from_millis(options.ramp)).await;
+            }
+
+            let mut stats = Vec::new();
+            let mut failed = 0;
+            while let Some(result) = clients.join_next().await {
+                match result {
+                    Ok(Ok(client_stats)) => stats.push(client_stats),
+                    Ok(Err(e)) => {
+                        eprintln!("Client failed: {}", e);
+                        failed += 1;
+                    }
+                    Err(_) => failed += 1,
+                }
+            }
+
+            print_summary(&options, &stats, failed);
+        });
+}
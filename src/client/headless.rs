@@ -0,0 +1,294 @@
+// Headless client speaking the game protocol over UDP, for load tests and other
+// tools. It plays like a bored human: wanders the mouse around, boosts now and then
+// and sets its name, while answering everything the server expects an answer to
+// (reliable packet acks, snapshot acks and pings) and recording what it receives.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration};
+use crate::game::constants as CONST;
+
+pub struct ClientConfig {
+    pub server: SocketAddr,
+    // Local address to send from, the server limits players per IP
+    pub bind: SocketAddr,
+    pub name: String,
+    pub duration: Duration,
+    pub input_delay: Duration,
+    pub ping_delay: Duration,
+    // Chance per input to toggle boosting
+    pub boost_chance: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct ClientStats {
+    pub connected: bool,
+    // ms between the connect message and the own snake
+    pub connect_time: Option<f64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub datagrams_received: u64,
+    pub messages_received: u64,
+    pub snapshots_received: u64,
+    // Round trip times of the pings answered, in ms
+    pub rtt_samples: Vec<f64>,
+    pub deaths: u64,
+}
+
+pub struct HeadlessClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    started: Instant,
+    pub stats: ClientStats,
+    // Fragments received so far, by message id
+    fragments: HashMap<u32, Vec<Option<Vec<u8>>>>,
+    input_seq: u32,
+    mouse_angle: f64,
+    boosting: bool,
+}
+
+impl HeadlessClient {
+    pub async fn bind(config: &ClientConfig) -> io::Result<HeadlessClient> {
+        let socket = UdpSocket::bind(config.bind).await?;
+        Ok(HeadlessClient {
+            socket,
+            server: config.server,
+            started: Instant::now(),
+            stats: ClientStats::default(),
+            fragments: HashMap::new(),
+            input_seq: 0,
+            mouse_angle: rand::random_range(0.0..std::f64::consts::TAU),
+            boosting: false,
+        })
+    }
+
+    fn now_ms(&self) -> f64 {
+        self.started.elapsed().as_secs_f64() * 1000.0
+    }
+
+    pub async fn send(&mut self, message: &str) {
+        if self.socket.send_to(message.as_bytes(), self.server).await.is_ok() {
+            self.stats.bytes_sent += message.len() as u64;
+        }
+    }
+
+    // Play for the configured duration and return what was recorded
+    pub async fn play(mut self, config: &ClientConfig) -> ClientStats {
+        let deadline = time::Instant::now() + config.duration;
+        let mut input_interval = time::interval(config.input_delay);
+        let mut ping_interval = time::interval(config.ping_delay);
+        let mut buf = vec![0u8; 65536];
+
+        self.send("0").await;
+
+        loop {
+            tokio::select! {
+                _ = time::sleep_until(deadline) => break,
+                received = self.socket.recv_from(&mut buf) => {
+                    if let Ok((size, from)) = received {
+                        if from == self.server {
+                            let datagram = buf[..size].to_vec();
+                            self.receive(&datagram, config).await;
+                        }
+                    }
+                }
+                _ = input_interval.tick(), if self.stats.connected => self.send_input(config).await,
+                _ = ping_interval.tick(), if self.stats.connected => {
+                    let ping = format!("13,{}", self.now_ms() as u64);
+                    self.send(&ping).await;
+                }
+            }
+        }
+
+        self.send("15").await;
+        self.stats
+    }
+
+    async fn send_input(&mut self, config: &ClientConfig) {
+        let window = 1000.0;
+        self.mouse_angle += rand::random_range(-0.3..0.3);
+        self.input_seq += 1;
+        let input = format!(
+            "2,{:.1},{:.1},{},{},{}",
+            window / 2.0 + self.mouse_angle.cos() * window / 4.0,
+            window / 2.0 + self.mouse_angle.sin() * window / 4.0,
+            window,
+            window,
+            self.input_seq
+        );
+        self.send(&input).await;
+
+        if rand::random_bool(config.boost_chance) {
+            self.boosting = !self.boosting;
+            self.input_seq += 1;
+            let boost = format!("{},{}", if self.boosting { 10 } else { 11 }, self.input_seq);
+            self.send(&boost).await;
+        }
+    }
+
+    async fn receive(&mut self, datagram: &[u8], config: &ClientConfig) {
+        self.stats.bytes_received += datagram.len() as u64;
+        self.stats.datagrams_received += 1;
+
+        let Some(data) = self.reassemble(datagram) else {
+            return;
+        };
+        let text = String::from_utf8_lossy(&data).to_string();
+        for message in text.split(CONST::COMM_START_NEW_MESS).filter(|message| !message.is_empty()) {
+            self.stats.messages_received += 1;
+            self.handle_message(message, config).await;
+        }
+    }
+
+    // Put fragmented messages back together, see network::fragment
+    fn reassemble(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        let header = format!("{}{}", CONST::COMM_START_NEW_MESS, CONST::COMM_FRAGMENT);
+        if !datagram.starts_with(header.as_bytes()) {
+            return Some(datagram.to_vec());
+        }
+
+        let fields: Vec<&[u8]> = datagram[header.len()..].splitn(4, |&b| b == b',').collect();
+        if fields.len() < 4 {
+            return None;
+        }
+        let number = |field: &[u8]| std::str::from_utf8(field).ok()?.parse::<usize>().ok();
+        let (id, index, count) = (number(fields[0])? as u32, number(fields[1])?, number(fields[2])?);
+        if index >= count {
+            return None;
+        }
+
+        let parts = self.fragments.entry(id).or_insert_with(|| vec![None; count]);
+        if parts.len() != count {
+            return None;
+        }
+        parts[index] = Some(fields[3].to_vec());
+        if parts.iter().any(|part| part.is_none()) {
+            return None;
+        }
+        self.fragments.remove(&id).map(|parts| parts.into_iter().flatten().flatten().collect())
+    }
+
+    async fn handle_message(&mut self, message: &str, config: &ClientConfig) {
+        let fields: Vec<&str> = message.split(',').collect();
+        match fields[0] {
+            "1" if !self.stats.connected => {
+                self.stats.connected = true;
+                self.stats.connect_time = Some(self.now_ms());
+                let name = format!("9,{}", config.name);
+                self.send(&name).await;
+            }
+            "8" => self.stats.deaths += 1,
+            "20" => {
+                self.stats.snapshots_received += 1;
+                if let Some(seq) = fields.get(1) {
+                    let ack = format!("12,{}", seq);
+                    self.send(&ack).await;
+                }
+            }
+            "25" => {
+                if let Some(Ok(sent)) = fields.get(1).map(|sent| sent.parse::<f64>()) {
+                    let rtt = self.now_ms() - sent;
+                    self.stats.rtt_samples.push(rtt);
+                }
+            }
+            "26" => {
+                if let Some(server_time) = fields.get(1) {
+                    let pong = format!("14,{}", server_time);
+                    self.send(&pong).await;
+                }
+            }
+            "30" => {
+                if let Some(seq) = fields.get(1) {
+                    let ack = format!("17,{}", seq);
+                    self.send(&ack).await;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Connect a client and play with it
+pub async fn run(config: ClientConfig) -> io::Result<ClientStats> {
+    let client = HeadlessClient::bind(&config).await?;
+    Ok(client.play(&config).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::fragment;
+
+    fn config(server: SocketAddr) -> ClientConfig {
+        ClientConfig {
+            server,
+            bind: "127.0.0.1:0".parse().unwrap(),
+            name: "tester".to_string(),
+            duration: Duration::from_secs(1),
+            input_delay: Duration::from_millis(50),
+            ping_delay: Duration::from_millis(50),
+            boost_chance: 0.0,
+        }
+    }
+
+    // Wait for the next message from the client starting with a prefix, skipping the others
+    async fn expect(server: &UdpSocket, prefix: &str) -> (String, SocketAddr) {
+        let mut buf = vec![0u8; 65536];
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                let (size, from) = server.recv_from(&mut buf).await.unwrap();
+                let message = String::from_utf8_lossy(&buf[..size]).to_string();
+                if message.starts_with(prefix) {
+                    return (message, from);
+                }
+            }
+        }).await.expect("timed out")
+    }
+
+    #[tokio::test]
+    async fn fragments_are_put_back_together() {
+        let mut client = HeadlessClient::bind(&config("127.0.0.1:1".parse().unwrap())).await.unwrap();
+        let data: Vec<u8> = (0..3000).map(|i| b'0' + (i % 10) as u8).collect();
+        let mut fragments = fragment::split(data.clone());
+        assert!(fragments.len() > 1);
+
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.iter().rev() {
+            assert_eq!(client.reassemble(fragment), None);
+        }
+        assert_eq!(client.reassemble(&last), Some(data));
+        assert_eq!(client.reassemble(b"$2,1,2"), Some(b"$2,1,2".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn clients_answer_what_the_server_expects() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = config(server.local_addr().unwrap());
+        let client = HeadlessClient::bind(&config).await.unwrap();
+        let playing = tokio::spawn(async move { client.play(&config).await });
+
+        let (_, client_addr) = expect(&server, "0").await;
+        server.send_to(b"$1,0,tester,0,0,0,0", client_addr).await.unwrap();
+        assert_eq!(expect(&server, "9,").await.0, "9,tester");
+        expect(&server, "2,").await;
+
+        server.send_to(b"$30,5$8", client_addr).await.unwrap();
+        assert_eq!(expect(&server, "17,").await.0, "17,5");
+        server.send_to(b"$20,7,0,1,0,0$2,1.0,2.0", client_addr).await.unwrap();
+        assert_eq!(expect(&server, "12,").await.0, "12,7");
+        server.send_to(b"$26,1234", client_addr).await.unwrap();
+        assert_eq!(expect(&server, "14,").await.0, "14,1234");
+        let (ping, _) = expect(&server, "13,").await;
+        server.send_to(format!("$25,{}", &ping[3..]).as_bytes(), client_addr).await.unwrap();
+
+        expect(&server, "15").await;
+        let stats = playing.await.unwrap();
+        assert!(stats.connected);
+        assert_eq!(stats.deaths, 1);
+        assert_eq!(stats.snapshots_received, 1);
+        assert_eq!(stats.rtt_samples.len(), 1);
+    }
+}
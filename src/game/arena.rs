@@ -0,0 +1,449 @@
+// Arenas: independent games hosted by the same server, each with its own map, player
+// cap and game loop. Players, baits and bots belong to one arena and only ever meet
+// what is in the same arena. The arenas listed in ARENAS are open to everyone; a
+// client can also ask for a named room with "0,room=<name>", created on first use,
+// or create a private room with "0,create" that only clients presenting its join
+// code (and password, if it has one) get into. Rooms close once no human is left.
+// Arenas with teams play in team mode, see teams, arenas with a round duration play
+// timed rounds, see rounds, and arenas with a zone shrink duration battle royale
+// rounds, see royale.
+
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use rand::prelude::*;
+use crate::game::clock;
+use crate::game::collision::Rect;
+use crate::game::constants as CONST;
+use crate::game::royale;
+use crate::game::shape::{self, Border, Shape};
+use crate::game::bots;
+use crate::models::player;
+
+pub struct Arena {
+    pub id: usize,
+    pub name: String,
+    // Bounding box and shape of the playable area, and what happens to snakes
+    // reaching its edges
+    pub map: Rect,
+    pub shape: Shape,
+    pub border: Border,
+    // Classic snake rules: a head running into its own body dies
+    pub self_collision: bool,
+    pub max_players: usize,
+    pub max_baits: usize,
+    // Joined by connections that do not ask for a room
+    pub public: bool,
+    // Number of teams in team mode, 0 for everyone against everyone
+    pub teams: usize,
+    // Private rooms are only joined with their code, and password if any
+    pub code: Option<String>,
+    pub password: Option<String>,
+    // Length of a round in seconds, 0 to play forever
+    pub round_duration: u64,
+    // Current round and when it started, in server time
+    pub round: u32,
+    pub round_started: u64,
+    // Seconds the battle royale zone takes to shrink, 0 outside battle royale, and
+    // the center of the final zone for the current round
+    pub zone_shrink_duration: u64,
+    pub zone_center: (f64, f64),
+    // Game loop tick of this arena
+    pub tick: u64,
+}
+
+impl Clone for Arena {
+    fn clone(&self) -> Self {
+        Arena {
+            id: self.id,
+            name: self.name.clone(),
+            map: Rect {
+                top: self.map.top,
+                left: self.map.left,
+                right: self.map.right,
+                bottom: self.map.bottom,
+            },
+            shape: self.shape,
+            border: self.border,
+            self_collision: self.self_collision,
+            max_players: self.max_players,
+            max_baits: self.max_baits,
+            public: self.public,
+            teams: self.teams,
+            code: self.code.clone(),
+            password: self.password.clone(),
+            round_duration: self.round_duration,
+            round: self.round,
+            round_started: self.round_started,
+            zone_shrink_duration: self.zone_shrink_duration,
+            zone_center: self.zone_center,
+            tick: self.tick,
+        }
+    }
+}
+
+// How an arena plays, chosen in ARENAS or by whoever creates a private room
+pub struct Settings {
+    pub width: f64,
+    pub height: f64,
+    pub shape: Shape,
+    pub border: Border,
+    pub self_collision: bool,
+    pub max_players: usize,
+    // Baits for the same area, in percent of the original map
+    pub bait_density: f64,
+    pub teams: usize,
+    // Seconds, 0 for no rounds
+    pub round_duration: u64,
+    // Seconds, 0 for no battle royale
+    pub zone_shrink_duration: u64,
+    pub password: Option<String>,
+}
+
+// Where a connection asks to go
+pub enum Destination {
+    Public,
+    Room(String),
+    Private { code: String, password: Option<String> },
+    Create(Settings),
+}
+
+static ARENAS: Lazy<Mutex<Vec<Option<Arena>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Settings of a room nobody chose anything for
+pub fn room_settings() -> Settings {
+    Settings {
+        width: CONST::ROOM_MAP_WIDTH,
+        height: CONST::ROOM_MAP_HEIGHT,
+        shape: Shape::Rectangle,
+        border: Border::Clamp,
+        self_collision: false,
+        max_players: CONST::ROOM_MAX_PLAYERS,
+        bait_density: 100.0,
+        teams: 0,
+        round_duration: 0,
+        zone_shrink_duration: 0,
+        password: None,
+    }
+}
+
+pub fn create(name: &str, settings: &Settings, public: bool) -> usize {
+    let mut arenas = ARENAS.lock().unwrap();
+    let id = arenas.len();
+
+    let map = Rect {
+        top: CONST::OFFSET_Y,
+        left: CONST::OFFSET_X,
+        right: CONST::OFFSET_X + settings.width,
+        bottom: CONST::OFFSET_Y + settings.height,
+    };
+    let zone_center = royale::pick_center(settings.shape, &map);
+
+    // Bait density relative to the original map
+    let default_area = (CONST::TRUE_MAP_WIDTH - CONST::OFFSET_X) * (CONST::TRUE_MAP_HEIGHT - CONST::OFFSET_Y);
+    let max_baits = CONST::MAX_BAITS as f64 * shape::area(settings.shape, &map) / default_area * settings.bait_density / 100.0;
+
+    arenas.push(Some(Arena {
+        id,
+        name: name.to_string(),
+        map,
+        shape: settings.shape,
+        border: settings.border,
+        self_collision: settings.self_collision,
+        max_players: settings.max_players,
+        max_baits: max_baits as usize,
+        public,
+        teams: settings.teams,
+        code: None,
+        password: settings.password.clone(),
+        round_duration: settings.round_duration,
+        round: 1,
+        round_started: clock::now_ms(),
+        zone_shrink_duration: settings.zone_shrink_duration,
+        zone_center,
+        tick: 0,
+    }));
+    println!(
+        "Arena {} \"{}\" created ({}x{} {} {}, {} players max, {} teams, {} s rounds)",
+        id,
+        name,
+        settings.width,
+        settings.height,
+        settings.shape.name(),
+        settings.border.name(),
+        settings.max_players,
+        settings.teams,
+        settings.round_duration
+    );
+    id
+}
+
+pub fn read(id: usize) -> Option<Arena> {
+    ARENAS.lock().unwrap().get(id).cloned().flatten()
+}
+
+pub fn keys() -> Vec<usize> {
+    let arenas = ARENAS.lock().unwrap();
+    arenas.iter()
+        .enumerate()
+        .filter(|(_, arena)| arena.is_some())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Named rooms and public arenas, private rooms are only found by their code
+pub fn find_by_name(name: &str) -> Option<usize> {
+    let arenas = ARENAS.lock().unwrap();
+    arenas.iter().flatten().find(|arena| arena.code.is_none() && arena.name == name).map(|arena| arena.id)
+}
+
+pub fn find_by_code(code: &str) -> Option<usize> {
+    let arenas = ARENAS.lock().unwrap();
+    arenas.iter().flatten().find(|arena| arena.code.as_deref() == Some(code)).map(|arena| arena.id)
+}
+
+fn new_code() -> String {
+    let mut rng = rand::rng();
+    loop {
+        let code: String = (0..CONST::ROOM_CODE_LENGTH)
+            .map(|_| *CONST::ROOM_CODE_ALPHABET.as_bytes().choose(&mut rng).unwrap() as char)
+            .collect();
+        if find_by_code(&code).is_none() && find_by_name(&code).is_none() {
+            return code;
+        }
+    }
+}
+
+// Create a private room, its join code is also its name
+fn create_private(settings: &Settings) -> Result<usize, &'static str> {
+    if keys().len() >= CONST::MAX_ARENAS {
+        return Err("too many arenas");
+    }
+    let code = new_code();
+    let id = create(&code, settings, false);
+    if let Some(Some(arena)) = ARENAS.lock().unwrap().get_mut(id) {
+        arena.code = Some(code);
+    }
+    Ok(id)
+}
+
+// Remove an arena, its game loop stops on its next tick
+pub fn close(id: usize) {
+    let mut arenas = ARENAS.lock().unwrap();
+    if let Some(arena) = arenas.get_mut(id) {
+        if let Some(closed) = arena.take() {
+            println!("Arena {} \"{}\" closed", id, closed.name);
+        }
+    }
+}
+
+// Players of an arena, bots aside
+pub fn humans_in(id: usize) -> usize {
+    player::keys_in(id).into_iter().filter(|&i| !bots::is_bot(i)).count()
+}
+
+// Rooms nobody plays in any more, bots aside
+pub fn empty_rooms() -> Vec<usize> {
+    keys().into_iter()
+        .filter(|&id| read(id).is_some_and(|arena| !arena.public))
+        .filter(|&id| humans_in(id) == 0)
+        .collect()
+}
+
+// Advance an arena to its next game loop tick and return it
+pub fn advance(id: usize) -> u64 {
+    let mut arenas = ARENAS.lock().unwrap();
+    match arenas.get_mut(id) {
+        Some(Some(arena)) => {
+            arena.tick += 1;
+            arena.tick
+        }
+        _ => 0,
+    }
+}
+
+// Start the next round of an arena and return its number
+pub fn start_round(id: usize) -> u32 {
+    let mut arenas = ARENAS.lock().unwrap();
+    match arenas.get_mut(id) {
+        Some(Some(arena)) => {
+            arena.round += 1;
+            arena.round_started = clock::now_ms();
+            arena.zone_center = royale::pick_center(arena.shape, &arena.map);
+            arena.round
+        }
+        _ => 0,
+    }
+}
+
+pub fn tick(id: usize) -> u64 {
+    read(id).map_or(0, |arena| arena.tick)
+}
+
+// Where a new player goes: the requested or created room, or the least full public
+// arena. Returns the arena and whether it has just been created, so its game loop
+// can be started. Bots do not take up places: the arena drops one when a player
+// joins it full.
+pub fn assign(destination: &Destination) -> Result<(usize, bool), &'static str> {
+    let has_room = |arena: &Arena| humans_in(arena.id) < arena.max_players;
+
+    match destination {
+        Destination::Create(settings) => create_private(settings).map(|id| (id, true)),
+        Destination::Private { code, password } => {
+            let arena = find_by_code(code).and_then(read).ok_or("no such room")?;
+            if arena.password.is_some() && arena.password != *password {
+                return Err("wrong password");
+            }
+            if has_room(&arena) { Ok((arena.id, false)) } else { Err("room full") }
+        }
+        Destination::Room(room) => {
+            // Private rooms are named after their code, which must not be a way in
+            if find_by_code(room).is_some() {
+                return Err("room name taken");
+            }
+            if let Some(id) = find_by_name(room) {
+                return match read(id) {
+                    Some(arena) if has_room(&arena) => Ok((id, false)),
+                    _ => Err("room full"),
+                };
+            }
+            if keys().len() >= CONST::MAX_ARENAS {
+                return Err("too many arenas");
+            }
+            let id = create(room, &room_settings(), false);
+            Ok((id, true))
+        }
+        Destination::Public => {
+            let arenas: Vec<Arena> = ARENAS.lock().unwrap().iter().flatten().cloned().collect();
+            arenas.iter()
+                .filter(|arena| arena.public && has_room(arena))
+                .min_by_key(|arena| humans_in(arena.id))
+                .map(|arena| (arena.id, false))
+                .ok_or("server full")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::snake;
+    use crate::network::transport::Peer;
+
+    // Public players go to any public arena, so tests creating some take turns
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn join(arena_id: usize, peer: Peer) -> usize {
+        let arena = read(arena_id).unwrap();
+        let snake = snake::create(CONST::SNAKE_INITIAL_LENGTH as f64, 0, CONST::SNAKE_SPEED, &arena);
+        player::create(String::new(), String::new(), 0, String::new(), snake, peer, arena_id);
+        player::find_id_by_addr(&peer).unwrap()
+    }
+
+    fn create_room(max_players: usize, password: Option<&str>) -> usize {
+        let mut settings = room_settings();
+        settings.max_players = max_players;
+        settings.password = password.map(str::to_string);
+        let (id, created) = assign(&Destination::Create(settings)).unwrap();
+        assert!(created);
+        id
+    }
+
+    #[test]
+    fn private_rooms_get_a_code_they_are_only_found_by() {
+        let id = create_room(5, None);
+        let code = read(id).unwrap().code.unwrap();
+        assert_eq!(code.len(), CONST::ROOM_CODE_LENGTH);
+        assert!(code.chars().all(|c| CONST::ROOM_CODE_ALPHABET.contains(c)));
+        assert_eq!(find_by_code(&code), Some(id));
+        assert_eq!(find_by_name(&code), None);
+        assert!(!read(id).unwrap().public);
+
+        // Nor can a named room take the code as its name
+        assert_eq!(assign(&Destination::Room(code.clone())), Err("room name taken"));
+
+        close(id);
+        assert_eq!(find_by_code(&code), None);
+        assert!(read(id).is_none());
+    }
+
+    #[test]
+    fn private_rooms_take_players_with_the_code_and_password_up_to_their_cap() {
+        let id = create_room(2, Some("secret"));
+        let code = read(id).unwrap().code.unwrap();
+        let with = |password: Option<&str>| assign(&Destination::Private {
+            code: code.clone(),
+            password: password.map(str::to_string),
+        });
+
+        assert_eq!(with(None), Err("wrong password"));
+        assert_eq!(with(Some("guess")), Err("wrong password"));
+        assert_eq!(with(Some("secret")), Ok((id, false)));
+        assert_eq!(assign(&Destination::Private { code: "ZZZZZZ".to_string(), password: None }), Err("no such room"));
+
+        let players = [join(id, Peer::Memory(u64::MAX - 200)), join(id, Peer::Memory(u64::MAX - 201))];
+        assert_eq!(with(Some("secret")), Err("room full"));
+
+        players.into_iter().for_each(player::destroy);
+        close(id);
+    }
+
+    #[test]
+    fn named_rooms_are_created_on_first_use_then_joined() {
+        let room = Destination::Room("arena-test-room".to_string());
+        let (id, created) = assign(&room).unwrap();
+        assert!(created);
+        assert_eq!(find_by_name("arena-test-room"), Some(id));
+        assert_eq!(assign(&room), Ok((id, false)));
+        assert!(read(id).unwrap().code.is_none());
+        close(id);
+    }
+
+    #[test]
+    fn players_are_counted_per_arena_and_rooms_close_when_empty() {
+        let _serial = SERIAL.lock().unwrap();
+        let (first, second) = (create_room(5, None), create_room(5, None));
+        let public = create("arena test public", &room_settings(), true);
+        let a = join(first, Peer::Memory(u64::MAX - 202));
+        let b = join(first, Peer::Memory(u64::MAX - 203));
+        let c = join(second, Peer::Memory(u64::MAX - 204));
+        assert_eq!((humans_in(first), humans_in(second), humans_in(public)), (2, 1, 0));
+
+        // Public arenas stay open without players
+        let empty = empty_rooms();
+        assert!(!empty.contains(&first) && !empty.contains(&second) && !empty.contains(&public));
+        player::destroy(c);
+        assert!(empty_rooms().contains(&second));
+        assert!(!empty_rooms().contains(&public));
+
+        player::destroy(a);
+        assert_eq!(humans_in(first), 1);
+        player::destroy(b);
+        assert!(empty_rooms().contains(&first));
+
+        close(first);
+        close(second);
+        close(public);
+    }
+
+    #[test]
+    fn public_players_go_to_the_least_full_public_arena() {
+        let _serial = SERIAL.lock().unwrap();
+        let mut settings = room_settings();
+        settings.max_players = 1;
+        let first = create("arena test first", &settings, true);
+        let second = create("arena test second", &settings, true);
+        let (id, created) = assign(&Destination::Public).unwrap();
+        assert!(!created && (id == first || id == second));
+
+        let a = join(id, Peer::Memory(u64::MAX - 205));
+        let other = if id == first { second } else { first };
+        assert_eq!(assign(&Destination::Public), Ok((other, false)));
+        let b = join(other, Peer::Memory(u64::MAX - 206));
+        assert_eq!(assign(&Destination::Public), Err("server full"));
+
+        player::destroy(a);
+        player::destroy(b);
+        close(first);
+        close(second);
+    }
+}
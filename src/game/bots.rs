@@ -0,0 +1,515 @@
+// Snakes played by the server so small arenas do not feel empty. Bots are players
+// like the others, with a Peer::Bot address nothing is ever sent to, and steer
+// through the same move_x/move_y inputs clients send: they go for the baits in view,
+// keep away from bodies and borders, and now and then hunt another snake by cutting
+// in front of its head.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use once_cell::sync::Lazy;
+use rand::prelude::*;
+use uuid::Uuid;
+use crate::game::arena::{self, Arena};
+use crate::game::clock;
+use crate::game::collision::Rect;
+use crate::game::constants as CONST;
+use crate::game::royale;
+use crate::game::shape::{self, Shape};
+use crate::game::teams;
+use crate::models::{bait, player, snake};
+use crate::network::transport::Peer;
+
+// Window size bots pretend to have, move_snake steers towards the mouse from its center
+const BOT_WINDOW: f64 = 1000.0;
+
+pub struct Bot {
+    // Player hunted and when the hunt ends, in server time
+    pub prey: Option<usize>,
+    pub hunt_until: u64,
+    // Direction followed when there is nothing better to do, in radians
+    pub heading: f64,
+}
+
+static BOTS: Lazy<Mutex<HashMap<usize, Bot>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_BOT_ID: AtomicU64 = AtomicU64::new(1);
+static TARGET_POPULATION: AtomicUsize = AtomicUsize::new(CONST::BOT_TARGET_POPULATION);
+
+// Number of snakes, players and bots together, that bots keep each arena at
+pub fn set_target_population(population: usize) {
+    TARGET_POPULATION.store(population, Ordering::Relaxed);
+}
+
+pub fn count() -> usize {
+    BOTS.lock().unwrap().len()
+}
+
+pub fn is_bot(player_id: usize) -> bool {
+    BOTS.lock().unwrap().contains_key(&player_id)
+}
+
+fn spawn(arena: &Arena) {
+    let id = NEXT_BOT_ID.fetch_add(1, Ordering::Relaxed);
+    let addr = Peer::Bot(id);
+    let name = CONST::BOT_NAMES[rand::random_range(0..CONST::BOT_NAMES.len())].to_string();
+    let player_id = Uuid::new_v4().to_string();
+
+    let team = teams::pick(arena.id);
+    let bot_snake = snake::create(
+        CONST::SNAKE_INITIAL_LENGTH as f64,
+        team.map_or_else(|| rand::random_range(0..CONST::SNAKE_SKIN_COLOR_RANGE), teams::color),
+        CONST::SNAKE_SPEED,
+        arena
+    );
+    player::create(player_id.clone(), name, 0, player_id, bot_snake, addr, arena.id);
+
+    if let Some(index) = player::find_id_by_addr(&addr) {
+        player::update_player_team(index, team);
+        BOTS.lock().unwrap().insert(index, Bot {
+            prey: None,
+            hunt_until: 0,
+            heading: rand::random_range(0.0..2.0 * PI),
+        });
+    }
+}
+
+// Add bots to an arena while it has fewer snakes than the target population, and
+// return the bots to remove because it has more
+pub fn backfill(arena_id: usize) -> Vec<usize> {
+    let Some(arena) = arena::read(arena_id) else {
+        return Vec::new();
+    };
+    let players = player::keys_in(arena_id);
+    let target = usize::min(TARGET_POPULATION.load(Ordering::Relaxed), arena.max_players);
+
+    if players.len() < target {
+        for _ in players.len()..target {
+            spawn(&arena);
+        }
+        return Vec::new();
+    }
+
+    let excess = players.len() - target;
+    let bots = BOTS.lock().unwrap();
+    players.into_iter()
+        .filter(|id| bots.contains_key(id))
+        .take(excess)
+        .collect()
+}
+
+fn normalize(x: f64, y: f64) -> (f64, f64) {
+    let length = (x * x + y * y).sqrt();
+    if length == 0.0 {
+        (0.0, 0.0)
+    } else {
+        (x / length, y / length)
+    }
+}
+
+// Whether a point is far enough from the borders and the bodies of the other snakes
+fn is_safe(bot_id: usize, x: f64, y: f64, players: &[(usize, player::Player)], (shape, map): (Shape, &Rect)) -> bool {
+    let margin = CONST::BOT_AVOID_DISTANCE;
+    if !shape::contains(shape, map, x, y, margin) {
+        return false;
+    }
+
+    players.iter()
+        .filter(|(j, _)| *j != bot_id)
+        .flat_map(|(_, other)| other.snake.nodes.iter())
+        .all(|node| (node.x - x).powi(2) + (node.y - y).powi(2) >= margin * margin)
+}
+
+// Direction a bot wants to go to, and whether it boosts
+fn steer(bot_id: usize, bot: &mut Bot, me: &player::Player, players: &[(usize, player::Player)], baits: &[bait::Bait], bounds: (Shape, &Rect), arena: &Arena) -> (f64, f64, bool) {
+    let head = &me.snake.nodes[0];
+    let now = clock::now_ms();
+
+    // Keep away from the bodies of the others, its own too with self collision, and
+    // from the borders, harder the closer they are
+    let (mut avoid_x, mut avoid_y) = (0.0, 0.0);
+    for (j, other) in players {
+        let skip = if *j != bot_id {
+            0
+        } else if arena.self_collision {
+            snake::neck_end(&other.snake, arena)
+        } else {
+            other.snake.nodes.len()
+        };
+        for node in other.snake.nodes.iter().skip(skip) {
+            let (dx, dy) = (head.x - node.x, head.y - node.y);
+            let distance = (dx * dx + dy * dy).sqrt();
+            if distance < CONST::BOT_AVOID_DISTANCE {
+                let (nx, ny) = normalize(dx, dy);
+                let push = 1.0 - distance / CONST::BOT_AVOID_DISTANCE;
+                avoid_x += nx * push;
+                avoid_y += ny * push;
+            }
+        }
+    }
+    let (shape, map) = bounds;
+    for (distance, nx, ny) in shape::borders_near(shape, map, head.x, head.y, CONST::BOT_AVOID_DISTANCE) {
+        let push = 1.0 - distance.max(0.0) / CONST::BOT_AVOID_DISTANCE;
+        avoid_x += nx * push;
+        avoid_y += ny * push;
+    }
+
+    let distance_to = |x: f64, y: f64| ((x - head.x).powi(2) + (y - head.y).powi(2)).sqrt();
+
+    // Now and then go after a snake in view that is not longer than this one, nor a teammate
+    if bot.prey.is_none() && rand::random_bool(CONST::BOT_HUNT_CHANCE) {
+        bot.prey = players.iter()
+            .filter(|(j, other)| {
+                *j != bot_id
+                    && teams::can_kill(me, other)
+                    && other.snake.nodes.len() <= me.snake.nodes.len()
+                    && distance_to(other.snake.nodes[0].x, other.snake.nodes[0].y) < CONST::BOT_VIEW_DISTANCE
+            })
+            .map(|(j, _)| *j)
+            .choose(&mut rand::rng());
+        bot.hunt_until = now + CONST::BOT_HUNT_DURATION;
+    }
+
+    let prey = bot.prey
+        .filter(|_| now < bot.hunt_until)
+        .and_then(|prey| players.iter().find(|(j, _)| *j == prey))
+        .map(|(_, other)| other)
+        .filter(|other| distance_to(other.snake.nodes[0].x, other.snake.nodes[0].y) < CONST::BOT_VIEW_DISTANCE);
+    if prey.is_none() {
+        bot.prey = None;
+    }
+
+    let goal = if let Some(prey) = prey {
+        // Aim ahead of the prey's head so it runs into this snake's body
+        let prey_head = &prey.snake.nodes[0];
+        let (dir_x, dir_y) = match prey.snake.nodes.get(1) {
+            Some(neck) => normalize(prey_head.x - neck.x, prey_head.y - neck.y),
+            None => (0.0, 0.0),
+        };
+        Some((prey_head.x + dir_x * CONST::BOT_HUNT_LEAD, prey_head.y + dir_y * CONST::BOT_HUNT_LEAD))
+    } else {
+        // The most worthwhile bait in view: big and close. Baits where avoiding would
+        // push the bot back would only keep it hovering around them.
+        baits.iter()
+            .map(|b| (b, distance_to(b.x, b.y)))
+            .filter(|(_, distance)| *distance < CONST::BOT_VIEW_DISTANCE)
+            .filter(|(b, _)| is_safe(bot_id, b.x, b.y, players, bounds))
+            .max_by(|(a, a_distance), (b, b_distance)| {
+                (a.size / (a_distance + 1.0)).total_cmp(&(b.size / (b_distance + 1.0)))
+            })
+            .map(|(b, _)| (b.x, b.y))
+    };
+
+    let (goal_x, goal_y) = match goal {
+        Some((x, y)) => {
+            let (gx, gy) = normalize(x - head.x, y - head.y);
+            bot.heading = gy.atan2(gx);
+            (gx, gy)
+        }
+        None => {
+            bot.heading += rand::random_range(-CONST::BOT_WANDER_TURN..CONST::BOT_WANDER_TURN);
+            (bot.heading.cos(), bot.heading.sin())
+        }
+    };
+
+    let (x, y) = normalize(
+        goal_x + avoid_x * CONST::BOT_AVOID_WEIGHT,
+        goal_y + avoid_y * CONST::BOT_AVOID_WEIGHT
+    );
+    let boost = prey.is_some() && me.snake.nodes.len() > 2 * CONST::SNAKE_INITIAL_LENGTH;
+    (x, y, boost)
+}
+
+// Decide where every bot of an arena goes, every BOT_THINK_DELAY
+pub fn think(arena_id: usize, tick: u64) {
+    if !tick.is_multiple_of(u64::max(1, (CONST::BOT_THINK_DELAY / CONST::GAME_LOOP_DELAY) as u64)) {
+        return;
+    }
+    let Some(arena) = arena::read(arena_id) else {
+        return;
+    };
+
+    let players: Vec<(usize, player::Player)> = player::keys_in(arena_id).into_iter()
+        .filter_map(|i| player::read(i).map(|p| (i, p)))
+        .collect();
+    let baits = bait::all_in(arena_id);
+    // In battle royale the zone is where bots want to stay
+    let zone = royale::zone(&arena);
+    let bounds = match &zone {
+        Some(zone) => (arena.shape, zone),
+        None => (arena.shape, &arena.map),
+    };
+
+    // Decide under the lock and apply the decisions once it is released: BOTS is
+    // never held while taking another lock, so it can be taken under any of them
+    let decisions: Vec<(usize, f64, f64, bool, bool)> = {
+        let mut bots = BOTS.lock().unwrap();
+        bots.iter_mut()
+            .filter_map(|(&bot_id, bot)| {
+                let (_, me) = players.iter().find(|(i, _)| *i == bot_id)?;
+                let (x, y, boost) = steer(bot_id, bot, me, &players, &baits, bounds, &arena);
+                Some((bot_id, x, y, boost, me.snake.accelerate))
+            })
+            .collect()
+    };
+
+    for (bot_id, x, y, boost, accelerating) in decisions {
+        // Point the mouse from the center of the window, as a client would
+        player::update_player_xy(
+            bot_id,
+            BOT_WINDOW / 2.0 + x * BOT_WINDOW / 4.0,
+            BOT_WINDOW / 2.0 + y * BOT_WINDOW / 4.0,
+            BOT_WINDOW,
+            BOT_WINDOW
+        );
+        if accelerating != boost {
+            player::update_player_acceleration(bot_id, boost);
+        }
+    }
+}
+
+pub fn destroy(player_id: usize) {
+    BOTS.lock().unwrap().remove(&player_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::arena::Destination;
+
+    // The target population and the players are shared by all tests
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn join(arena_id: usize, peer: Peer) -> usize {
+        let arena = arena::read(arena_id).unwrap();
+        let snake = snake::create(CONST::SNAKE_INITIAL_LENGTH as f64, 0, CONST::SNAKE_SPEED, &arena);
+        player::create(String::new(), String::new(), 0, String::new(), snake, peer, arena_id);
+        player::find_id_by_addr(&peer).unwrap()
+    }
+
+    fn leave(player_id: usize) {
+        player::destroy(player_id);
+        destroy(player_id);
+    }
+
+    // An arena of the given shape, as it is when the test starts
+    fn test_arena(shape: Shape) -> Arena {
+        let mut settings = arena::room_settings();
+        settings.shape = shape;
+        let id = arena::create("bots test", &settings, false);
+        let arena = arena::read(id).unwrap();
+        arena::close(id);
+        arena
+    }
+
+    // A snake heading right with its head at (x, y)
+    fn snake_at(x: f64, y: f64, length: usize, team: Option<usize>) -> player::Player {
+        player::Player {
+            id: String::new(),
+            name: String::new(),
+            score: 0,
+            current_rank: String::new(),
+            snake: snake::Snake {
+                length: length as f64,
+                skin: 0,
+                speed: CONST::SNAKE_SPEED,
+                current_speed_sec: 0.0,
+                nodes: (0..length).map(|i| snake::Node { x: x - 5.0 * i as f64, y }).collect(),
+                current_angle: 0.0,
+                rotate_angle: 0.0,
+                is_dead: false,
+                accelerate: false,
+                accelerate_time: 0.0,
+            },
+            addr: Peer::Bot(0),
+            arena: 0,
+            team,
+            kills: 0,
+            dead: false,
+            move_x: 0.0,
+            move_y: 0.0,
+            window_w: 0.0,
+            window_h: 0.0,
+            last_seen: std::time::Instant::now(),
+            last_input_seq: 0,
+            rtt: 0.0,
+            rtt_jitter: 0.0,
+            lost: false,
+        }
+    }
+
+    fn new_bot(heading: f64) -> Bot {
+        Bot {
+            prey: None,
+            hunt_until: 0,
+            heading,
+        }
+    }
+
+    fn bait_at(x: f64, y: f64, size: f64) -> bait::Bait {
+        bait::Bait {
+            id: 0,
+            arena: 0,
+            x,
+            y,
+            color: String::new(),
+            size,
+        }
+    }
+
+    // Where bot 0 of the given players goes, and whether it boosts
+    fn decide(bot: &mut Bot, players: &[(usize, player::Player)], baits: &[bait::Bait], arena: &Arena) -> (f64, f64, bool) {
+        steer(0, bot, &players[0].1, players, baits, (arena.shape, &arena.map), arena)
+    }
+
+    #[test]
+    fn bots_go_for_big_close_baits_away_from_bodies() {
+        let arena = test_arena(Shape::Rectangle);
+        let players = vec![
+            (0, snake_at(1600.0, 1600.0, 5, None)),
+            // Longer, so never hunted
+            (1, snake_at(1500.0, 1620.0, 10, None)),
+        ];
+        let baits = [
+            bait_at(1700.0, 1600.0, 1.0),
+            bait_at(1600.0, 1900.0, 5.0),
+            // Bigger, but right next to a body
+            bait_at(1500.0, 1600.0, 10.0),
+            // Out of view
+            bait_at(1600.0, 2300.0, 100.0),
+        ];
+
+        let mut bot = new_bot(0.0);
+        let (x, y, boost) = decide(&mut bot, &players, &baits, &arena);
+        assert!(x.abs() < 1e-9 && (y - 1.0).abs() < 1e-9, "{} {}", x, y);
+        assert!(!boost);
+        assert!((bot.heading - PI / 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bots_steer_away_from_borders_and_bodies() {
+        // Wandering out of a rectangle or a circle
+        let arena = test_arena(Shape::Rectangle);
+        let players = vec![(0, snake_at(arena.map.left + 20.0, 1600.0, 5, None))];
+        let (x, _, _) = decide(&mut new_bot(PI), &players, &[], &arena);
+        assert!(x > 0.0);
+
+        let circle = test_arena(Shape::Circle);
+        let players = vec![(0, snake_at(circle.map.right - 20.0, 1600.0, 5, None))];
+        let (x, _, _) = decide(&mut new_bot(0.0), &players, &[], &circle);
+        assert!(x < 0.0);
+
+        // Into a body across its way
+        let mut wall = snake_at(1630.0, 1560.0, 1, None);
+        wall.snake.nodes = (0..16).map(|i| snake::Node { x: 1630.0, y: 1560.0 + 5.0 * i as f64 }).collect();
+        let players = vec![(0, snake_at(1600.0, 1600.0, 5, None)), (1, wall)];
+        let (x, _, _) = decide(&mut new_bot(0.0), &players, &[], &arena);
+        assert!(x < 0.0);
+    }
+
+    #[test]
+    fn bots_hunt_shorter_snakes_in_view_but_not_teammates() {
+        let arena = test_arena(Shape::Rectangle);
+        let players = vec![
+            (0, snake_at(1600.0, 1600.0, 12, Some(0))),
+            (1, snake_at(1400.0, 1400.0, 5, Some(0))),
+            (2, snake_at(1600.0, 1800.0, 20, Some(1))),
+            (3, snake_at(2200.0, 1600.0, 5, Some(1))),
+            (4, snake_at(1700.0, 1600.0, 5, Some(1))),
+        ];
+
+        // Hunts start by chance
+        let mut bot = new_bot(0.0);
+        for _ in 0..10000 {
+            bot = new_bot(0.0);
+            decide(&mut bot, &players, &[], &arena);
+            if bot.prey.is_some() {
+                break;
+            }
+        }
+        assert_eq!(bot.prey, Some(4));
+
+        // Aiming ahead of its head, boosting as the hunter is long enough
+        let (x, y, boost) = decide(&mut bot, &players, &[], &arena);
+        assert!((x - 1.0).abs() < 1e-9 && y.abs() < 1e-9, "{} {}", x, y);
+        assert!(boost);
+
+        // Until the hunt is over
+        bot.hunt_until = 0;
+        let (_, _, boost) = decide(&mut bot, &players, &[], &arena);
+        assert!(bot.prey.is_none() && !boost);
+    }
+
+    #[test]
+    fn arenas_are_kept_at_the_target_population() {
+        let _serial = SERIAL.lock().unwrap();
+        let arena_id = arena::create("population test", &arena::room_settings(), false);
+        let bots_in = || player::keys_in(arena_id).into_iter().filter(|&i| is_bot(i)).count();
+
+        set_target_population(3);
+        assert!(backfill(arena_id).is_empty());
+        assert_eq!(bots_in(), 3);
+        assert!(backfill(arena_id).is_empty());
+        assert_eq!(bots_in(), 3);
+
+        // A player counts towards the population, but is never removed
+        let human = join(arena_id, Peer::Memory(u64::MAX - 102));
+        backfill(arena_id).into_iter().for_each(leave);
+        assert_eq!(bots_in(), 2);
+        set_target_population(0);
+        let excess = backfill(arena_id);
+        assert_eq!(excess.len(), 2);
+        assert!(!excess.contains(&human));
+        excess.into_iter().for_each(leave);
+        assert_eq!(player::keys_in(arena_id), vec![human]);
+
+        // Never more than the arena takes
+        set_target_population(CONST::ROOM_MAX_PLAYERS + 5);
+        backfill(arena_id);
+        assert_eq!(player::keys_in(arena_id).len(), CONST::ROOM_MAX_PLAYERS);
+
+        set_target_population(CONST::BOT_TARGET_POPULATION);
+        player::keys_in(arena_id).into_iter().for_each(leave);
+        arena::close(arena_id);
+    }
+
+    #[test]
+    fn bots_give_up_their_places_in_rooms_to_players() {
+        let _serial = SERIAL.lock().unwrap();
+        set_target_population(5);
+        let mut settings = arena::room_settings();
+        settings.max_players = 2;
+        let (arena_id, created) = arena::assign(&Destination::Create(settings)).unwrap();
+        assert!(created);
+        let code = arena::read(arena_id).unwrap().code.unwrap();
+        let by_code = || arena::assign(&Destination::Private { code: code.clone(), password: None });
+
+        // Bots fill the room up to its cap, but still let a player in
+        assert!(backfill(arena_id).is_empty());
+        assert_eq!(player::keys_in(arena_id).len(), 2);
+        assert_eq!(arena::humans_in(arena_id), 0);
+        assert_eq!(by_code(), Ok((arena_id, false)));
+
+        // Then one of them leaves to make room
+        let first = join(arena_id, Peer::Memory(u64::MAX - 100));
+        let excess = backfill(arena_id);
+        assert_eq!(excess.len(), 1);
+        assert!(is_bot(excess[0]));
+        excess.into_iter().for_each(leave);
+
+        // The bot left plays along with the player
+        think(arena_id, 0);
+        let bot_id = player::keys_in(arena_id).into_iter().find(|&i| is_bot(i)).unwrap();
+        assert_eq!(player::read(bot_id).unwrap().window_w, BOT_WINDOW);
+
+        let second = join(arena_id, Peer::Memory(u64::MAX - 101));
+        backfill(arena_id).into_iter().for_each(leave);
+        assert_eq!(player::keys_in(arena_id), vec![first, second]);
+        assert_eq!(by_code(), Err("room full"));
+
+        set_target_population(CONST::BOT_TARGET_POPULATION);
+        leave(first);
+        leave(second);
+        arena::close(arena_id);
+    }
+}
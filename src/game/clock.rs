@@ -0,0 +1,12 @@
+// Server clock: the time since the server started, sent to clients so they can
+// interpolate snakes against server time. Game loop ticks are counted per arena.
+
+use std::time::Instant;
+use once_cell::sync::Lazy;
+
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+
+// Milliseconds since the server started
+pub fn now_ms() -> u64 {
+    START.elapsed().as_millis() as u64
+}
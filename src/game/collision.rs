@@ -12,4 +12,64 @@ pub fn rect_intersect(r1: &Rect, r2: &Rect) -> bool {
       r2.right < r1.left || 
       r2.top > r1.bottom ||
       r2.bottom < r1.top)
-} 
\ No newline at end of file
+}
+
+// Shortest signed distance along an axis that wraps around every size
+pub fn wrap_delta(delta: f64, size: f64) -> f64 {
+    delta - size * (delta / size).round()
+}
+
+// On a map that wraps around, the copy of (x, y) closest to (to_x, to_y)
+pub fn nearest_image(x: f64, y: f64, to_x: f64, to_y: f64, map: &Rect) -> (f64, f64) {
+    (
+        to_x + wrap_delta(x - to_x, map.right - map.left),
+        to_y + wrap_delta(y - to_y, map.bottom - map.top),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> Rect {
+        Rect {
+            top: 800.0,
+            left: 800.0,
+            right: 2400.0,
+            bottom: 2000.0,
+        }
+    }
+
+    #[test]
+    fn wrapped_distances_take_the_short_way_around() {
+        assert_eq!(wrap_delta(100.0, 1000.0), 100.0);
+        assert_eq!(wrap_delta(-100.0, 1000.0), -100.0);
+        assert_eq!(wrap_delta(900.0, 1000.0), -100.0);
+        assert_eq!(wrap_delta(-900.0, 1000.0), 100.0);
+        assert_eq!(wrap_delta(2100.0, 1000.0), 100.0);
+        assert_eq!(wrap_delta(0.0, 1000.0), 0.0);
+        assert_eq!(wrap_delta(499.0, 1000.0), 499.0);
+        assert_eq!(wrap_delta(501.0, 1000.0), -499.0);
+    }
+
+    #[test]
+    fn nearest_images_are_across_the_closest_edges() {
+        let map = map();
+        // Close already
+        assert_eq!(nearest_image(1000.0, 1000.0, 1100.0, 1200.0, &map), (1000.0, 1000.0));
+        // Across the right edge, and across the bottom one
+        assert_eq!(nearest_image(810.0, 1500.0, 2390.0, 1500.0, &map), (2410.0, 1500.0));
+        assert_eq!(nearest_image(1500.0, 1990.0, 1500.0, 805.0, &map), (1500.0, 790.0));
+        // Across a corner, each axis on its own size
+        assert_eq!(nearest_image(2395.0, 1995.0, 805.0, 805.0, &map), (795.0, 795.0));
+    }
+
+    #[test]
+    fn rects_touching_intersect() {
+        let map = map();
+        let touching = Rect { top: 2000.0, left: 2400.0, right: 2500.0, bottom: 2100.0 };
+        let apart = Rect { top: 2000.1, left: 2400.0, right: 2500.0, bottom: 2100.0 };
+        assert!(rect_intersect(&map, &touching) && rect_intersect(&touching, &map));
+        assert!(!rect_intersect(&map, &apart) && !rect_intersect(&apart, &map));
+    }
+}
@@ -1,3 +1,6 @@
+use crate::game::arena::Settings;
+use crate::game::shape::{Border, Shape};
+
 // SNAKE
 pub const SNAKE_INITIAL_LENGTH: usize = 5;        // 5 dots
 pub const SNAKE_SPEED: f64 = 1.0;
@@ -13,6 +16,7 @@ pub const SNAKE_NODE_SPACE: f64 = 0.0;
 pub const SNAKE_NODE_INITIAL_DISTANCE: f64 = 7.071067811865475; // Math.sqrt(50)
 pub const SNAKE_INITIAL_SIZE: f64 = 17.0;
 pub const SNAKE_IT_IS_TIME_TO_SHORTER: i32 = 20;
+pub const SNAKE_SELF_COLLISION_NECK: f64 = 20.0; // body behind the head it cannot run into, past the hitboxes' reach
 
 // BAIT
 pub const MAX_BAIT_COLOR_RANGE: i32 = 255;
@@ -33,24 +37,170 @@ pub const OFFSET_Y: f64 = 800.0;
 pub const TRUE_MAP_WIDTH: f64 = 3200.0;
 pub const TRUE_MAP_HEIGHT: f64 = 3200.0;
 
+// ARENAS
+// Arenas open to everyone, by name
+pub const ARENAS: [(&str, Settings); 1] = [
+    ("main", Settings {
+        width: 2400.0,
+        height: 2400.0,
+        shape: Shape::Rectangle,
+        border: Border::Clamp,
+        self_collision: false,
+        max_players: 100,
+        bait_density: 100.0,
+        teams: 0,
+        round_duration: 0,
+        zone_shrink_duration: 0,
+        password: None,
+    }),
+];
+pub const MAX_ARENAS: usize = 16;                          // public arenas and rooms together
+pub const ROOM_MAP_WIDTH: f64 = 1600.0;                    // map of a room created on request
+pub const ROOM_MAP_HEIGHT: f64 = 1600.0;
+pub const ROOM_MAX_PLAYERS: usize = 20;
+pub const ROOM_NAME_MAX_LENGTH: usize = 24;                // letters, digits, "-" and "_"
+pub const ROOM_CODE_LENGTH: usize = 6;                     // join code of a private room
+pub const ROOM_CODE_ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789"; // no 0/O or 1/I to mix up
+pub const ROOM_PASSWORD_MAX_LENGTH: usize = 32;
+pub const ROOM_MIN_MAP_SIZE: f64 = 400.0;                  // width and height a private room can ask for
+pub const ROOM_MAX_MAP_SIZE: f64 = 4000.0;
+pub const ROOM_PLAYERS_LIMIT: usize = 100;                 // max players a private room can ask for
+pub const ROOM_MAX_BAIT_DENSITY: f64 = 400.0;              // in percent of the public arenas' density
+
+// TEAMS
+pub const TEAM_MAX_COUNT: usize = 4;
+pub const TEAM_COLORS: [i32; TEAM_MAX_COUNT] = [0, 64, 128, 192]; // skin worn by each team's snakes
+pub const TEAM_FRIENDLY_FIRE: bool = false;                // whether teammates can kill each other
+pub const TEAM_SCORES_DELAY: i32 = 1000;                   // ms between two team score broadcasts
+
+// ROUNDS
+pub const ROUND_MIN_DURATION: u64 = 10;                    // seconds, for rooms that ask for rounds
+pub const ROUND_MAX_DURATION: u64 = 3600;
+pub const ROUND_COUNTDOWN: u64 = 10;                       // seconds counted down before the end of a round
+pub const ROUND_LEADERBOARD_SIZE: usize = 10;              // players listed in the results
+
+// BATTLE ROYALE
+pub const ROYALE_MIN_SHRINK: u64 = 10;                     // seconds the zone takes to shrink, for rooms that ask for it
+pub const ROYALE_MAX_SHRINK: u64 = 3600;
+pub const ROYALE_FINAL_ZONE: f64 = 200.0;                  // width and height of the zone once shrunk, diameter in circles
+pub const ROYALE_DAMAGE_DELAY: i32 = 250;                  // ms between two nodes lost outside the zone
+pub const ROYALE_DEATH_LENGTH: usize = 3;                  // snakes outside the zone die at this length
+pub const ROYALE_ZONE_DELAY: i32 = 1000;                   // ms between two broadcasts of the zone
+
 // GAME
 pub const GAME_LOOP_DELAY: i32 = 10;
+pub const SNAPSHOT_DELAY: i32 = 50;                        // ms between two snapshots sent to a client (20 Hz)
+pub const SNAPSHOT_MAX_DELAY: i32 = 200;                   // slowest snapshot rate for clients on slow links
+pub const SNAPSHOT_LAG_THRESHOLD: u32 = 8;                 // unacked snapshots before a client is slowed down
+pub const PING_DELAY: i32 = 1000;                          // ms between two pings sent to a client
+pub const PING_MAX_RTT: u64 = 10000;                       // older pongs are ignored
+pub const PLAYER_TIMEOUT: u64 = 5;                         // seconds of silence before a player is lost
+pub const PLAYER_TIMEOUT_GRACE: u64 = 10;                  // seconds a lost player's snake stays before removal
+pub const HOUSEKEEPING_DELAY: i32 = 1000;                  // ms between two checks for lost players and two reports
+
+// RELIABLE CHANNEL
+pub const RELIABLE_TIMEOUT: u64 = 200;                     // ms before a reliable packet is first sent again
+pub const RELIABLE_MAX_TIMEOUT: u64 = 3000;                // the timeout doubles on every retry up to this
+pub const RELIABLE_MAX_RETRIES: u32 = 8;
+pub const RELIABLE_RESEND_CHECK_DELAY: u64 = 50;           // ms between two checks for packets to resend
+
+// FRAGMENTATION
+pub const FRAGMENT_MTU: usize = 1200;                      // largest datagram sent, larger messages are fragmented
+pub const MAX_CLIENT_DATAGRAM: usize = 1200;               // larger client datagrams are rejected
+pub const FRAGMENT_MAX_COUNT: usize = 64;                  // fragments of one client message
+pub const FRAGMENT_MAX_MESSAGE: usize = 16384;             // bytes of one reassembled client message
+pub const FRAGMENT_MAX_PENDING_PER_PEER: usize = 32768;    // bytes of incomplete messages kept per client
+pub const FRAGMENT_MAX_PENDING: usize = 4194304;           // bytes of incomplete messages kept overall
+pub const FRAGMENT_TIMEOUT: u64 = 2000;                    // ms to receive all fragments of a message
+
+// ENCRYPTION
+pub const SESSION_SETUP_TIMEOUT: u64 = 10;                 // seconds an encrypted session may go without a player
+
+// BOTS
+pub const BOT_TARGET_POPULATION: usize = 10;               // bots join while there are fewer snakes than this
+pub const BOT_THINK_DELAY: i32 = 100;                      // ms between two decisions of a bot
+pub const BOT_VIEW_DISTANCE: f64 = 400.0;                  // baits and snakes further away are ignored
+pub const BOT_AVOID_DISTANCE: f64 = 80.0;                  // bodies and borders closer than this push the bot away
+pub const BOT_AVOID_WEIGHT: f64 = 3.0;                     // how much avoiding matters against the goal
+pub const BOT_HUNT_CHANCE: f64 = 0.02;                     // chance per decision to hunt a snake in view
+pub const BOT_HUNT_DURATION: u64 = 3000;                   // ms before a bot gives up a hunt
+pub const BOT_HUNT_LEAD: f64 = 60.0;                       // how far ahead of the prey's head a bot aims
+pub const BOT_WANDER_TURN: f64 = 0.3;                      // largest turn in radians per decision when wandering
+pub const BOT_NAMES: [&str; 10] = [
+    "Noodle", "Slinky", "Viper", "Mamba", "Zigzag", "Wiggles", "Sidewinder", "Cobra", "Taipan", "Boomslang",
+];
+
+// RATE LIMITING
+pub const MAX_PLAYERS: usize = 500;
+pub const MAX_PLAYERS_PER_IP: usize = 4;
+// Token buckets as (tokens per second, burst), for each session and for all the sessions of an IP
+pub const RATE_CONNECT: (f64, f64) = (0.2, 2.0);           // "0"
+pub const RATE_CONNECT_PER_IP: (f64, f64) = (1.0, 5.0);
+pub const RATE_HANDSHAKE: (f64, f64) = (0.2, 2.0);         // "0,key=...", before the key exchange
+pub const RATE_HANDSHAKE_PER_IP: (f64, f64) = (1.0, 5.0);
+pub const RATE_MOVE: (f64, f64) = (120.0, 60.0);           // "2"
+pub const RATE_MOVE_PER_IP: (f64, f64) = (480.0, 240.0);
+pub const RATE_NAME: (f64, f64) = (0.5, 3.0);              // "9"
+pub const RATE_NAME_PER_IP: (f64, f64) = (2.0, 10.0);
+pub const RATE_BOOST: (f64, f64) = (20.0, 20.0);           // "10", "11"
+pub const RATE_BOOST_PER_IP: (f64, f64) = (80.0, 80.0);
+pub const RATE_CONTROL: (f64, f64) = (100.0, 100.0);       // acks, pings, heartbeats and leaving
+pub const RATE_CONTROL_PER_IP: (f64, f64) = (400.0, 400.0);
+pub const RATE_OTHER: (f64, f64) = (10.0, 10.0);           // unknown commands
+pub const RATE_OTHER_PER_IP: (f64, f64) = (40.0, 40.0);
+pub const RATE_LIMIT_REPORT_DELAY: i32 = 10000;            // ms between two logs of the dropped messages
+
+// INPUT VALIDATION
+pub const INPUT_MAX_WINDOW: f64 = 10000.0;                 // largest window size and mouse coordinate accepted
+pub const NAME_MAX_LENGTH: usize = 16;                     // characters
+pub const NAME_ALLOWED_SYMBOLS: &str = " _-.'!?";          // allowed in names besides letters and digits
+
+// COMPRESSION
+pub const COMPRESSION_STATS_DELAY: i32 = 10000;            // ms between two logs of the bytes saved by compression
+
+// SERVER
 pub const SERVER_IP: &str = "0.0.0.0";
 pub const SERVER_PORT: i32 = 3000;
+pub const WEBSOCKET_PORT: i32 = 3001;
+pub const WEBSOCKET_QUEUE_SIZE: usize = 1000;              // messages waiting to be written to a WebSocket client
+pub const OUTBOUND_QUEUE_SIZE: usize = 256;                // events waiting to be sent to a client
 pub const SERVER_CURRENT_UPDATE_PLAYER_METHOD: i32 = 2;    // 1: old, 2: new
-pub const SERVER_CURRENT_SENDING_PLAYER_METHOD: i32 = 2;   // 2: old, 21: new (head only)
-pub const SERVER_UPDATE_ENEMY_METHOD: i32 = 6;             // 6: old, 61: new (head only)
+pub const SERVER_CURRENT_SENDING_PLAYER_METHOD: i32 = 23;  // 2: old, 21: new (head only), 23: delta since the acked snapshot
+pub const SERVER_UPDATE_ENEMY_METHOD: i32 = 63;            // 6: old, 61: new (head only), 63: delta since the acked snapshot
+pub const DELTA_MAX_ACK_AGE: u32 = 32;                     // older acks fall back to a full snapshot
+pub const DELTA_PRECISION: f64 = 0.01;                     // map units of one step of a node offset in a delta
+
+// INTEREST
+pub const SERVER_AREA_OF_INTEREST: bool = true;           // false: every player sees the whole map
+pub const INTEREST_MARGIN: f64 = 200.0;                   // extra distance around the client's window
+pub const INTEREST_DEFAULT_WINDOW_W: f64 = 1920.0;        // used until the client reports its window size
+pub const INTEREST_DEFAULT_WINDOW_H: f64 = 1080.0;
 
 // COMMAND
 pub const COMM_START_NEW_MESS: &str = "$";
 pub const COMM_NEW_SNAKE: &str = "1,";
 pub const COMM_UPDATE_SNAKE: &str = "2,";
 pub const COMM_UPDATE_SNAKE_HEAD_ONLY: &str = "21,";      // Send only the head
+pub const COMM_UPDATE_SNAKE_DELTA: &str = "23,";          // Changes since the acked snapshot
+pub const COMM_SNAPSHOT: &str = "20,";                    // Snapshot sequence, baseline, tick, last input and server time
 pub const COMM_NEW_BAIT: &str = "3,";
 pub const COMM_DELETE_BAIT: &str = "4,";
 pub const COMM_NEW_ENEMY: &str = "5,";
 pub const COMM_UPDATE_ENEMY: &str = "6,";
+pub const COMM_UPDATE_ENEMY_DELTA: &str = "63,";          // Changes since the acked snapshot
 pub const COMM_DEAD_ENEMY: &str = "7,";
 pub const COMM_DIE: &str = "8,";
 pub const COMM_ENEMY_NAME: &str = "9,";
-pub const COMM_SNAKE_ACCELERATING: &str = "10,"; 
\ No newline at end of file
+pub const COMM_SNAKE_ACCELERATING: &str = "10,";
+pub const COMM_PONG: &str = "25,";                        // Answer to a client ping with the server time
+pub const COMM_PING: &str = "26,";                        // Server ping, echoed back by the client
+pub const COMM_SESSION_KEY: &str = "27,";                 // Server public key of an encrypted session
+pub const COMM_ARENA: &str = "28,";                       // Arena joined: name, map bounds, border and shape
+pub const COMM_TEAM: &str = "29,";                        // Team of a player and the skin it wears
+pub const COMM_RELIABLE: &str = "30,";                    // Sequence number of a reliable packet
+pub const COMM_FRAGMENT: &str = "31,";                    // Fragment of a message larger than FRAGMENT_MTU 
+pub const COMM_TEAM_SCORES: &str = "32,";                 // Total length of the snakes of each team
+pub const COMM_ROUND_COUNTDOWN: &str = "33,";             // Seconds left in the round
+pub const COMM_ROUND_RESULTS: &str = "34,";               // Leaderboard, longest snake and most kills of the round
+pub const COMM_ROUND_START: &str = "35,";                 // New round and its duration, the world was reset
+pub const COMM_ZONE: &str = "36,";                        // Battle royale zone now and once shrunk, seconds left
\ No newline at end of file
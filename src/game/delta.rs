@@ -0,0 +1,229 @@
+// Delta snapshots: remembers what each client has of every snake after each snapshot,
+// so the next one only has to carry the changes since the last snapshot the client
+// acknowledged.
+//
+// A snake in a delta is sent as "length,dx,dy,dx,dy,...": for each node the baseline
+// also has, its offset from the baseline node in steps of DELTA_PRECISION, then the
+// nodes past the end of the baseline as full "x,y". The client rebuilds it from its
+// copy of the baseline snake: keep its first `length` nodes, move them by the offsets
+// and add the new ones. Every node moves a little on every tick, so offsets are much
+// shorter than positions. The history keeps the nodes as the client rebuilt them,
+// so rounding the offsets never adds up over several deltas.
+//
+// Only clients that acknowledge snapshots get deltas, the others always get the full
+// node lists; a client sending "12,<seq>" must understand "$23" and "$63".
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::game::constants as CONST;
+
+// A node as the client has it, in ten-thousandths of a map unit: full snapshots send
+// coordinates with 4 decimals
+pub type SentNode = (i64, i64);
+
+// The nodes of every snake in a snapshot, keyed by player index
+pub type SnapshotNodes = HashMap<usize, Vec<SentNode>>;
+
+pub struct Snapshot {
+    pub seq: u32,
+    pub nodes: SnapshotNodes,
+}
+
+pub struct SnapshotHistory {
+    pub snapshots: VecDeque<Snapshot>,
+}
+
+// The snapshot a delta is computed against
+pub struct Baseline {
+    pub seq: u32,
+    pub nodes: SnapshotNodes,
+}
+
+// Keyed by the index of the player receiving the snapshots
+static HISTORIES: Lazy<Mutex<HashMap<usize, SnapshotHistory>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The baseline of a new snapshot, if the client acknowledged a recent enough one.
+// Snapshots are numbered by send_rate, which also keeps the acks.
+pub fn begin(player_id: usize, seq: u32, acked: Option<u32>) -> Option<Baseline> {
+    let mut histories = HISTORIES.lock().unwrap();
+    let history = histories.entry(player_id).or_insert_with(|| SnapshotHistory {
+        snapshots: VecDeque::new(),
+    });
+
+    acked
+        .filter(|&acked| seq.wrapping_sub(acked) <= CONST::DELTA_MAX_ACK_AGE)
+        .and_then(|acked| history.snapshots.iter().find(|snapshot| snapshot.seq == acked))
+        .map(|snapshot| Baseline {
+            seq: snapshot.seq,
+            nodes: snapshot.nodes.clone(),
+        })
+}
+
+// Remember what the client will have after applying a snapshot
+pub fn record(player_id: usize, seq: u32, nodes: SnapshotNodes) {
+    let mut histories = HISTORIES.lock().unwrap();
+    if let Some(history) = histories.get_mut(&player_id) {
+        history.snapshots.push_back(Snapshot { seq, nodes });
+        while history.snapshots.len() > CONST::DELTA_MAX_ACK_AGE as usize {
+            history.snapshots.pop_front();
+        }
+    }
+}
+
+pub fn to_sent(x: f64, y: f64) -> SentNode {
+    ((x * 10000.0).round() as i64, (y * 10000.0).round() as i64)
+}
+
+fn coordinate(value: i64) -> String {
+    format!("{:.4}", value as f64 / 10000.0)
+}
+
+// Format nodes in full as "x,y,x,y,..."
+pub fn full_message(nodes: &[SentNode]) -> String {
+    nodes.iter()
+        .map(|&(x, y)| format!("{},{}", coordinate(x), coordinate(y)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// Format the changes of a snake since a baseline, along with the nodes the client
+// will have after applying them. None if that is not shorter than the full node list.
+pub fn encode(nodes: &[SentNode], baseline: &[SentNode]) -> Option<(String, Vec<SentNode>)> {
+    let step = (CONST::DELTA_PRECISION * 10000.0).round() as i64;
+    let offset = |value: i64, from: i64| (value - from + step / 2).div_euclid(step);
+
+    let mut msg = nodes.len().to_string();
+    let mut rebuilt = Vec::with_capacity(nodes.len());
+    for (i, &(x, y)) in nodes.iter().enumerate() {
+        match baseline.get(i) {
+            Some(&(from_x, from_y)) => {
+                let (dx, dy) = (offset(x, from_x), offset(y, from_y));
+                msg.push_str(&format!(",{},{}", dx, dy));
+                rebuilt.push((from_x + dx * step, from_y + dy * step));
+            }
+            None => {
+                msg.push_str(&format!(",{},{}", coordinate(x), coordinate(y)));
+                rebuilt.push((x, y));
+            }
+        }
+    }
+
+    if msg.len() < full_message(nodes).len() {
+        Some((msg, rebuilt))
+    } else {
+        None
+    }
+}
+
+pub fn destroy(player_id: usize) {
+    HISTORIES.lock().unwrap().remove(&player_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(points: &[(f64, f64)]) -> Vec<SentNode> {
+        points.iter().map(|&(x, y)| to_sent(x, y)).collect()
+    }
+
+    fn parse(field: &str) -> f64 {
+        field.parse().unwrap()
+    }
+
+    // What the client does with a delta and its copy of the baseline
+    fn apply(baseline: &[SentNode], delta: &str) -> Vec<SentNode> {
+        let fields: Vec<&str> = delta.split(',').collect();
+        let length: usize = fields[0].parse().unwrap();
+        fields[1..].chunks(2)
+            .enumerate()
+            .map(|(i, pair)| match baseline.get(i) {
+                Some(&(x, y)) => (
+                    x + (parse(pair[0]) * CONST::DELTA_PRECISION * 10000.0).round() as i64,
+                    y + (parse(pair[1]) * CONST::DELTA_PRECISION * 10000.0).round() as i64,
+                ),
+                None => to_sent(parse(pair[0]), parse(pair[1])),
+            })
+            .take(length)
+            .collect()
+    }
+
+    // Every node of the rebuilt snake within half a step of the real one
+    fn assert_close(rebuilt: &[SentNode], nodes: &[SentNode]) {
+        let tolerance = (CONST::DELTA_PRECISION * 10000.0 / 2.0).ceil() as i64;
+        assert_eq!(rebuilt.len(), nodes.len());
+        for (a, b) in rebuilt.iter().zip(nodes) {
+            assert!((a.0 - b.0).abs() <= tolerance && (a.1 - b.1).abs() <= tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    // A snake heading right along a gentle curve, `ticks` steps after the start
+    fn moving(length: usize, ticks: usize) -> Vec<SentNode> {
+        let points: Vec<(f64, f64)> = (0..length)
+            .map(|i| {
+                let along = 3.3 * ticks as f64 - 7.07 * i as f64;
+                (1000.0 + along, 1000.0 + (along / 50.0).sin() * 30.0)
+            })
+            .collect();
+        nodes(&points)
+    }
+
+    #[test]
+    fn deltas_rebuild_the_full_snapshot() {
+        // A snake that moved on, then grew or lost its tail since the baseline
+        let baseline = moving(20, 0);
+        for length in [12, 20, 21, 25] {
+            let current = moving(length, 1);
+            let (delta, rebuilt) = encode(&current, &baseline).unwrap();
+            assert_eq!(apply(&baseline, &delta), rebuilt);
+            assert_close(&rebuilt, &current);
+        }
+
+        let (delta, rebuilt) = encode(&baseline, &baseline).unwrap();
+        assert_eq!(delta, format!("20{}", ",0,0".repeat(20)));
+        assert_eq!(rebuilt, baseline);
+    }
+
+    #[test]
+    fn deltas_are_shorter_than_full_snapshots() {
+        let baseline = moving(50, 0);
+        let current = moving(50, 3);
+        let (delta, _) = encode(&current, &baseline).unwrap();
+        assert!(delta.len() * 2 < full_message(&current).len());
+
+        // Nothing to start from: the full list is shorter
+        assert!(encode(&current, &[]).is_none());
+    }
+
+    #[test]
+    fn rounding_does_not_add_up_over_deltas() {
+        // The client's copy after each delta is the next baseline
+        let mut client = moving(30, 0);
+        for tick in 1..200 {
+            let current = moving(30, tick);
+            let (delta, rebuilt) = encode(&current, &client).unwrap();
+            client = apply(&client, &delta);
+            assert_eq!(client, rebuilt);
+            assert_close(&client, &current);
+        }
+    }
+
+    #[test]
+    fn acks_pick_the_baseline() {
+        let player_id = usize::MAX;
+        assert!(begin(player_id, 1, None).is_none());
+        let mut sent = SnapshotNodes::new();
+        sent.insert(3, moving(5, 0));
+        record(player_id, 1, sent);
+
+        let baseline = begin(player_id, 2, Some(1)).unwrap();
+        assert_eq!(baseline.seq, 1);
+        assert_eq!(baseline.nodes[&3], moving(5, 0));
+
+        // Snapshots never recorded, and too old an ack, fall back to a full snapshot
+        assert!(begin(player_id, 3, Some(2)).is_none());
+        assert!(begin(player_id, 2 + CONST::DELTA_MAX_ACK_AGE, Some(1)).is_none());
+        destroy(player_id);
+    }
+}
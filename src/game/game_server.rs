@@ -1,63 +1,81 @@
 use crate::game::constants as CONST;
 use crate::models::{player, bait, snake};
-use crate::game::collision::{Rect, rect_intersect};
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-use tokio::time::{self, Duration};
-use std::net::SocketAddr;
+use crate::game::collision::{self, Rect, rect_intersect};
+use crate::game::interest;
+use crate::game::delta;
+use crate::game::send_rate;
+use crate::game::clock;
+use crate::game::arena::{self, Arena};
+use crate::game::bots;
+use crate::game::teams;
+use crate::game::rounds;
+use crate::game::royale;
+use crate::game::shape::{self, Border};
+use crate::game::input;
+use crate::network::reliable;
+use crate::network::fragment;
+use crate::network::compression;
+use crate::network::crypto;
+use crate::network::rate_limit;
+use crate::network::outbound::Outbound;
+use crate::network::transport::{Peer, Transport};
+use crate::network::udp::UdpTransport;
+use crate::network::websocket::WebSocketTransport;
+use tokio::time::{self, Duration, MissedTickBehavior};
+use std::collections::HashSet;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
 use uuid::Uuid;
 use rand::prelude::*;
-use std::time::SystemTime;
 
 // UDP packet structure
 pub struct UdpPacket {
-    pub addr: SocketAddr,
+    pub addr: Peer,
     pub data: Vec<u8>,
+    pub reliable: bool,     // events sent through the reliable channel, see network::reliable
+    pub kind: PacketKind,
 }
 
-// Game server state
-struct GameServer {
-    socket: Arc<UdpSocket>,
-    running: Arc<AtomicBool>,
+pub enum PacketKind {
+    Event,
+    // Sent after the events, and replaced by the next snapshot if still waiting
+    Snapshot,
 }
 
-// A Channel to send messages to clients
-type UdpSender = mpsc::Sender<UdpPacket>;
+// Per-client queues to send messages to clients
+pub type UdpSender = Arc<Outbound>;
 
-// Generate random bait
-fn generate_bait(low: f64, high: f64) -> bait::Bait {
-    let mut rng = rand::thread_rng();
-    let x = rng.gen_range(low..high);
-    let y = rng.gen_range(low..high);
+// Generate random bait somewhere in an arena
+fn generate_bait(arena: &Arena) -> bait::Bait {
+    let mut rng = rand::rng();
+    let (x, y) = shape::random_point(arena.shape, &arena.map, 10.0);
     
-    let color = rng.gen_range(0..CONST::MAX_BAIT_COLOR_RANGE).to_string();
-    let size = rng.gen_range(0.0..CONST::MAX_BAIT_SIZE);
+    let color = rng.random_range(0..CONST::MAX_BAIT_COLOR_RANGE).to_string();
+    let size = rng.random_range(0.0..CONST::MAX_BAIT_SIZE);
     
-    bait::create(x, y, color, size)
+    bait::create(arena.id, x, y, color, size)
 }
 
 // Generate specific bait at a location
-fn generate_specific_bait(x: f64, y: f64, color: i32, size: f64) -> bait::Bait {
-    bait::create(x, y, color.to_string(), size)
+fn generate_specific_bait(arena: usize, x: f64, y: f64, color: i32, size: f64) -> bait::Bait {
+    bait::create(arena, x, y, color.to_string(), size)
 }
 
 // Generate mass baits based on a dead snake
-fn generate_mass_bait(snake: &snake::Snake) -> Vec<bait::Bait> {
+fn generate_mass_bait(arena: usize, snake: &snake::Snake) -> Vec<bait::Bait> {
     let mut new_bait_arr = Vec::new();
-    let mut rng = rand::thread_rng();
-    let color = rng.gen_range(0..CONST::MAX_BAIT_COLOR_RANGE).to_string();
+    let mut rng = rand::rng();
+    let color = rng.random_range(0..CONST::MAX_BAIT_COLOR_RANGE).to_string();
     
     for i in (0..snake.nodes.len()).step_by(2) {
         if i >= snake.nodes.len() - 1 {
             break;
         }
         
-        let offset_x = rng.gen_range(-5.0..5.0);
-        let offset_y = rng.gen_range(-5.0..5.0);
+        let offset_x = rng.random_range(-5.0..5.0);
+        let offset_y = rng.random_range(-5.0..5.0);
         
         let new_bait = bait::create(
+            arena,
             snake.nodes[i].x + offset_x,
             snake.nodes[i].y + offset_y,
             color.clone(),
@@ -70,59 +88,368 @@ fn generate_mass_bait(snake: &snake::Snake) -> Vec<bait::Bait> {
     new_bait_arr
 }
 
-// The main game loop
-async fn game_loop(socket: Arc<UdpSocket>, tx: UdpSender) {
-    println!("Game loop started");
+// Send a message to every player of an arena
+fn broadcast(arena_id: usize, msg: &str, reliable: bool, tx: &UdpSender) {
+    for i in player::keys_in(arena_id) {
+        if let Some(player_i) = player::read(i) {
+            tx.send(UdpPacket {
+                addr: player_i.addr,
+                data: msg.as_bytes().to_vec(),
+                reliable,
+                kind: PacketKind::Event,
+            });
+        }
+    }
+}
+
+// End the round of an arena: send the results, reset the world and start the next one
+fn end_round(arena: &Arena, tx: &UdpSender) {
+    broadcast(arena.id, &rounds::results_message(arena), true, tx);
+    
+    // New baits all over the map
+    bait::destroy_in(arena.id);
+    for _ in 0..arena.max_baits {
+        generate_bait(arena);
+    }
+    
+    // Every snake respawns somewhere else, so clients drop the enemies they know and
+    // get them again, whole, with the next area of interest update and snapshot
+    let player_keys = player::keys_in(arena.id);
+    for &i in &player_keys {
+        if let Some(player_i) = player::read(i) {
+            let mut msg_enemies = String::new();
+            for j in interest::visible_enemies(i) {
+                msg_enemies.push_str(&format!("{}7,{}", CONST::COMM_START_NEW_MESS, j));
+            }
+            if !msg_enemies.is_empty() {
+                tx.send(UdpPacket {
+                    addr: player_i.addr,
+                    data: msg_enemies.into_bytes(),
+                    reliable: true,
+                    kind: PacketKind::Event,
+                });
+            }
+            
+            let new_snake = snake::create(
+                CONST::SNAKE_INITIAL_LENGTH as f64,
+                player_i.snake.skin,
+                CONST::SNAKE_SPEED,
+                arena
+            );
+            player::respawn(i, new_snake);
+            delta::destroy(i);
+        }
+    }
+    for &i in &player_keys {
+        interest::forget_enemy(i);
+    }
+    
+    let round = arena::start_round(arena.id);
+    println!("Arena {} starts round {}", arena.id, round);
+    if let Some(arena) = arena::read(arena.id) {
+        broadcast(arena.id, &rounds::start_message(&arena), true, tx);
+    }
+}
+
+// Build the message announcing a snake to a player that can now see it
+fn new_enemy_message(enemy: &player::Player) -> String {
+    let mut msg = format!(
+        "{}{}{},{},",
+        CONST::COMM_START_NEW_MESS,
+        CONST::COMM_NEW_ENEMY,
+        enemy.id,
+        enemy.name
+    );
+    
+    for (i, node) in enemy.snake.nodes.iter().enumerate() {
+        msg.push_str(&format!("{:.4},{:.4}", node.x, node.y));
+        if i < enemy.snake.nodes.len() - 1 {
+            msg.push(',');
+        }
+    }
+    
+    msg
+}
+
+// Work out what came into and went out of the view of the given players and
+// send the matching new/dead enemy and new/deleted bait messages
+fn update_interests(arena: &Arena, players: &[usize], player_keys: &[usize], tx: &UdpSender) {
+    let baits = bait::all_in(arena.id);
+    let wrap = (arena.border == Border::Wrap).then_some(&arena.map);
+    
+    for &i in players {
+        if let Some(player_i) = player::read(i) {
+            let view = interest::view_rect(&player_i);
+            
+            let mut visible_enemies = HashSet::new();
+            for &j in player_keys {
+                if i == j {
+                    continue;
+                }
+                
+                if let Some(player_j) = player::read(j) {
+                    if interest::snake_in_view(&player_j.snake, &view, wrap) {
+                        visible_enemies.insert(j);
+                    }
+                }
+            }
+            
+            let visible_baits = baits.iter()
+                .filter(|bait| interest::bait_in_view(bait, &view, wrap))
+                .cloned()
+                .collect();
+            
+            let changes = interest::update(i, visible_enemies, visible_baits);
+            
+            // Enemies coming and going must not get lost, or the client keeps a ghost snake
+            let mut msg_enemies = String::new();
+            
+            for &j in &changes.entered_enemies {
+                if let Some(player_j) = player::read(j) {
+                    msg_enemies.push_str(&new_enemy_message(&player_j));
+                    msg_enemies.push_str(&teams::team_message(j, &player_j));
+                }
+            }
+            
+            for &j in &changes.left_enemies {
+                msg_enemies.push_str(&format!("{}7,{}", CONST::COMM_START_NEW_MESS, j));
+            }
+            
+            if !msg_enemies.is_empty() {
+                tx.send(UdpPacket {
+                    addr: player_i.addr,
+                    data: msg_enemies.into_bytes(),
+                    reliable: true,
+                    kind: PacketKind::Event,
+                });
+            }
+            
+            let mut msg = String::new();
+            
+            for bait in &changes.entered_baits {
+                msg.push_str(&format!(
+                    "{}3,{},{},{}",
+                    CONST::COMM_START_NEW_MESS,
+                    bait.x,
+                    bait.y,
+                    bait.size
+                ));
+            }
+            
+            for &(x, y) in &changes.left_baits {
+                msg.push_str(&format!("{}4,{},{}", CONST::COMM_START_NEW_MESS, x, y));
+            }
+            
+            if !msg.is_empty() {
+                tx.send(UdpPacket {
+                    addr: player_i.addr,
+                    data: msg.into_bytes(),
+                    reliable: false,
+                    kind: PacketKind::Event,
+                });
+            }
+        }
+    }
+}
+
+// The nodes of a snake as a full snapshot sends them
+fn sent_nodes(snake: &snake::Snake) -> Vec<delta::SentNode> {
+    snake.nodes.iter()
+        .map(|node| delta::to_sent(node.x, node.y))
+        .collect()
+}
+
+// Build the snapshot a player receives: a header, its own snake and the
+// snakes it can see, each encoded according to the current update method
+fn snapshot_message(i: usize, player_i: &player::Player, tick: u64) -> String {
+    let use_delta = CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 23 || CONST::SERVER_UPDATE_ENEMY_METHOD == 63;
+    let mut sent = delta::SnapshotNodes::new();
+    
+    // Snapshots are numbered so the client can acknowledge them, which sets the send
+    // rate and, for delta snapshots, the baseline (0 when there is none). The server
+    // tick and the last input applied let the client reconcile its predicted snake
+    // with this one, the server time lets it interpolate the enemies.
+    let (seq, acked) = send_rate::next_seq(i);
+    let baseline = if use_delta { delta::begin(i, seq, acked) } else { None };
+    let mut msg = format!(
+        "{}{}{},{},{},{},{}",
+        CONST::COMM_START_NEW_MESS,
+        CONST::COMM_SNAPSHOT,
+        seq,
+        baseline.as_ref().map_or(0, |baseline| baseline.seq),
+        tick,
+        player_i.last_input_seq,
+        clock::now_ms()
+    );
+    
+    // The player's own snake
+    let nodes = sent_nodes(&player_i.snake);
+    let mut client_nodes = nodes.clone();
+    let changes = baseline.as_ref()
+        .filter(|_| CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 23)
+        .and_then(|baseline| baseline.nodes.get(&i))
+        .and_then(|baseline_nodes| delta::encode(&nodes, baseline_nodes));
+    if let Some((changes, rebuilt)) = changes {
+        // Delta method: only the changes since the acknowledged snapshot
+        msg.push_str(&format!(
+            "{}{}{}",
+            CONST::COMM_START_NEW_MESS,
+            CONST::COMM_UPDATE_SNAKE_DELTA,
+            changes
+        ));
+        client_nodes = rebuilt;
+    } else if CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 2 || CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 23 {
+        // Old method, or no usable baseline for the delta: send all nodes
+        msg.push_str(&format!(
+            "{}{}{}",
+            CONST::COMM_START_NEW_MESS,
+            CONST::COMM_UPDATE_SNAKE,
+            delta::full_message(&nodes)
+        ));
+    } else if CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 21 {
+        // New method: send only the head
+        let head = &player_i.snake.nodes[0];
+        msg.push_str(&format!(
+            "{}21,{},{}",
+            CONST::COMM_START_NEW_MESS,
+            head.x,
+            head.y
+        ));
+    }
+    sent.insert(i, client_nodes);
+    
+    // The snakes this player can see
+    let mut visible_enemies: Vec<usize> = interest::visible_enemies(i).into_iter().collect();
+    visible_enemies.sort_unstable();
+    
+    for j in visible_enemies {
+        if let Some(player_j) = player::read(j) {
+            let nodes = sent_nodes(&player_j.snake);
+            let mut client_nodes = nodes.clone();
+            let changes = baseline.as_ref()
+                .filter(|_| CONST::SERVER_UPDATE_ENEMY_METHOD == 63)
+                .and_then(|baseline| baseline.nodes.get(&j))
+                .and_then(|baseline_nodes| delta::encode(&nodes, baseline_nodes));
+            if let Some((changes, rebuilt)) = changes {
+                // Delta method: only the changes since the acknowledged snapshot
+                msg.push_str(&format!(
+                    "{}{}{},{}",
+                    CONST::COMM_START_NEW_MESS,
+                    CONST::COMM_UPDATE_ENEMY_DELTA,
+                    j,
+                    changes
+                ));
+                client_nodes = rebuilt;
+            } else if CONST::SERVER_UPDATE_ENEMY_METHOD == 6 || CONST::SERVER_UPDATE_ENEMY_METHOD == 63 {
+                // Old method, or the enemy is not in the baseline: all nodes
+                msg.push_str(&format!(
+                    "{}{}{},{}",
+                    CONST::COMM_START_NEW_MESS,
+                    CONST::COMM_UPDATE_ENEMY,
+                    j,
+                    delta::full_message(&nodes)
+                ));
+            } else if CONST::SERVER_UPDATE_ENEMY_METHOD == 61 {
+                // New method: head only
+                let head = &player_j.snake.nodes[0];
+                msg.push_str(&format!(
+                    "{}61,{},{},{}",
+                    CONST::COMM_START_NEW_MESS,
+                    j,
+                    head.x,
+                    head.y
+                ));
+            }
+            sent.insert(j, client_nodes);
+        }
+    }
+    
+    if use_delta {
+        delta::record(i, seq, sent);
+    }
+    
+    msg
+}
+
+// The game loop of an arena
+async fn game_loop(arena_id: usize, tx: UdpSender) {
+    println!("Game loop of arena {} started", arena_id);
     
     let mut interval = time::interval(Duration::from_millis(CONST::GAME_LOOP_DELAY as u64));
-    let mut last_time = SystemTime::now();
+    // A late tick must not be caught up at once, the receive tasks would never get
+    // to run and every player would time out under load
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_countdown = 0;
     loop {
         interval.tick().await;
-        let cur_time = SystemTime::now();
-        let mili = cur_time.duration_since(last_time).unwrap().as_millis();
-
-        println!("Ticked:{}", mili);
-        last_time = cur_time;
+        let tick = arena::advance(arena_id);
+        let Some(arena) = arena::read(arena_id) else {
+            break;
+        };
+        
+        // Timed rounds: count the end down, then reset the world for the next one
+        if let Some(seconds_left) = rounds::seconds_left(&arena) {
+            if seconds_left == 0 {
+                end_round(&arena, &tx);
+                continue;
+            }
+            if seconds_left <= CONST::ROUND_COUNTDOWN && seconds_left != last_countdown {
+                broadcast(arena_id, &rounds::countdown_message(seconds_left), false, &tx);
+                last_countdown = seconds_left;
+            }
+        }
+        
+        // Battle royale: the round ends with the last snake standing
+        if royale::is_over(&arena) {
+            end_round(&arena, &tx);
+            continue;
+        }
         
-        let mut new_bait_arr = Vec::new();
-        let mut msg_new_bait_arr = String::new();
         let mut dead_players = Vec::new();
         
-        // Create new bait if needed
-        if bait::length() < CONST::MAX_BAITS as usize {
-            new_bait_arr.push(generate_bait(
-                CONST::OFFSET_X + 10.0, 
-                CONST::TRUE_MAP_WIDTH - 10.0
-            ));
+        // Create new bait if needed. New, eaten and dropped baits all reach the
+        // clients through the area of interest update further down.
+        if bait::length_in(arena_id) < arena.max_baits {
+            generate_bait(&arena);
         }
         
-        // Update all player positions
-        let player_keys = player::keys();
+        // Keep the arena populated with bots and let them decide where to go
+        for id in bots::backfill(arena_id) {
+            delete_player(id, &tx);
+        }
+        bots::think(arena_id, tick);
         
-        for &i in &player_keys {
+        // Update all player positions. In battle royale dead snakes are out of the
+        // game until the next round, their players only watch.
+        let player_keys = player::keys_in(arena_id);
+        let playing: Vec<usize> = player_keys.iter()
+            .copied()
+            .filter(|&i| arena.zone_shrink_duration == 0 || player::read(i).is_some_and(|player| !player.dead))
+            .collect();
+        
+        for &i in &playing {
             if let Some(mut player_i) = player::read(i) {
                 // Handle snake acceleration and shortening
-                if player_i.snake.accelerate {
-                    if player_i.snake.nodes.len() > CONST::SNAKE_INITIAL_LENGTH {
-                        if player_i.snake.accelerate_time < CONST::SNAKE_IT_IS_TIME_TO_SHORTER as f64 {
-                            player_i.snake.accelerate_time += 1.0;
-                        } else {
-                            player_i.snake.accelerate_time = 0.0;
-                            
-                            let last_node = &player_i.snake.nodes[player_i.snake.nodes.len() - 1];
-                            let mut rng = rand::thread_rng();
-                            let color = rng.gen_range(0..CONST::MAX_BAIT_COLOR_RANGE);
-                            
-                            new_bait_arr.push(generate_specific_bait(
-                                last_node.x,
-                                last_node.y,
-                                color,
-                                5.0
-                            ));
-                            
-                            // Remove last node
-                            snake::shorter(&mut player_i.snake);
-                        }
+                if player_i.snake.accelerate && player_i.snake.nodes.len() > CONST::SNAKE_INITIAL_LENGTH {
+                    if player_i.snake.accelerate_time < CONST::SNAKE_IT_IS_TIME_TO_SHORTER as f64 {
+                        player_i.snake.accelerate_time += 1.0;
+                    } else {
+                        player_i.snake.accelerate_time = 0.0;
+                        
+                        let last_node = &player_i.snake.nodes[player_i.snake.nodes.len() - 1];
+                        let mut rng = rand::rng();
+                        let color = rng.random_range(0..CONST::MAX_BAIT_COLOR_RANGE);
+                        
+                        generate_specific_bait(
+                            arena_id,
+                            last_node.x,
+                            last_node.y,
+                            color,
+                            5.0
+                        );
+                        
+                        // Remove last node
+                        snake::shorter(&mut player_i.snake);
                     }
                 }
                 
@@ -132,7 +459,8 @@ async fn game_loop(socket: Arc<UdpSocket>, tx: UdpSender) {
                     player_i.move_x,
                     player_i.move_y,
                     player_i.window_w,
-                    player_i.window_h
+                    player_i.window_h,
+                    &arena
                 );
                 
                 // Update the player in the collection
@@ -140,21 +468,69 @@ async fn game_loop(socket: Arc<UdpSocket>, tx: UdpSender) {
             }
         }
         
+        // Snakes touching the edge of the map die when the border kills
+        if arena.border == Border::Kill {
+            for &i in &playing {
+                if let Some(player_i) = player::read(i) {
+                    if snake::touches_border(&player_i.snake, &arena) && player::mark_dead(i) {
+                        generate_mass_bait(arena_id, &player_i.snake);
+                        dead_players.push(i);
+                        tx.send(UdpPacket {
+                            addr: player_i.addr,
+                            data: format!("{}8", CONST::COMM_START_NEW_MESS).into_bytes(),
+                            reliable: true,
+                            kind: PacketKind::Event,
+                        });
+                    }
+                }
+            }
+        }
+        
+        // Snakes outside the battle royale zone wither away
+        if let Some(zone) = royale::zone(&arena) {
+            if tick.is_multiple_of(u64::max(1, (CONST::ROYALE_DAMAGE_DELAY / CONST::GAME_LOOP_DELAY) as u64)) {
+                for &i in &playing {
+                    if let Some(mut player_i) = player::read(i) {
+                        if !royale::is_outside(arena.shape, &zone, &player_i.snake.nodes[0]) {
+                            continue;
+                        }
+                        if player_i.snake.nodes.len() > CONST::ROYALE_DEATH_LENGTH {
+                            snake::shorter(&mut player_i.snake);
+                            player::update_player_snake(i, player_i.snake);
+                        } else if player::mark_dead(i) {
+                            dead_players.push(i);
+                            tx.send(UdpPacket {
+                                addr: player_i.addr,
+                                data: format!("{}8", CONST::COMM_START_NEW_MESS).into_bytes(),
+                                reliable: true,
+                                kind: PacketKind::Event,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        
         // Check if a player hits another player
-        for &i in &player_keys {
+        for &i in &playing {
             if let Some(player_i) = player::read(i) {
                 // If player is already dead, skip
                 if dead_players.contains(&i) {
                     continue;
                 }
                 
-                // Check against all other players
-                for &j in &player_keys {
-                    if i == j {
+                // Check against all other players, and itself with self collision
+                for &j in &playing {
+                    if i == j && !arena.self_collision {
                         continue; // A player cannot hit itself
                     }
                     
                     if let Some(player_j) = player::read(j) {
+                        // Teammates pass through each other
+                        if i != j && !teams::can_kill(&player_i, &player_j) {
+                            continue;
+                        }
+                        
                         let player_j_head = Rect {
                             top: player_j.snake.nodes[0].y - CONST::SNAKE_INITIAL_SIZE / 3.0,
                             left: player_j.snake.nodes[0].x - CONST::SNAKE_INITIAL_SIZE / 3.0,
@@ -162,39 +538,46 @@ async fn game_loop(socket: Arc<UdpSocket>, tx: UdpSender) {
                             bottom: player_j.snake.nodes[0].y + CONST::SNAKE_INITIAL_SIZE / 3.0,
                         };
                         
-                        // Check collision with each node of player i
+                        // Check collision with each node of player i, taking the copy
+                        // closest to the head when the map wraps around. A snake's own
+                        // head and neck always overlap, so only its body counts.
+                        let first = if i == j { snake::neck_end(&player_i.snake, &arena) } else { 0 };
                         let mut hit = false;
-                        for k in 0..player_i.snake.nodes.len() {
+                        for k in first..player_i.snake.nodes.len() {
+                            let node = &player_i.snake.nodes[k];
+                            let (x, y) = if arena.border == Border::Wrap {
+                                collision::nearest_image(node.x, node.y, player_j.snake.nodes[0].x, player_j.snake.nodes[0].y, &arena.map)
+                            } else {
+                                (node.x, node.y)
+                            };
                             let player_i_node = Rect {
-                                top: player_i.snake.nodes[k].y - CONST::SNAKE_INITIAL_SIZE / 3.0,
-                                left: player_i.snake.nodes[k].x - CONST::SNAKE_INITIAL_SIZE / 3.0,
-                                right: player_i.snake.nodes[k].x + CONST::SNAKE_INITIAL_SIZE / 3.0,
-                                bottom: player_i.snake.nodes[k].y + CONST::SNAKE_INITIAL_SIZE / 3.0,
+                                top: y - CONST::SNAKE_INITIAL_SIZE / 3.0,
+                                left: x - CONST::SNAKE_INITIAL_SIZE / 3.0,
+                                right: x + CONST::SNAKE_INITIAL_SIZE / 3.0,
+                                bottom: y + CONST::SNAKE_INITIAL_SIZE / 3.0,
                             };
                             
                             if rect_intersect(&player_i_node, &player_j_head) {
                                 hit = true;
                                 
                                 // Generate baits from dead snake
-                                let new_bait_on_dead = generate_mass_bait(&player_j.snake);
-                                for bait in &new_bait_on_dead {
-                                    msg_new_bait_arr.push_str(&format!(
-                                        "{}3,{},{},{}",
-                                        CONST::COMM_START_NEW_MESS,
-                                        bait.x,
-                                        bait.y,
-                                        bait.size
-                                    ));
-                                }
+                                generate_mass_bait(arena_id, &player_j.snake);
                                 
                                 dead_players.push(j);
+                                // A snake that keeps dying is only counted once, and
+                                // running into itself is no kill
+                                if player::mark_dead(j) && i != j {
+                                    player::add_kill(i);
+                                }
                                 
                                 // Notify player about death
                                 let death_msg = format!("{}8", CONST::COMM_START_NEW_MESS);
-                                let _ = tx.send(UdpPacket {
+                                tx.send(UdpPacket {
                                     addr: player_j.addr,
                                     data: death_msg.into_bytes(),
-                                }).await;
+                                    reliable: true,
+                                    kind: PacketKind::Event,
+                                });
                                 
                                 break;
                             }
@@ -222,33 +605,30 @@ async fn game_loop(socket: Arc<UdpSocket>, tx: UdpSender) {
         if !msg_dead_players.is_empty() {
             for &i in &player_keys {
                 if let Some(player_i) = player::read(i) {
-                    let _ = tx.send(UdpPacket {
+                    tx.send(UdpPacket {
                         addr: player_i.addr,
                         data: msg_dead_players.clone().into_bytes(),
-                    }).await;
+                        reliable: true,
+                        kind: PacketKind::Event,
+                    });
                 }
             }
         }
         
-        // Send new baits to all players
-        if !msg_new_bait_arr.is_empty() {
-            for &i in &player_keys {
-                if let Some(player_i) = player::read(i) {
-                    let _ = tx.send(UdpPacket {
-                        addr: player_i.addr,
-                        data: msg_new_bait_arr.clone().into_bytes(),
-                    }).await;
-                }
+        // Dead bots leave the game, the others were just told about their death. In
+        // battle royale they stay out until the next round, like the players.
+        for &dead_id in &dead_players {
+            if bots::is_bot(dead_id) && arena.zone_shrink_duration == 0 {
+                forget_player(dead_id, &tx);
             }
         }
         
         // Check if a player eats a bait
-        let bait_keys = bait::keys();
-        let mut deleted_baits = Vec::new();
+        let baits = bait::all_in(arena_id);
+        let mut eaten = HashSet::new();
         let mut grown_players = Vec::new();
-        let mut msg_grown_players = String::new();
         
-        for &i in &player_keys {
+        for &i in &playing {
             if let Some(player_i) = player::read(i) {
                 let player_i_head = Rect {
                     top: player_i.snake.nodes[0].y - CONST::SNAKE_INITIAL_SIZE / 2.0,
@@ -257,331 +637,455 @@ async fn game_loop(socket: Arc<UdpSocket>, tx: UdpSender) {
                     bottom: player_i.snake.nodes[0].y + CONST::SNAKE_INITIAL_SIZE / 2.0,
                 };
                 
-                for &j in &bait_keys {
-                    if let Some(bait_temp) = bait::read(j) {
-                        let bait_rect = Rect {
-                            top: bait_temp.y - bait_temp.size / 2.0,
-                            left: bait_temp.x - bait_temp.size / 2.0,
-                            right: bait_temp.x + bait_temp.size / 2.0,
-                            bottom: bait_temp.y + bait_temp.size / 2.0,
-                        };
+                for bait_temp in &baits {
+                    // Eaten by another snake earlier in this tick
+                    if eaten.contains(&bait_temp.id) {
+                        continue;
+                    }
+                    
+                    let (x, y) = if arena.border == Border::Wrap {
+                        collision::nearest_image(bait_temp.x, bait_temp.y, player_i.snake.nodes[0].x, player_i.snake.nodes[0].y, &arena.map)
+                    } else {
+                        (bait_temp.x, bait_temp.y)
+                    };
+                    let bait_rect = Rect {
+                        top: y - bait_temp.size / 2.0,
+                        left: x - bait_temp.size / 2.0,
+                        right: x + bait_temp.size / 2.0,
+                        bottom: y + bait_temp.size / 2.0,
+                    };
+                    
+                    if rect_intersect(&player_i_head, &bait_rect) {
+                        // Grow the snake
+                        player::grow_player_snake(i);
                         
-                        if rect_intersect(&player_i_head, &bait_rect) {
-                            // Grow the snake
-                            player::grow_player_snake(i);
-                            
-                            // New update method notification
-                            if CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 21 {
-                                if let Some(player) = player::read(i) {
-                                    let _ = tx.send(UdpPacket {
-                                        addr: player.addr,
-                                        data: format!("{}22", CONST::COMM_START_NEW_MESS).into_bytes(),
-                                    }).await;
-                                }
+                        // New update method notification
+                        if CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 21 {
+                            if let Some(player) = player::read(i) {
+                                tx.send(UdpPacket {
+                                    addr: player.addr,
+                                    data: format!("{}22", CONST::COMM_START_NEW_MESS).into_bytes(),
+                                    reliable: false,
+                                    kind: PacketKind::Event,
+                                });
                             }
-                            
-                            grown_players.push(i);
-                            msg_grown_players.push_str(&format!(
-                                "{}62,{}",
-                                CONST::COMM_START_NEW_MESS,
-                                i
-                            ));
-                            
-                            bait::destroy(j);
-                            deleted_baits.push(bait_temp);
                         }
+                        
+                        grown_players.push(i);
+                        bait::destroy(bait_temp.id);
+                        eaten.insert(bait_temp.id);
                     }
                 }
             }
         }
         
-        // Inform players about deleted baits
-        let mut msg_deleted_baits = String::new();
-        for bait in &deleted_baits {
-            msg_deleted_baits.push_str(&format!(
-                "{}4,{},{}",
-                CONST::COMM_START_NEW_MESS,
-                bait.x,
-                bait.y
-            ));
-        }
+        // Players getting a snapshot on this tick
+        let snapshot_players: Vec<usize> = player_keys.iter()
+            .copied()
+            .filter(|&i| !bots::is_bot(i) && send_rate::is_due(i, tick))
+            .collect();
         
-        // Send bait deletion and growth notifications
+        // Send enemies and baits coming into or going out of view
+        update_interests(&arena, &snapshot_players, &playing, &tx);
+        
+        // Send growth notifications to the players that can see the grown snake
         for &i in &player_keys {
             if let Some(player_i) = player::read(i) {
-                if !msg_deleted_baits.is_empty() {
-                    let _ = tx.send(UdpPacket {
-                        addr: player_i.addr,
-                        data: msg_deleted_baits.clone().into_bytes(),
-                    }).await;
+                let visible_enemies = interest::visible_enemies(i);
+                let mut msg_grown_players = String::new();
+                
+                for &j in &grown_players {
+                    if visible_enemies.contains(&j) {
+                        msg_grown_players.push_str(&format!(
+                            "{}62,{}",
+                            CONST::COMM_START_NEW_MESS,
+                            j
+                        ));
+                    }
                 }
                 
                 if !msg_grown_players.is_empty() {
-                    let _ = tx.send(UdpPacket {
+                    tx.send(UdpPacket {
                         addr: player_i.addr,
-                        data: msg_grown_players.clone().into_bytes(),
-                    }).await;
+                        data: msg_grown_players.into_bytes(),
+                        reliable: false,
+                        kind: PacketKind::Event,
+                    });
                 }
             }
         }
         
-        // Send each snake back to its player based on the current update method
-        if CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 2 {
-            // Old method: send all nodes
-            for &i in &player_keys {
-                if let Some(player_i) = player::read(i) {
-                    let mut msg_update_player = format!("{}2,", CONST::COMM_START_NEW_MESS);
-                    
-                    for (j, node) in player_i.snake.nodes.iter().enumerate() {
-                        msg_update_player.push_str(&format!("{:.4},{:.4}", node.x, node.y));
-                        if j < player_i.snake.nodes.len() - 1 {
-                            msg_update_player.push(',');
-                        }
-                    }
-                    
-                    let _ = tx.send(UdpPacket {
-                        addr: player_i.addr,
-                        data: msg_update_player.into_bytes(),
-                    }).await;
-                }
+        // Send each player its own snake and the snakes it can see
+        for &i in &snapshot_players {
+            if let Some(player_i) = player::read(i) {
+                let msg_snapshot = snapshot_message(i, &player_i, tick);
+                
+                tx.send(UdpPacket {
+                    addr: player_i.addr,
+                    data: msg_snapshot.into_bytes(),
+                    reliable: false,
+                    kind: PacketKind::Snapshot,
+                });
+                
+                send_rate::adapt(i);
             }
-        } else if CONST::SERVER_CURRENT_SENDING_PLAYER_METHOD == 21 {
-            // New method: send only the head
-            for &i in &player_keys {
-                if let Some(player_i) = player::read(i) {
-                    let head = &player_i.snake.nodes[0];
-                    let msg_update_player_head = format!(
-                        "{}21,{},{}",
-                        CONST::COMM_START_NEW_MESS,
-                        head.x,
-                        head.y
-                    );
-                    
-                    let _ = tx.send(UdpPacket {
-                        addr: player_i.addr,
-                        data: msg_update_player_head.into_bytes(),
-                    });
-                }
+        }
+        
+        // Broadcast the team scores in team mode
+        if tick.is_multiple_of(u64::max(1, (CONST::TEAM_SCORES_DELAY / CONST::GAME_LOOP_DELAY) as u64)) {
+            if let Some(msg_scores) = teams::scores_message(arena_id) {
+                broadcast(arena_id, &msg_scores, false, &tx);
             }
         }
         
-        // Send all snakes to each player based on the update method
-        if CONST::SERVER_UPDATE_ENEMY_METHOD == 61 {
-            // New method: head only
-            for &i in &player_keys {
-                if let Some(player_i) = player::read(i) {
-                    let mut msg_update_enemies_position = String::new();
-                    
-                    for &j in &player_keys {
-                        if i == j {
-                            continue;
-                        }
-                        
-                        if let Some(player_j) = player::read(j) {
-                            let head = &player_j.snake.nodes[0];
-                            msg_update_enemies_position.push_str(&format!(
-                                "{}61,{},{},{}",
-                                CONST::COMM_START_NEW_MESS,
-                                j,
-                                head.x,
-                                head.y
-                            ));
-                        }
-                    }
-                    
-                    if !msg_update_enemies_position.is_empty() {
-                        let _ = tx.send(UdpPacket {
-                            addr: player_i.addr,
-                            data: msg_update_enemies_position.into_bytes(),
-                        }).await;
-                    }
-                }
+        // Broadcast the battle royale zone
+        if tick.is_multiple_of(u64::max(1, (CONST::ROYALE_ZONE_DELAY / CONST::GAME_LOOP_DELAY) as u64)) {
+            if let Some(msg_zone) = royale::zone_message(&arena) {
+                broadcast(arena_id, &msg_zone, false, &tx);
             }
-        } else if CONST::SERVER_UPDATE_ENEMY_METHOD == 6 {
-            // Old method: all nodes
+        }
+        
+        // Ping every player regularly to keep its round trip time up to date
+        if tick.is_multiple_of(u64::max(1, (CONST::PING_DELAY / CONST::GAME_LOOP_DELAY) as u64)) {
+            let msg_ping = format!("{}{}{}", CONST::COMM_START_NEW_MESS, CONST::COMM_PING, clock::now_ms());
             for &i in &player_keys {
                 if let Some(player_i) = player::read(i) {
-                    let mut msg_update_enemies_position = String::new();
-                    
-                    for &j in &player_keys {
-                        if i == j {
-                            continue;
-                        }
-                        
-                        if let Some(player_j) = player::read(j) {
-                            msg_update_enemies_position.push_str(&format!(
-                                "{}6,{},",
-                                CONST::COMM_START_NEW_MESS,
-                                j
-                            ));
-                            
-                            for (k, node) in player_j.snake.nodes.iter().enumerate() {
-                                msg_update_enemies_position.push_str(&format!("{:.4},{:.4}", node.x, node.y));
-                                if k < player_j.snake.nodes.len() - 1 {
-                                    msg_update_enemies_position.push(',');
-                                }
-                            }
-                        }
-                    }
-                    
-                    if !msg_update_enemies_position.is_empty() {
-                        let _ = tx.send(UdpPacket {
-                            addr: player_i.addr,
-                            data: msg_update_enemies_position.into_bytes(),
-                        }).await;
-                    }
+                    tx.send(UdpPacket {
+                        addr: player_i.addr,
+                        data: msg_ping.clone().into_bytes(),
+                        reliable: false,
+                        kind: PacketKind::Event,
+                    });
                 }
             }
         }
+    }
+}
+
+// Checks and reports that concern the whole server rather than one arena
+async fn housekeeping_loop(tx: UdpSender) {
+    let mut interval = time::interval(Duration::from_millis(CONST::HOUSEKEEPING_DELAY as u64));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut round: u64 = 0;
+    
+    loop {
+        interval.tick().await;
+        round += 1;
         
-        // Send all new randomly generated baits
-        msg_new_bait_arr.clear();
-        for bait in &new_bait_arr {
-            msg_new_bait_arr.push_str(&format!(
-                "{}3,{},{},{}",
-                CONST::COMM_START_NEW_MESS,
-                bait.x,
-                bait.y,
-                bait.size
-            ));
+        // Report how much compression saves, per game loop tick on average
+        if round.is_multiple_of(u64::max(1, (CONST::COMPRESSION_STATS_DELAY / CONST::HOUSEKEEPING_DELAY) as u64)) {
+            let (raw_bytes, sent_bytes) = compression::take_stats();
+            if raw_bytes > 0 {
+                let stats_ticks = u64::max(1, (CONST::COMPRESSION_STATS_DELAY / CONST::GAME_LOOP_DELAY) as u64);
+                println!(
+                    "Compression: {} bytes sent instead of {}, {} bytes saved per tick ({:.1}%)",
+                    sent_bytes,
+                    raw_bytes,
+                    (raw_bytes - sent_bytes) / stats_ticks,
+                    100.0 * (raw_bytes - sent_bytes) as f64 / raw_bytes as f64
+                );
+            }
         }
         
-        if !msg_new_bait_arr.is_empty() {
-            for &i in &player_keys {
-                if let Some(player_i) = player::read(i) {
-                    let _ = tx.send(UdpPacket {
-                        addr: player_i.addr,
-                        data: msg_new_bait_arr.clone().into_bytes(),
-                    }).await;
-                }
+        // Report the messages dropped by the rate limits or rejected as invalid
+        if round.is_multiple_of(u64::max(1, (CONST::RATE_LIMIT_REPORT_DELAY / CONST::HOUSEKEEPING_DELAY) as u64)) {
+            let dropped = rate_limit::take_dropped();
+            if !dropped.is_empty() {
+                let counts: Vec<String> = dropped.iter().map(|(kind, count)| format!("{} {}", count, kind)).collect();
+                println!("Rate limits dropped: {}", counts.join(", "));
+            }
+            let rejected = input::take_rejected();
+            if !rejected.is_empty() {
+                let counts: Vec<String> = rejected.iter()
+                    .map(|((kind, reason), count)| format!("{} {} ({})", count, kind, reason))
+                    .collect();
+                println!("Invalid messages rejected: {}", counts.join(", "));
             }
+            rate_limit::prune();
         }
         
-        // Clean up inactive players (UDP connection management)
-        let inactive_players = player::clean_inactive_players(30); // 30 seconds timeout
+        // Clean up players that stayed silent past the timeout and its grace period
+        let inactive_players = player::clean_inactive_players(
+            CONST::PLAYER_TIMEOUT,
+            CONST::PLAYER_TIMEOUT_GRACE
+        );
         for id in inactive_players {
             println!("Player {} disconnected due to inactivity", id);
-            println!("Total player(s): {}", player::length());
-            let msg = format!("{}7,{}", CONST::COMM_START_NEW_MESS, id);
-            
-            // Notify remaining players
-            for &i in &player_keys {
-                if i != id {
-                    if let Some(player_i) = player::read(i) {
-                        let _ = tx.send(UdpPacket {
-                            addr: player_i.addr,
-                            data: msg.clone().into_bytes(),
-                        }).await;
-                    }
-                }
+            delete_player(id, &tx);
+        }
+        
+        // Disconnect the players that never acknowledged a reliable packet, they
+        // missed an event and their game can no longer be trusted
+        for addr in reliable::take_failed() {
+            if let Some(id) = player::find_id_by_addr(&Peer::Udp(addr)) {
+                println!("Player {} disconnected, reliable packets were not acknowledged", id);
+                delete_player(id, &tx);
             }
         }
+        for peer in tx.take_failed() {
+            if let Some(id) = player::find_id_by_addr(&peer) {
+                println!("Player {} disconnected, too many events waiting to be sent", id);
+                delete_player(id, &tx);
+            }
+        }
+        
+        // Drop the encrypted sessions that never got a player
+        for addr in crypto::started_before(Duration::from_secs(CONST::SESSION_SETUP_TIMEOUT)) {
+            if player::find_id_by_addr(&Peer::Udp(addr)).is_none() {
+                crypto::destroy(&addr);
+            }
+        }
+        
+        // Close the rooms no human plays in any more, along with their bots and baits
+        for arena_id in arena::empty_rooms() {
+            for id in player::keys_in(arena_id) {
+                forget_player(id, &tx);
+            }
+            bait::destroy_in(arena_id);
+            arena::close(arena_id);
+        }
     }
 }
 
 // Process a received packet from a client
-pub async fn process_packet(data: &[u8], addr: SocketAddr, tx: &mpsc::Sender<UdpPacket>) {
-    let message = String::from_utf8_lossy(data);
+pub fn process_packet(data: &[u8], addr: Peer, tx: &UdpSender) {
+    let Ok(message) = std::str::from_utf8(data) else {
+        input::reject("other", "invalid UTF-8");
+        return;
+    };
     let splitted: Vec<&str> = message.split(',').collect();
     
-    if splitted.is_empty() {
+    if !rate_limit::allow(&addr, splitted[0]) {
         return;
     }
-    
-    println!("{}", message);
 
     // Try to find the player by address
     let player_id_opt = player::find_id_by_addr(&addr);
-
-    // println!("{}", player_id_opt.unwrap_or(0));
     
     match splitted[0] {
         "0" => {
-            // New connection/player request
-            create_player(addr, tx.clone()).await;
+            // New connection/player request, optionally followed by options such as "compress=lz4"
+            if player::length() >= CONST::MAX_PLAYERS {
+                rate_limit::drop_message("connect");
+                println!("Refused player from {}: server full", addr);
+                return;
+            }
+            if addr.ip().is_some_and(|ip| player::count_by_ip(ip) >= CONST::MAX_PLAYERS_PER_IP) {
+                rate_limit::drop_message("connect");
+                println!("Refused player from {}: too many players from this address", addr);
+                return;
+            }
+            let connect = match input::parse_connect(&splitted) {
+                Ok(connect) => connect,
+                Err(reason) => return input::reject("connect", reason),
+            };
+            match connect.compress {
+                Some(codec) if compression::supports(codec) => compression::enable(addr),
+                Some(codec) => println!("Ignored unsupported compression from {}: {}", addr, codec),
+                None => {}
+            }
+            for option in &connect.unknown {
+                println!("Ignored unknown connect option from {}: {}", addr, option);
+            }
+            let assigned = arena::assign(&connect.destination);
+            let arena_id = match assigned {
+                Ok((arena_id, created)) => {
+                    if created {
+                        tokio::spawn(game_loop(arena_id, tx.clone()));
+                    }
+                    arena_id
+                }
+                Err(reason) => {
+                    rate_limit::drop_message("connect");
+                    println!("Refused player from {}: {}", addr, reason);
+                    return;
+                }
+            };
+            create_player(addr, arena_id, tx);
         }
         "2" => {
-            // Update player's mouse position
+            // Update player's mouse position, optionally followed by the input sequence number
             if let Some(player_id) = player_id_opt {
-                if splitted.len() >= 5 {
-                    player::update_player_xy(
-                        player_id,
-                        splitted[1].parse().unwrap_or(0.0),
-                        splitted[2].parse().unwrap_or(0.0),
-                        splitted[3].parse().unwrap_or(0.0),
-                        splitted[4].parse().unwrap_or(0.0)
-                    );
+                match input::parse_move(&splitted) {
+                    Ok(input_move) => {
+                        if player::accept_input(player_id, input_move.seq) {
+                            player::update_player_xy(
+                                player_id,
+                                input_move.x,
+                                input_move.y,
+                                input_move.window_w,
+                                input_move.window_h
+                            );
+                        }
+                    }
+                    Err(reason) => input::reject("move", reason),
                 }
             }
         }
         "9" => {
             // Player sends their name to all other players
-            if let Some(player_id) = player_id_opt {
-                if splitted.len() >= 2 {
-                    let name = splitted[1].to_string();
-                    
-                    // Update the player's name
-                    player::update_player_name(player_id, name.clone());
-                    
-                    // Notify all other players
-                    let msg_enemy_name = format!(
-                        "{}{}{}",
-                        CONST::COMM_START_NEW_MESS,
-                        CONST::COMM_ENEMY_NAME,
-                        player_id
-                    );
-                    
-                    let player_keys = player::keys();
-                    for &i in &player_keys {
-                        if i != player_id {
-                            if let Some(other_player) = player::read(i) {
-                                let _ = tx.send(UdpPacket {
-                                    addr: other_player.addr,
-                                    data: msg_enemy_name.clone().into_bytes(),
-                                }).await;
+            if let Some((player_id, player)) = player_id_opt.and_then(|id| player::read(id).map(|player| (id, player))) {
+                match input::parse_name(message) {
+                    Ok(name) => {
+                        // Update the player's name
+                        player::update_player_name(player_id, name.clone());
+                        
+                        // Notify the other players of the arena
+                        let msg_enemy_name = format!(
+                            "{}{}{},{}",
+                            CONST::COMM_START_NEW_MESS,
+                            CONST::COMM_ENEMY_NAME,
+                            player_id,
+                            name
+                        );
+                        
+                        let player_keys = player::keys_in(player.arena);
+                        for &i in &player_keys {
+                            if i != player_id {
+                                if let Some(other_player) = player::read(i) {
+                                    tx.send(UdpPacket {
+                                        addr: other_player.addr,
+                                        data: msg_enemy_name.clone().into_bytes(),
+                                        reliable: true,
+                                        kind: PacketKind::Event,
+                                    });
+                                }
                             }
                         }
                     }
+                    Err(reason) => input::reject("name", reason),
+                }
+            }
+        }
+        "12" => {
+            // Player acknowledges the last snapshot it received
+            if let Some(player_id) = player_id_opt {
+                match input::parse_number(&splitted) {
+                    Ok(seq) => send_rate::ack(player_id, seq),
+                    Err(reason) => return input::reject("control", reason),
+                }
+                player::update_last_seen(player_id);
+            }
+        }
+        "13" => {
+            // Client ping: echo the client time along with the server time and tick
+            if let Some((player_id, player)) = player_id_opt.and_then(|id| player::read(id).map(|player| (id, player))) {
+                let client_time: u64 = match input::parse_number(&splitted) {
+                    Ok(client_time) => client_time,
+                    Err(reason) => return input::reject("control", reason),
+                };
+                let msg_pong = format!(
+                    "{}{}{},{},{}",
+                    CONST::COMM_START_NEW_MESS,
+                    CONST::COMM_PONG,
+                    client_time,
+                    clock::now_ms(),
+                    arena::tick(player.arena)
+                );
+                
+                tx.send(UdpPacket {
+                    addr,
+                    data: msg_pong.into_bytes(),
+                    reliable: false,
+                    kind: PacketKind::Event,
+                });
+                player::update_last_seen(player_id);
+            }
+        }
+        "14" => {
+            // Client answers a server ping with the server time it carried
+            if let Some(player_id) = player_id_opt {
+                let sent: u64 = match input::parse_number(&splitted) {
+                    Ok(sent) => sent,
+                    Err(reason) => return input::reject("control", reason),
+                };
+                let now = clock::now_ms();
+                if sent <= now && now - sent <= CONST::PING_MAX_RTT {
+                    player::update_player_rtt(player_id, (now - sent) as f64);
                 }
+                player::update_last_seen(player_id);
             }
         }
-        "10" => {
-            // Player is accelerating
+        "17" => {
+            // Client acknowledges a reliable packet
+            let seq = match input::parse_number(&splitted) {
+                Ok(seq) => seq,
+                Err(reason) => return input::reject("control", reason),
+            };
+            if let Peer::Udp(udp_addr) = addr {
+                reliable::ack(&udp_addr, seq);
+            }
             if let Some(player_id) = player_id_opt {
-                player::update_player_acceleration(player_id, true);
+                player::update_last_seen(player_id);
             }
         }
-        "11" => {
-            // Player stops accelerating
+        "15" => {
+            // Player leaves the game (tab closed, back to menu...)
+            if let Err(reason) = input::parse_empty(&splitted) {
+                return input::reject("control", reason);
+            }
+            if let Some(player_id) = player_id_opt {
+                println!("Player {} left", player_id);
+                delete_player(player_id, tx);
+            }
+        }
+        "16" => {
+            // Heartbeat: the client is still there even if the player does nothing
+            if let Err(reason) = input::parse_empty(&splitted) {
+                return input::reject("control", reason);
+            }
             if let Some(player_id) = player_id_opt {
-                player::update_player_acceleration(player_id, false);
+                player::update_last_seen(player_id);
+            }
+        }
+        "10" | "11" => {
+            // Player starts (10) or stops (11) accelerating
+            if let Some(player_id) = player_id_opt {
+                match input::parse_boost(&splitted) {
+                    Ok(seq) => {
+                        if player::accept_input(player_id, seq) {
+                            player::update_player_acceleration(player_id, splitted[0] == "10");
+                        }
+                    }
+                    Err(reason) => input::reject("boost", reason),
+                }
             }
         }
-        _ => {}
+        _ => input::reject("other", "unknown command"),
     }
 }
 
-// Create a new player
-async fn create_player(addr: SocketAddr, tx: mpsc::Sender<UdpPacket>) -> String {
+// Create a new player in an arena
+fn create_player(addr: Peer, arena_id: usize, tx: &UdpSender) -> String {
+    let Some(arena) = arena::read(arena_id) else {
+        return String::new();
+    };
     let player_id = Uuid::new_v4().to_string();
     println!("New player created: {}", player_id);
     
-    // Create a new snake
+    // Create a new snake, in the team's color in team mode
+    let team = teams::pick(arena_id);
     let player_snake = snake::create(
         CONST::SNAKE_INITIAL_LENGTH as f64,
-        rand::random_range(0..CONST::SNAKE_SKIN_COLOR_RANGE),
-        CONST::SNAKE_SPEED
+        team.map_or_else(|| rand::random_range(0..CONST::SNAKE_SKIN_COLOR_RANGE), teams::color),
+        CONST::SNAKE_SPEED,
+        &arena
     );
     
     // Create the player
-    let new_player = player::create(
+    player::create(
         player_id.clone(),
         String::new(),
         0,
         player_id.clone(),
         player_snake.clone(),
-        addr
+        addr,
+        arena_id
     );
+    let index = player::find_id_by_addr(&addr);
+    if let Some(index) = index {
+        player::update_player_team(index, team);
+    }
     
     // Send first snake back to the client
     let mut msg = format!("{}1,", CONST::COMM_START_NEW_MESS);
@@ -591,160 +1095,151 @@ async fn create_player(addr: SocketAddr, tx: mpsc::Sender<UdpPacket>) -> String
             msg.push(',');
         }
     }
-
-    println!("{} {}",addr.ip(), addr.port());
-    println!("{}", msg);
-        
-    let _ = tx.send(UdpPacket {
+    
+    tx.send(UdpPacket {
         addr,
         data: msg.into_bytes(),
-    }).await;
+        reliable: false,
+        kind: PacketKind::Event,
+    });
     
-    // Prepare new enemy message for other players
-    let new_enemy_msg = format!(
-        "{}5,{},Unnamed,",
+    // Then the arena it landed in, the bounds and shape of its map, and its team if any
+    let mut msg_arena = format!(
+        "{}{}{},{:.4},{:.4},{:.4},{:.4},{},{}",
         CONST::COMM_START_NEW_MESS,
-        player_id
+        CONST::COMM_ARENA,
+        arena.name,
+        arena.map.left,
+        arena.map.top,
+        arena.map.right,
+        arena.map.bottom,
+        arena.border.name(),
+        arena.shape.name()
     );
-    
-    let mut full_enemy_msg = new_enemy_msg;
-    for (i, node) in player_snake.nodes.iter().enumerate() {
-        full_enemy_msg.push_str(&format!("{:.4},{:.4}", node.x, node.y));
-        if i < player_snake.nodes.len() - 1 {
-            full_enemy_msg.push(',');
-        }
-    }
-    
-    // Send all other players to this new player
-    let player_keys = player::keys();
-    let mut data = String::new();
-    
-    for &i in &player_keys {
-        if let Some(other_player) = player::read(i) {
-            if other_player.id != player_id {
-                data.push_str(&format!(
-                    "{}{}{}",
-                    CONST::COMM_START_NEW_MESS,
-                    CONST::COMM_NEW_ENEMY,
-                    other_player.id
-                ));
-                data.push_str(&format!(",{},", other_player.name));
-                
-                for (j, node) in other_player.snake.nodes.iter().enumerate() {
-                    data.push_str(&format!("{},{}", node.x, node.y));
-                    if j < other_player.snake.nodes.len() - 1 {
-                        data.push(',');
-                    }
-                }
-            }
-        }
+    if let Some((index, player)) = index.and_then(|index| player::read(index).map(|player| (index, player))) {
+        msg_arena.push_str(&teams::team_message(index, &player));
     }
+    tx.send(UdpPacket {
+        addr,
+        data: msg_arena.into_bytes(),
+        reliable: true,
+        kind: PacketKind::Event,
+    });
     
-    if !data.is_empty() {
-        let _ = tx.send(UdpPacket {
-            addr,
-            data: data.into_bytes(),
-        }).await;
-    }
-    
-    // Send new player to all other players
-    for &i in &player_keys {
-        if let Some(other_player) = player::read(i) {
-            if other_player.id != player_id {
-                let _ = tx.send(UdpPacket {
-                    addr: other_player.addr,
-                    data: full_enemy_msg.clone().into_bytes(),
-                }).await;
-            }
-        }
-    }
-    
-    // Send all baits to the new player
-    let bait_keys = bait::keys();
-    for &i in &bait_keys {
-        if let Some(bait) = bait::read(i) {
-            let bait_msg = format!(
-                "{}3,{},{},{}",
-                CONST::COMM_START_NEW_MESS,
-                bait.x,
-                bait.y,
-                bait.size
-            );
-            
-            let _ = tx.send(UdpPacket {
-                addr,
-                data: bait_msg.into_bytes(),
-            }).await;
-        }
-    }
+    // Other snakes and the baits around the new player are sent, and the new
+    // player announced to whoever can see it, by the next area of interest update
     
     println!("Total player(s): {}", player::length());
     player_id
 }
 
 // Delete a player
-pub async fn delete_player(player_id: usize, tx: &mpsc::Sender<UdpPacket>) {
-    // Inform all players about the dead/closed player
-    let player_keys = player::keys();
+pub fn delete_player(player_id: usize, tx: &UdpSender) {
+    // Inform the players of its arena about the dead/closed player
+    let player_keys = player::read(player_id).map_or(Vec::new(), |player| player::keys_in(player.arena));
     let data = format!("{}7,{}", CONST::COMM_START_NEW_MESS, player_id);
     
     for &i in &player_keys {
         if i != player_id {
             if let Some(player) = player::read(i) {
-                let _ = tx.send(UdpPacket {
+                tx.send(UdpPacket {
                     addr: player.addr,
                     data: data.clone().into_bytes(),
-                }).await;
+                    reliable: true,
+                    kind: PacketKind::Event,
+                });
             }
         }
     }
     
+    forget_player(player_id, tx);
+}
+
+// Remove a player and everything kept about it, without telling the others
+fn forget_player(player_id: usize, tx: &UdpSender) {
+    if let Some(player) = player::read(player_id) {
+        if let Peer::Udp(addr) = player.addr {
+            reliable::destroy(&addr);
+            fragment::destroy(&addr);
+            crypto::destroy(&addr);
+        }
+        tx.remove(&player.addr);
+        compression::disable(&player.addr);
+        rate_limit::destroy(&player.addr);
+    }
     player::destroy(player_id);
+    interest::destroy(player_id);
+    interest::forget_enemy(player_id);
+    delta::destroy(player_id);
+    send_rate::destroy(player_id);
+    bots::destroy(player_id);
     println!("Total player(s): {}", player::length());
 }
 
-// Start the game server
-pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+// Run the game for the clients of the given transports
+pub async fn serve(transports: Vec<Arc<dyn Transport>>) {
     println!("game_server is running");
     
-    // Bind to UDP socket
-    let socket = UdpSocket::bind(format!("{}:{}", CONST::SERVER_IP, CONST::SERVER_PORT)).await?;
-    let socket = Arc::new(socket);
+    // Create the queues for sending packets
+    let tx: UdpSender = Arc::new(Outbound::new());
     
-    // Create a channel for sending UDP packets
-    let (tx, mut rx) = mpsc::channel::<UdpPacket>(1000);
+    // Start a receive task for every transport, and one removing the players whose
+    // connection closed
+    for transport in &transports {
+        let receiver = transport.clone();
+        let receiver_tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some((addr, data)) = receiver.recv().await {
+                process_packet(&data, addr, &receiver_tx);
+            }
+        });
+        
+        let transport = transport.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(addr) = transport.closed().await {
+                if let Some(player_id) = player::find_id_by_addr(&addr) {
+                    delete_player(player_id, &tx);
+                }
+            }
+        });
+    }
     
-    // Start the game loop
-    let game_loop_socket = socket.clone();
-    let game_loop_tx = tx.clone();
-    let sender_socket = socket.clone();
-
-    let mut buf = [0u8; 1024];
+    // Start the packet sender task, handing each packet to the transport of its peer
+    let sender_tx = tx.clone();
     tokio::spawn(async move {
-
-        while let (size, addr) = socket.recv_from(&mut buf).await.unwrap() {
-            if size > 0 {
-                let rx_data = &buf[..size];
-                println!("Going to process packet");
-                process_packet(rx_data, addr, &tx).await;
-            } else {
-                println!("Error: no data received.");
+        loop {
+            let packet = sender_tx.next().await;
+            if let Some(transport) = transports.iter().find(|transport| transport.owns(&packet.addr)) {
+                let data = compression::compress(&packet.addr, packet.data);
+                transport.send(packet.addr, data, packet.reliable).await;
             }
         }
     });
     
-    // Start the packet sender task
-    tokio::spawn(async move {
-        while let Some(packet) = rx.recv().await {
-            let _ = sender_socket.send_to(&packet.data, packet.addr).await;
+    // Start the game loop of every arena, creating the public ones on first start
+    if arena::keys().is_empty() {
+        for (name, settings) in &CONST::ARENAS {
+            arena::create(name, settings, true);
         }
-    });
-    
-    // Main receive loop
+    }
+    for arena_id in arena::keys() {
+        tokio::spawn(game_loop(arena_id, tx.clone()));
+    }
     
+    housekeeping_loop(tx).await;
+}
 
-    game_loop(game_loop_socket, game_loop_tx).await;
+// Start the game server
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    // Bind to UDP socket
+    let udp = UdpTransport::bind(&format!("{}:{}", CONST::SERVER_IP, CONST::SERVER_PORT)).await?;
+    
+    // Start the WebSocket listener for browser clients
+    let websocket = WebSocketTransport::bind(&format!("{}:{}", CONST::SERVER_IP, CONST::WEBSOCKET_PORT)).await?;
+    println!("WebSocket server listening on {}:{}", CONST::SERVER_IP, CONST::WEBSOCKET_PORT);
+    
+    serve(vec![Arc::new(udp), Arc::new(websocket)]).await;
 
     Ok(())
-
-} 
\ No newline at end of file
+}
@@ -0,0 +1,458 @@
+// Parsing and validation of client messages. Every field is checked strictly: a
+// message with a missing, malformed or out of range field is rejected as a whole
+// and counted, never applied with a default value in place of the bad field.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::game::arena::{self, Destination};
+use crate::game::shape::{Border, Shape};
+use crate::game::constants as CONST;
+
+pub type Result<T> = std::result::Result<T, &'static str>;
+
+// Mouse position and window size sent with "2"
+pub struct Move {
+    pub x: f64,
+    pub y: f64,
+    pub window_w: f64,
+    pub window_h: f64,
+    pub seq: Option<u32>,
+}
+
+// Options of "0": compression codec, where to play and what was not understood
+pub struct Connect<'a> {
+    pub compress: Option<&'a str>,
+    pub destination: Destination,
+    pub unknown: Vec<&'a str>,
+}
+
+// Messages rejected per message type and reason since the last report
+static REJECTED: Lazy<Mutex<HashMap<(&'static str, &'static str), u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn check_count(fields: &[&str], min: usize, max: usize) -> Result<()> {
+    if fields.len() < min {
+        Err("missing field")
+    } else if fields.len() > max {
+        Err("unexpected field")
+    } else {
+        Ok(())
+    }
+}
+
+fn number<T: FromStr>(fields: &[&str], position: usize) -> Result<T> {
+    fields.get(position)
+        .ok_or("missing field")?
+        .trim()
+        .parse()
+        .map_err(|_| "malformed number")
+}
+
+// A finite number no further than max from 0
+fn bounded(fields: &[&str], position: usize, max: f64) -> Result<f64> {
+    let value: f64 = number(fields, position)?;
+    if !value.is_finite() {
+        return Err("number not finite");
+    }
+    if value.abs() > max {
+        return Err("number out of range");
+    }
+    Ok(value)
+}
+
+fn optional_seq(fields: &[&str], position: usize) -> Result<Option<u32>> {
+    if fields.len() > position {
+        number(fields, position).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+// "2,x,y,window_w,window_h[,seq]"
+pub fn parse_move(fields: &[&str]) -> Result<Move> {
+    check_count(fields, 5, 6)?;
+    let window_w = bounded(fields, 3, CONST::INPUT_MAX_WINDOW)?;
+    let window_h = bounded(fields, 4, CONST::INPUT_MAX_WINDOW)?;
+    if window_w <= 0.0 || window_h <= 0.0 {
+        return Err("empty window");
+    }
+
+    Ok(Move {
+        x: bounded(fields, 1, CONST::INPUT_MAX_WINDOW)?,
+        y: bounded(fields, 2, CONST::INPUT_MAX_WINDOW)?,
+        window_w,
+        window_h,
+        seq: optional_seq(fields, 5)?,
+    })
+}
+
+// "10[,seq]" and "11[,seq]"
+pub fn parse_boost(fields: &[&str]) -> Result<Option<u32>> {
+    check_count(fields, 1, 2)?;
+    optional_seq(fields, 1)
+}
+
+// Messages carrying a single number: snapshot and reliable acks, pings and pongs
+pub fn parse_number<T: FromStr>(fields: &[&str]) -> Result<T> {
+    check_count(fields, 2, 2)?;
+    number(fields, 1)
+}
+
+// Messages without fields
+pub fn parse_empty(fields: &[&str]) -> Result<()> {
+    check_count(fields, 1, 1)
+}
+
+// "9,name": the whole rest of the message is the name. Names end up inside "$5" and
+// "$9" messages, so only characters that cannot be taken for separators ("," and
+// "$") or break the client display are allowed, and the name needs no escaping.
+pub fn parse_name(message: &str) -> Result<String> {
+    let (_, name) = message.split_once(',').ok_or("missing field")?;
+    let name = name.trim();
+
+    let length = name.chars().count();
+    if length == 0 {
+        return Err("empty name");
+    }
+    if length > CONST::NAME_MAX_LENGTH {
+        return Err("name too long");
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || CONST::NAME_ALLOWED_SYMBOLS.contains(c)) {
+        return Err("forbidden character in name");
+    }
+    Ok(name.to_string())
+}
+
+// "0[,option...]": "compress=<codec>", "key=<hex>" (read by the UDP transport),
+// "room=<name>", "code=<code>" with an optional "password=<password>", or "create"
+// with the settings of the private room: "width", "height", "shape" (rectangle or
+// circle), "border" (clamp, kill or wrap, rectangles only), "self_collision" (heads
+// die on their own body), "players", "baits" (in percent of the usual density),
+// "teams", "round" (seconds), "royale" (seconds the zone takes to shrink) and
+// "password". At most one way to pick a room.
+pub fn parse_connect<'a>(fields: &[&'a str]) -> Result<Connect<'a>> {
+    let mut connect = Connect {
+        compress: None,
+        destination: Destination::Public,
+        unknown: Vec::new(),
+    };
+    let (mut room, mut code, mut password, mut create) = (None, None, None, false);
+    let mut settings = arena::room_settings();
+    let mut has_settings = false;
+
+    for &option in &fields[1..] {
+        let option = option.trim();
+        match option.split_once('=') {
+            Some(("compress", codec)) => connect.compress = Some(codec),
+            Some(("key", _)) => {}
+            Some(("room", name)) => room = Some(parse_room(name)?),
+            Some(("code", value)) => code = Some(parse_code(value)?),
+            Some(("password", value)) => password = Some(parse_password(value)?.to_string()),
+            Some(("width", value)) => {
+                settings.width = parse_setting(value, CONST::ROOM_MIN_MAP_SIZE, CONST::ROOM_MAX_MAP_SIZE)?;
+                has_settings = true;
+            }
+            Some(("height", value)) => {
+                settings.height = parse_setting(value, CONST::ROOM_MIN_MAP_SIZE, CONST::ROOM_MAX_MAP_SIZE)?;
+                has_settings = true;
+            }
+            Some(("players", value)) => {
+                settings.max_players = parse_setting(value, 1.0, CONST::ROOM_PLAYERS_LIMIT as f64)? as usize;
+                has_settings = true;
+            }
+            Some(("shape", value)) => {
+                settings.shape = Shape::parse(value.trim()).ok_or("unknown shape")?;
+                has_settings = true;
+            }
+            Some(("border", value)) => {
+                settings.border = Border::parse(value.trim()).ok_or("unknown border")?;
+                has_settings = true;
+            }
+            Some(("teams", value)) => {
+                settings.teams = parse_setting(value, 2.0, CONST::TEAM_MAX_COUNT as f64)? as usize;
+                has_settings = true;
+            }
+            Some(("round", value)) => {
+                settings.round_duration = parse_setting(value, CONST::ROUND_MIN_DURATION as f64, CONST::ROUND_MAX_DURATION as f64)? as u64;
+                has_settings = true;
+            }
+            Some(("royale", value)) => {
+                settings.zone_shrink_duration = parse_setting(value, CONST::ROYALE_MIN_SHRINK as f64, CONST::ROYALE_MAX_SHRINK as f64)? as u64;
+                has_settings = true;
+            }
+            Some(("baits", value)) => {
+                settings.bait_density = parse_setting(value, 0.0, CONST::ROOM_MAX_BAIT_DENSITY)?;
+                has_settings = true;
+            }
+            None if option == "create" => create = true,
+            None if option == "self_collision" => {
+                settings.self_collision = true;
+                has_settings = true;
+            }
+            _ => connect.unknown.push(option),
+        }
+    }
+
+    if [room.is_some(), code.is_some(), create].iter().filter(|&&way| way).count() > 1 {
+        return Err("conflicting room options");
+    }
+    if has_settings && !create {
+        return Err("room setting without create");
+    }
+    if password.is_some() && code.is_none() && !create {
+        return Err("password without room code");
+    }
+    if settings.shape == Shape::Circle && settings.border == Border::Wrap {
+        return Err("wrap border on a circle");
+    }
+
+    connect.destination = if create {
+        settings.password = password;
+        Destination::Create(settings)
+    } else if let Some(code) = code {
+        Destination::Private { code, password }
+    } else if let Some(room) = room {
+        Destination::Room(room.to_string())
+    } else {
+        Destination::Public
+    };
+    Ok(connect)
+}
+
+// "room=<name>" connect option: the name is echoed back in "$28", so it is kept to
+// letters, digits, "-" and "_"
+fn parse_room(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("empty room name");
+    }
+    if name.len() > CONST::ROOM_NAME_MAX_LENGTH {
+        return Err("room name too long");
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("forbidden character in room name");
+    }
+    Ok(name)
+}
+
+// "code=<code>" connect option, join codes are read in any case
+fn parse_code(code: &str) -> Result<String> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() != CONST::ROOM_CODE_LENGTH || !code.chars().all(|c| CONST::ROOM_CODE_ALPHABET.contains(c)) {
+        return Err("malformed room code");
+    }
+    Ok(code)
+}
+
+// "password=<password>" connect option
+fn parse_password(password: &str) -> Result<&str> {
+    if password.is_empty() {
+        return Err("empty password");
+    }
+    if password.len() > CONST::ROOM_PASSWORD_MAX_LENGTH {
+        return Err("password too long");
+    }
+    if !password.chars().all(|c| c.is_ascii_graphic() && c != '$') {
+        return Err("forbidden character in password");
+    }
+    Ok(password)
+}
+
+// Number given as a room setting, between min and max
+fn parse_setting(value: &str, min: f64, max: f64) -> Result<f64> {
+    let value: f64 = value.trim().parse().map_err(|_| "malformed number")?;
+    if !value.is_finite() {
+        return Err("number not finite");
+    }
+    if value < min || value > max {
+        return Err("number out of range");
+    }
+    Ok(value)
+}
+
+// Count a rejected message
+pub fn reject(kind: &'static str, reason: &'static str) {
+    *REJECTED.lock().unwrap().entry((kind, reason)).or_insert(0) += 1;
+}
+
+// Messages rejected per message type and reason since the previous call
+pub fn take_rejected() -> Vec<((&'static str, &'static str), u64)> {
+    let mut rejected: Vec<_> = REJECTED.lock().unwrap().drain().collect();
+    rejected.sort();
+    rejected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // x, y and seq of a move
+    type Aim = (f64, f64, Option<u32>);
+
+    fn fields(message: &str) -> Vec<&str> {
+        message.split(',').collect()
+    }
+
+    // Reasons and counts taken for one message type
+    fn taken(kind: &str) -> Vec<(&'static str, u64)> {
+        take_rejected().into_iter()
+            .filter(|((rejected, _), _)| *rejected == kind)
+            .map(|((_, reason), count)| (reason, count))
+            .collect()
+    }
+
+    #[test]
+    fn moves_are_checked_field_by_field() {
+        let cases: &[(&str, Result<Aim>)] = &[
+            ("2,10,-20.5,800,600", Ok((10.0, -20.5, None))),
+            ("2,10,20,800,600,7", Ok((10.0, 20.0, Some(7)))),
+            ("2, 10 ,20,800,600", Ok((10.0, 20.0, None))),
+            ("2,10,20,800", Err("missing field")),
+            ("2,10,20,800,600,7,8", Err("unexpected field")),
+            ("2,x,20,800,600", Err("malformed number")),
+            ("2,NaN,20,800,600", Err("number not finite")),
+            ("2,10,inf,800,600", Err("number not finite")),
+            ("2,10,20,-inf,600", Err("number not finite")),
+            ("2,10001,20,800,600", Err("number out of range")),
+            ("2,10,20,800,1e9", Err("number out of range")),
+            ("2,10,20,0,600", Err("empty window")),
+            ("2,10,20,800,-600", Err("empty window")),
+            ("2,10,20,800,600,-1", Err("malformed number")),
+        ];
+        for (message, expected) in cases {
+            let parsed = parse_move(&fields(message)).map(|m| (m.x, m.y, m.seq));
+            assert_eq!(&parsed, expected, "{}", message);
+        }
+
+        let parsed = parse_move(&fields("2,1,2,800,600")).unwrap();
+        assert_eq!((parsed.window_w, parsed.window_h), (800.0, 600.0));
+    }
+
+    #[test]
+    fn boosts_take_an_optional_seq() {
+        let cases: &[(&str, Result<Option<u32>>)] = &[
+            ("10", Ok(None)),
+            ("11,42", Ok(Some(42))),
+            ("10,", Err("malformed number")),
+            ("10,4294967296", Err("malformed number")),
+            ("11,1,2", Err("unexpected field")),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(&parse_boost(&fields(message)), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn single_number_messages_take_exactly_one() {
+        let cases: &[(&str, Result<u32>)] = &[
+            ("12,5", Ok(5)),
+            ("17, 5 ", Ok(5)),
+            ("12", Err("missing field")),
+            ("12,5,6", Err("unexpected field")),
+            ("12,-5", Err("malformed number")),
+            ("12,5.5", Err("malformed number")),
+            ("12,NaN", Err("malformed number")),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(&parse_number::<u32>(&fields(message)), expected, "{}", message);
+        }
+
+        assert_eq!(parse_number::<f64>(&fields("26,1.5")), Ok(1.5));
+        assert_eq!(parse_empty(&fields("16")), Ok(()));
+        assert_eq!(parse_empty(&fields("16,1")), Err("unexpected field"));
+    }
+
+    #[test]
+    fn names_are_trimmed_limited_and_filtered() {
+        let longest = "n".repeat(CONST::NAME_MAX_LENGTH);
+        let overlong = format!("9,{}n", longest);
+        let cases: &[(&str, Result<String>)] = &[
+            ("9,Snake", Ok("Snake".to_string())),
+            ("9,  Mr. Snake!  ", Ok("Mr. Snake!".to_string())),
+            ("9,Žluťoučký", Ok("Žluťoučký".to_string())),
+            (&format!("9,{}", longest), Ok(longest.clone())),
+            (&overlong, Err("name too long")),
+            ("9", Err("missing field")),
+            ("9,", Err("empty name")),
+            ("9,   ", Err("empty name")),
+            ("9,a,b", Err("forbidden character in name")),
+            ("9,a$5", Err("forbidden character in name")),
+            ("9,<b>", Err("forbidden character in name")),
+        ];
+        for (message, expected) in cases {
+            assert_eq!(&parse_name(message), expected, "{}", message);
+        }
+    }
+
+    #[test]
+    fn connect_options_pick_the_destination() {
+        let connect = parse_connect(&fields("0,compress=lz4,key=abcd,colour=red,fast")).unwrap();
+        assert_eq!(connect.compress, Some("lz4"));
+        assert_eq!(connect.unknown, vec!["colour=red", "fast"]);
+        assert!(matches!(connect.destination, Destination::Public));
+
+        let connect = parse_connect(&fields("0,room=duel_1")).unwrap();
+        assert!(matches!(connect.destination, Destination::Room(name) if name == "duel_1"));
+
+        let connect = parse_connect(&fields("0,code=abc234,password=s3cret!")).unwrap();
+        assert!(matches!(connect.destination,
+            Destination::Private { code, password } if code == "ABC234" && password.as_deref() == Some("s3cret!")));
+
+        let connect = parse_connect(&fields("0,create,width=800,height=1200,shape=circle,players=5,teams=2,round=60,royale=30,baits=0,self_collision,password=pw")).unwrap();
+        let Destination::Create(settings) = connect.destination else { panic!("no room created") };
+        assert_eq!((settings.width, settings.height), (800.0, 1200.0));
+        assert!(settings.shape == Shape::Circle && settings.self_collision);
+        assert_eq!((settings.max_players, settings.teams), (5, 2));
+        assert_eq!((settings.round_duration, settings.zone_shrink_duration), (60, 30));
+        assert_eq!(settings.bait_density, 0.0);
+        assert_eq!(settings.password.as_deref(), Some("pw"));
+    }
+
+    #[test]
+    fn bad_connect_options_reject_the_connection() {
+        let long_room = format!("0,room={}", "r".repeat(CONST::ROOM_NAME_MAX_LENGTH + 1));
+        let long_password = format!("0,create,password={}", "p".repeat(CONST::ROOM_PASSWORD_MAX_LENGTH + 1));
+        let cases: &[(&str, &str)] = &[
+            ("0,room=", "empty room name"),
+            (&long_room, "room name too long"),
+            ("0,room=a b", "forbidden character in room name"),
+            ("0,code=ABC23", "malformed room code"),
+            ("0,code=ABC230", "malformed room code"),
+            ("0,code=ABC234,password=", "empty password"),
+            (&long_password, "password too long"),
+            ("0,create,password=a$b", "forbidden character in password"),
+            ("0,password=pw", "password without room code"),
+            ("0,room=a,code=ABC234", "conflicting room options"),
+            ("0,room=a,create", "conflicting room options"),
+            ("0,width=800", "room setting without create"),
+            ("0,self_collision", "room setting without create"),
+            ("0,create,width=399", "number out of range"),
+            ("0,create,height=4001", "number out of range"),
+            ("0,create,players=0", "number out of range"),
+            ("0,create,teams=1", "number out of range"),
+            ("0,create,teams=5", "number out of range"),
+            ("0,create,round=9", "number out of range"),
+            ("0,create,royale=3601", "number out of range"),
+            ("0,create,baits=-1", "number out of range"),
+            ("0,create,width=NaN", "number not finite"),
+            ("0,create,baits=inf", "number not finite"),
+            ("0,create,width=big", "malformed number"),
+            ("0,create,shape=hexagon", "unknown shape"),
+            ("0,create,border=bounce", "unknown border"),
+            ("0,create,shape=circle,border=wrap", "wrap border on a circle"),
+        ];
+        for (message, reason) in cases {
+            assert_eq!(parse_connect(&fields(message)).err(), Some(*reason), "{}", message);
+        }
+    }
+
+    #[test]
+    fn rejected_messages_are_counted_until_taken() {
+        reject("test", "too odd");
+        reject("test", "too odd");
+        reject("test", "too even");
+        assert_eq!(taken("test"), vec![("too even", 1), ("too odd", 2)]);
+        assert!(taken("test").is_empty());
+    }
+}
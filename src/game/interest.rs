@@ -0,0 +1,268 @@
+// Area of interest: tracks which enemies and baits each player can currently see,
+// so the game loop only sends what is inside a player's viewport (plus a margin)
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::game::constants as CONST;
+use crate::game::collision::{self, Rect, rect_intersect};
+use crate::models::bait::Bait;
+use crate::models::player::Player;
+use crate::models::snake::Snake;
+
+// What a player currently has on screen
+pub struct Interest {
+    pub enemies: HashSet<usize>,
+    // Bait id -> position, kept so a bait can still be deleted on the client
+    // after it has been eaten and is no longer in the bait collection
+    pub baits: HashMap<usize, (f64, f64)>,
+}
+
+// Changes in a player's view since the previous update
+pub struct InterestChanges {
+    pub entered_enemies: Vec<usize>,
+    pub left_enemies: Vec<usize>,
+    pub entered_baits: Vec<Bait>,
+    pub left_baits: Vec<(f64, f64)>,
+}
+
+// Keyed by player index
+static INTERESTS: Lazy<Mutex<HashMap<usize, Interest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The area of the map a player is interested in: its screen centered on its head, plus a margin
+pub fn view_rect(player: &Player) -> Rect {
+    if !CONST::SERVER_AREA_OF_INTEREST {
+        return Rect {
+            top: f64::MIN,
+            left: f64::MIN,
+            right: f64::MAX,
+            bottom: f64::MAX,
+        };
+    }
+
+    let window_w = if player.window_w > 0.0 { player.window_w } else { CONST::INTEREST_DEFAULT_WINDOW_W };
+    let window_h = if player.window_h > 0.0 { player.window_h } else { CONST::INTEREST_DEFAULT_WINDOW_H };
+    let head = &player.snake.nodes[0];
+
+    Rect {
+        top: head.y - window_h / 2.0 - CONST::INTEREST_MARGIN,
+        left: head.x - window_w / 2.0 - CONST::INTEREST_MARGIN,
+        right: head.x + window_w / 2.0 + CONST::INTEREST_MARGIN,
+        bottom: head.y + window_h / 2.0 + CONST::INTEREST_MARGIN,
+    }
+}
+
+// Where a point appears in a view: on a map that wraps around, a view crossing an
+// edge shows what is on the other side
+fn seen_at(x: f64, y: f64, view: &Rect, wrap: Option<&Rect>) -> (f64, f64) {
+    match wrap {
+        Some(map) => collision::nearest_image(x, y, (view.left + view.right) / 2.0, (view.top + view.bottom) / 2.0, map),
+        None => (x, y),
+    }
+}
+
+// A snake is visible as soon as any of its nodes overlaps the view. wrap is the map
+// when it wraps around.
+pub fn snake_in_view(snake: &Snake, view: &Rect, wrap: Option<&Rect>) -> bool {
+    snake.nodes.iter().any(|node| {
+        let (x, y) = seen_at(node.x, node.y, view, wrap);
+        let node_rect = Rect {
+            top: y - CONST::SNAKE_INITIAL_SIZE / 2.0,
+            left: x - CONST::SNAKE_INITIAL_SIZE / 2.0,
+            right: x + CONST::SNAKE_INITIAL_SIZE / 2.0,
+            bottom: y + CONST::SNAKE_INITIAL_SIZE / 2.0,
+        };
+        rect_intersect(&node_rect, view)
+    })
+}
+
+pub fn bait_in_view(bait: &Bait, view: &Rect, wrap: Option<&Rect>) -> bool {
+    let (x, y) = seen_at(bait.x, bait.y, view, wrap);
+    x >= view.left && x <= view.right && y >= view.top && y <= view.bottom
+}
+
+// Replace what a player sees and return what came into and went out of view
+pub fn update(player_id: usize, enemies: HashSet<usize>, baits: Vec<Bait>) -> InterestChanges {
+    let mut interests = INTERESTS.lock().unwrap();
+    let interest = interests.entry(player_id).or_insert_with(|| Interest {
+        enemies: HashSet::new(),
+        baits: HashMap::new(),
+    });
+
+    let mut entered_enemies: Vec<usize> = enemies.difference(&interest.enemies).copied().collect();
+    let mut left_enemies: Vec<usize> = interest.enemies.difference(&enemies).copied().collect();
+    entered_enemies.sort_unstable();
+    left_enemies.sort_unstable();
+
+    let mut visible_baits = HashMap::new();
+    let mut entered_baits = Vec::new();
+    for bait in baits {
+        visible_baits.insert(bait.id, (bait.x, bait.y));
+        if !interest.baits.contains_key(&bait.id) {
+            entered_baits.push(bait);
+        }
+    }
+
+    let left_baits = interest.baits.iter()
+        .filter(|(id, _)| !visible_baits.contains_key(id))
+        .map(|(_, &pos)| pos)
+        .collect();
+
+    interest.enemies = enemies;
+    interest.baits = visible_baits;
+
+    InterestChanges {
+        entered_enemies,
+        left_enemies,
+        entered_baits,
+        left_baits,
+    }
+}
+
+pub fn visible_enemies(player_id: usize) -> HashSet<usize> {
+    let interests = INTERESTS.lock().unwrap();
+    interests.get(&player_id)
+        .map(|interest| interest.enemies.clone())
+        .unwrap_or_default()
+}
+
+// Drop an enemy from every view without producing a leave event,
+// used when the enemy is gone and everyone has already been told
+pub fn forget_enemy(enemy: usize) {
+    let mut interests = INTERESTS.lock().unwrap();
+    for interest in interests.values_mut() {
+        interest.enemies.remove(&enemy);
+    }
+}
+
+pub fn destroy(player_id: usize) {
+    INTERESTS.lock().unwrap().remove(&player_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::models::snake::Node;
+    use crate::network::transport::Peer;
+
+    fn snake(points: &[(f64, f64)]) -> Snake {
+        Snake {
+            length: points.len() as f64,
+            skin: 0,
+            speed: 0.0,
+            current_speed_sec: 0.0,
+            nodes: points.iter().map(|&(x, y)| Node { x, y }).collect(),
+            current_angle: 0.0,
+            rotate_angle: 0.0,
+            is_dead: false,
+            accelerate: false,
+            accelerate_time: 0.0,
+        }
+    }
+
+    fn player_at(x: f64, y: f64, window_w: f64, window_h: f64) -> Player {
+        Player {
+            id: String::new(),
+            name: String::new(),
+            score: 0,
+            current_rank: String::new(),
+            snake: snake(&[(x, y)]),
+            addr: Peer::Memory(0),
+            arena: 0,
+            team: None,
+            kills: 0,
+            dead: false,
+            move_x: 0.0,
+            move_y: 0.0,
+            window_w,
+            window_h,
+            last_seen: Instant::now(),
+            last_input_seq: 0,
+            rtt: 0.0,
+            rtt_jitter: 0.0,
+            lost: false,
+        }
+    }
+
+    fn bait(id: usize, x: f64, y: f64) -> Bait {
+        Bait {
+            id,
+            arena: 0,
+            x,
+            y,
+            color: String::new(),
+            size: 1.0,
+        }
+    }
+
+    fn rect(left: f64, top: f64, right: f64, bottom: f64) -> Rect {
+        Rect { top, left, right, bottom }
+    }
+
+    #[test]
+    fn views_are_the_window_around_the_head_plus_a_margin() {
+        let view = view_rect(&player_at(5000.0, 4000.0, 800.0, 600.0));
+        assert_eq!(view.left, 5000.0 - 400.0 - CONST::INTEREST_MARGIN);
+        assert_eq!(view.right, 5000.0 + 400.0 + CONST::INTEREST_MARGIN);
+        assert_eq!(view.top, 4000.0 - 300.0 - CONST::INTEREST_MARGIN);
+        assert_eq!(view.bottom, 4000.0 + 300.0 + CONST::INTEREST_MARGIN);
+
+        // Until the client reports its window size
+        let view = view_rect(&player_at(5000.0, 4000.0, 0.0, 0.0));
+        assert_eq!(view.right - view.left, CONST::INTEREST_DEFAULT_WINDOW_W + 2.0 * CONST::INTEREST_MARGIN);
+        assert_eq!(view.bottom - view.top, CONST::INTEREST_DEFAULT_WINDOW_H + 2.0 * CONST::INTEREST_MARGIN);
+    }
+
+    #[test]
+    fn snakes_are_seen_by_any_node_and_across_a_wrapping_edge() {
+        // Nodes count with their size
+        let view = rect(1000.0, 1000.0, 2000.0, 2000.0);
+        assert!(snake_in_view(&snake(&[(500.0, 1500.0), (995.0, 1500.0)]), &view, None));
+        assert!(!snake_in_view(&snake(&[(500.0, 1500.0), (990.0, 1500.0)]), &view, None));
+
+        // A view at the left edge of a wrapping map shows the right edge
+        let map = rect(0.0, 0.0, 10000.0, 10000.0);
+        let view = rect(-500.0, 4000.0, 500.0, 5000.0);
+        let over_the_edge = snake(&[(9800.0, 4500.0)]);
+        assert!(snake_in_view(&over_the_edge, &view, Some(&map)));
+        assert!(!snake_in_view(&over_the_edge, &view, None));
+    }
+
+    #[test]
+    fn baits_are_seen_inside_the_view_and_across_a_wrapping_edge() {
+        let view = rect(1000.0, 1000.0, 2000.0, 2000.0);
+        assert!(bait_in_view(&bait(0, 1000.0, 2000.0), &view, None));
+        assert!(!bait_in_view(&bait(0, 2001.0, 1500.0), &view, None));
+
+        let map = rect(0.0, 0.0, 10000.0, 10000.0);
+        let view = rect(4000.0, 9500.0, 5000.0, 10500.0);
+        assert!(bait_in_view(&bait(0, 4500.0, 300.0), &view, Some(&map)));
+        assert!(!bait_in_view(&bait(0, 4500.0, 600.0), &view, Some(&map)));
+    }
+
+    #[test]
+    fn updates_report_what_entered_and_left_the_view() {
+        let player_id = usize::MAX;
+        let changes = update(player_id, HashSet::from([3, 1]), vec![bait(7, 10.0, 20.0), bait(8, 30.0, 40.0)]);
+        assert_eq!(changes.entered_enemies, vec![1, 3]);
+        assert!(changes.left_enemies.is_empty());
+        assert_eq!(changes.entered_baits.len(), 2);
+
+        // The bait that left is reported where it was, even if it no longer exists
+        let changes = update(player_id, HashSet::from([3, 4]), vec![bait(8, 30.0, 40.0)]);
+        assert_eq!(changes.entered_enemies, vec![4]);
+        assert_eq!(changes.left_enemies, vec![1]);
+        assert!(changes.entered_baits.is_empty());
+        assert_eq!(changes.left_baits, vec![(10.0, 20.0)]);
+
+        // A forgotten enemy is gone without a leave event
+        forget_enemy(3);
+        assert_eq!(visible_enemies(player_id), HashSet::from([4]));
+        let changes = update(player_id, HashSet::from([4]), vec![bait(8, 30.0, 40.0)]);
+        assert!(changes.left_enemies.is_empty() && changes.entered_enemies.is_empty());
+
+        destroy(player_id);
+        assert!(visible_enemies(player_id).is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+// Timed rounds: an arena created with a round duration plays rounds of that length.
+// The last ROUND_COUNTDOWN seconds are counted down to its players, then they all get
+// the results of the round and the world is reset, baits regenerated and every snake
+// respawned, before the next round starts.
+
+use crate::game::arena::Arena;
+use crate::game::clock;
+use crate::game::constants as CONST;
+use crate::models::player;
+
+// Seconds left in the current round, rounded up, or None outside timed rounds
+pub fn seconds_left(arena: &Arena) -> Option<u64> {
+    seconds_left_at(arena, clock::now_ms())
+}
+
+fn seconds_left_at(arena: &Arena, now: u64) -> Option<u64> {
+    if arena.round_duration == 0 {
+        return None;
+    }
+    let end = arena.round_started + arena.round_duration * 1000;
+    Some(end.saturating_sub(now).div_ceil(1000))
+}
+
+// "$33,seconds"
+pub fn countdown_message(seconds_left: u64) -> String {
+    format!("{}{}{}", CONST::COMM_START_NEW_MESS, CONST::COMM_ROUND_COUNTDOWN, seconds_left)
+}
+
+// "$34,round,longest,length,killer,kills,count[,player,name,length,kills]...": the
+// longest snake and the player with the most kills (-1 when nobody), then the
+// leaderboard: survivors first, then the longest snakes
+pub fn results_message(arena: &Arena) -> String {
+    let mut players: Vec<(usize, player::Player)> = player::keys_in(arena.id).into_iter()
+        .filter_map(|i| player::read(i).map(|p| (i, p)))
+        .collect();
+    players.sort_by_key(|(_, p)| std::cmp::Reverse(p.snake.nodes.len()));
+    let longest = players.first()
+        .map_or("-1,0".to_string(), |(i, p)| format!("{},{}", i, p.snake.nodes.len()));
+    let killer = players.iter()
+        .filter(|(_, p)| p.kills > 0)
+        .max_by_key(|(_, p)| p.kills)
+        .map_or("-1,0".to_string(), |(i, p)| format!("{},{}", i, p.kills));
+    players.sort_by_key(|(_, p)| p.dead);
+
+    let leaderboard: Vec<&(usize, player::Player)> = players.iter().take(CONST::ROUND_LEADERBOARD_SIZE).collect();
+    let mut msg = format!(
+        "{}{}{},{},{},{}",
+        CONST::COMM_START_NEW_MESS,
+        CONST::COMM_ROUND_RESULTS,
+        arena.round,
+        longest,
+        killer,
+        leaderboard.len()
+    );
+    for (i, p) in leaderboard {
+        msg.push_str(&format!(",{},{},{},{}", i, p.name, p.snake.nodes.len(), p.kills));
+    }
+    msg
+}
+
+// "$35,round,seconds"
+pub fn start_message(arena: &Arena) -> String {
+    format!(
+        "{}{}{},{}",
+        CONST::COMM_START_NEW_MESS,
+        CONST::COMM_ROUND_START,
+        arena.round,
+        arena.round_duration
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::arena;
+    use crate::models::snake;
+    use crate::network::transport::Peer;
+
+    fn timed_arena(round_duration: u64) -> usize {
+        let mut settings = arena::room_settings();
+        settings.round_duration = round_duration;
+        arena::create("rounds test", &settings, false)
+    }
+
+    // A player of the arena named after its length
+    fn join(arena_id: usize, peer: Peer, length: usize, kills: u32, dead: bool) -> usize {
+        let arena = arena::read(arena_id).unwrap();
+        let mut snake = snake::create(length as f64, 0, CONST::SNAKE_SPEED, &arena);
+        while snake.nodes.len() < length {
+            snake::grow(&mut snake);
+        }
+        player::create(String::new(), format!("L{}", length), 0, String::new(), snake, peer, arena_id);
+        let id = player::find_id_by_addr(&peer).unwrap();
+        for _ in 0..kills {
+            player::add_kill(id);
+        }
+        if dead {
+            player::mark_dead(id);
+        }
+        id
+    }
+
+    #[test]
+    fn the_end_of_a_round_is_counted_down_in_whole_seconds() {
+        let arena_id = timed_arena(60);
+        let mut arena = arena::read(arena_id).unwrap();
+        arena.round_started = 1_000_000;
+
+        assert_eq!(seconds_left_at(&arena, 1_000_000), Some(60));
+        assert_eq!(seconds_left_at(&arena, 1_000_001), Some(60));
+        assert_eq!(seconds_left_at(&arena, 1_050_999), Some(10));
+        assert_eq!(seconds_left_at(&arena, 1_059_001), Some(1));
+        assert_eq!(seconds_left_at(&arena, 1_060_000), Some(0));
+        assert_eq!(seconds_left_at(&arena, 2_000_000), Some(0));
+        assert_eq!(countdown_message(3), "$33,3");
+
+        arena.round_duration = 0;
+        assert_eq!(seconds_left_at(&arena, 1_000_000), None);
+        arena::close(arena_id);
+    }
+
+    #[test]
+    fn results_list_survivors_first_then_the_longest_snakes() {
+        let arena_id = timed_arena(60);
+        let arena = arena::read(arena_id).unwrap();
+        assert_eq!(results_message(&arena), "$34,1,-1,0,-1,0,0");
+
+        // (length, kills, dead), one more than the leaderboard takes
+        let players: Vec<usize> = [
+            (30, 0, true),
+            (8, 2, false),
+            (12, 0, false),
+            (25, 3, true),
+            (6, 0, false),
+            (7, 0, false),
+            (9, 1, false),
+            (10, 0, false),
+            (11, 0, false),
+            (13, 0, false),
+            (5, 0, true),
+        ]
+            .into_iter()
+            .enumerate()
+            .map(|(k, (length, kills, dead))| join(arena_id, Peer::Memory(u64::MAX - 400 - k as u64), length, kills, dead))
+            .collect();
+
+        let message = results_message(&arena);
+        let fields: Vec<&str> = message.split(',').collect();
+        assert_eq!(fields[..6], ["$34", "1", &players[0].to_string(), "30", &players[3].to_string(), "3"]);
+        assert_eq!(fields[6], CONST::ROUND_LEADERBOARD_SIZE.to_string());
+        let names: Vec<&str> = fields[7..].chunks(4).map(|entry| entry[1]).collect();
+        assert_eq!(names, ["L13", "L12", "L11", "L10", "L9", "L8", "L7", "L6", "L30", "L25"]);
+        let first = &fields[7..11];
+        assert_eq!(first, [players[9].to_string(), "L13".to_string(), "13".to_string(), "0".to_string()]);
+
+        players.into_iter().for_each(player::destroy);
+        arena::close(arena_id);
+    }
+
+    #[test]
+    fn rounds_reset_the_arena_and_the_players() {
+        let arena_id = timed_arena(60);
+        let player_id = join(arena_id, Peer::Memory(u64::MAX - 420), 20, 4, true);
+
+        assert_eq!(arena::start_round(arena_id), 2);
+        let arena = arena::read(arena_id).unwrap();
+        assert_eq!(arena.round, 2);
+        assert!(clock::now_ms() - arena.round_started < 1000);
+        assert_eq!(seconds_left(&arena), Some(60));
+        assert_eq!(start_message(&arena), "$35,2,60");
+
+        let new_snake = snake::create(CONST::SNAKE_INITIAL_LENGTH as f64, 0, CONST::SNAKE_SPEED, &arena);
+        player::respawn(player_id, new_snake);
+        let player = player::read(player_id).unwrap();
+        assert!(!player.dead && player.kills == 0);
+        assert_eq!(player.snake.nodes.len(), CONST::SNAKE_INITIAL_LENGTH);
+
+        player::destroy(player_id);
+        arena::close(arena_id);
+    }
+}
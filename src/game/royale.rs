@@ -0,0 +1,188 @@
+// Battle royale: in an arena created with a zone shrink duration, the safe zone is
+// the whole arena when a round starts and shrinks over that time towards a random
+// point, down to ROYALE_FINAL_ZONE. The zone has the shape of the arena: in circular
+// arenas it is a circle, held like the arena itself as the square around it. Snakes
+// with their head outside the zone lose a node every ROYALE_DAMAGE_DELAY and die once
+// down to ROYALE_DEATH_LENGTH. Dead snakes are out until the next round, which
+// starts as soon as one snake is left.
+
+use crate::game::arena::Arena;
+use crate::game::clock;
+use crate::game::collision::Rect;
+use crate::game::constants as CONST;
+use crate::game::shape::{self, Shape};
+use crate::models::player;
+use crate::models::snake::Node;
+
+// Center of the final zone, far enough from the border for the zone to fit
+pub fn pick_center(shape: Shape, map: &Rect) -> (f64, f64) {
+    shape::random_point(shape, map, CONST::ROYALE_FINAL_ZONE / 2.0)
+}
+
+fn final_zone(arena: &Arena) -> Rect {
+    let half = CONST::ROYALE_FINAL_ZONE / 2.0;
+    let (x, y) = arena.zone_center;
+    let bounds = shape::bounds(arena.shape, &arena.map);
+    Rect {
+        top: f64::max(bounds.top, y - half),
+        left: f64::max(bounds.left, x - half),
+        right: f64::min(bounds.right, x + half),
+        bottom: f64::min(bounds.bottom, y + half),
+    }
+}
+
+// Safe zone at a server time, from the whole arena to the final zone
+fn zone_at(arena: &Arena, now: u64) -> Rect {
+    let elapsed = now.saturating_sub(arena.round_started) as f64;
+    let progress = f64::min(1.0, elapsed / (arena.zone_shrink_duration * 1000) as f64);
+    let from = shape::bounds(arena.shape, &arena.map);
+    let to = final_zone(arena);
+    let lerp = |from: f64, to: f64| from + (to - from) * progress;
+    Rect {
+        top: lerp(from.top, to.top),
+        left: lerp(from.left, to.left),
+        right: lerp(from.right, to.right),
+        bottom: lerp(from.bottom, to.bottom),
+    }
+}
+
+// Current safe zone, or None outside battle royale
+pub fn zone(arena: &Arena) -> Option<Rect> {
+    if arena.zone_shrink_duration == 0 {
+        return None;
+    }
+    Some(zone_at(arena, clock::now_ms()))
+}
+
+pub fn is_outside(shape: Shape, zone: &Rect, node: &Node) -> bool {
+    !shape::contains(shape, zone, node.x, node.y, 0.0)
+}
+
+// "$36,left,top,right,bottom,left,top,right,bottom,seconds": the zone now, the final
+// zone and the seconds until it is reached, so clients can draw it shrinking. In
+// circular arenas the zones are the circles inside these squares.
+pub fn zone_message(arena: &Arena) -> Option<String> {
+    let zone = zone(arena)?;
+    let to = final_zone(arena);
+    let end = arena.round_started + arena.zone_shrink_duration * 1000;
+    Some(format!(
+        "{}{}{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{}",
+        CONST::COMM_START_NEW_MESS,
+        CONST::COMM_ZONE,
+        zone.left,
+        zone.top,
+        zone.right,
+        zone.bottom,
+        to.left,
+        to.top,
+        to.right,
+        to.bottom,
+        end.saturating_sub(clock::now_ms()).div_ceil(1000)
+    ))
+}
+
+// Whether a battle royale is down to its last snake
+pub fn is_over(arena: &Arena) -> bool {
+    if arena.zone_shrink_duration == 0 {
+        return false;
+    }
+    let players: Vec<player::Player> = player::keys_in(arena.id).into_iter().filter_map(player::read).collect();
+    players.len() >= 2 && players.iter().filter(|player| !player.dead).count() <= 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::shape::Border;
+
+    const STARTED: u64 = 1_000_000;
+
+    // A battle royale arena whose zone takes 100 s to shrink towards (x, y)
+    fn arena(shape: Shape, width: f64, height: f64, center: (f64, f64)) -> Arena {
+        Arena {
+            id: 0,
+            name: String::new(),
+            map: Rect { top: 0.0, left: 0.0, right: width, bottom: height },
+            shape,
+            border: Border::Clamp,
+            self_collision: false,
+            max_players: 10,
+            max_baits: 0,
+            public: false,
+            teams: 0,
+            code: None,
+            password: None,
+            round_duration: 0,
+            round: 1,
+            round_started: STARTED,
+            zone_shrink_duration: 100,
+            zone_center: center,
+            tick: 0,
+        }
+    }
+
+    fn corners(zone: &Rect) -> [f64; 4] {
+        [zone.left, zone.top, zone.right, zone.bottom]
+    }
+
+    fn assert_corners(zone: &Rect, expected: [f64; 4]) {
+        for (a, b) in corners(zone).iter().zip(expected) {
+            assert!((a - b).abs() < 1e-6, "{:?} != {:?}", corners(zone), expected);
+        }
+    }
+
+    #[test]
+    fn zones_shrink_steadily_to_the_final_zone() {
+        let arena = arena(Shape::Rectangle, 1000.0, 600.0, (700.0, 200.0));
+        let half = CONST::ROYALE_FINAL_ZONE / 2.0;
+        let last = [700.0 - half, 200.0 - half, 700.0 + half, 200.0 + half];
+
+        assert_corners(&zone_at(&arena, STARTED), [0.0, 0.0, 1000.0, 600.0]);
+        assert_corners(&zone_at(&arena, STARTED + 25_000), [
+            last[0] / 4.0,
+            last[1] / 4.0,
+            1000.0 + (last[2] - 1000.0) / 4.0,
+            600.0 + (last[3] - 600.0) / 4.0,
+        ]);
+        assert_corners(&zone_at(&arena, STARTED + 100_000), last);
+        assert_corners(&zone_at(&arena, STARTED + 500_000), last);
+
+        // Before the round started, and outside battle royale
+        assert_corners(&zone_at(&arena, 0), [0.0, 0.0, 1000.0, 600.0]);
+        let mut no_zone = arena.clone();
+        no_zone.zone_shrink_duration = 0;
+        assert!(zone(&no_zone).is_none() && zone_message(&no_zone).is_none());
+    }
+
+    #[test]
+    fn zones_of_circular_arenas_are_circles() {
+        // The circle of a wide map is the one in its middle square
+        let arena = arena(Shape::Circle, 1000.0, 600.0, (500.0, 300.0));
+        let start = zone_at(&arena, STARTED);
+        assert_corners(&start, [200.0, 0.0, 800.0, 600.0]);
+        assert!(!is_outside(Shape::Circle, &start, &Node { x: 500.0, y: 5.0 }));
+        assert!(is_outside(Shape::Circle, &start, &Node { x: 230.0, y: 30.0 }));
+        assert!(!is_outside(Shape::Rectangle, &start, &Node { x: 230.0, y: 30.0 }));
+
+        let last = zone_at(&arena, STARTED + 100_000);
+        let r = CONST::ROYALE_FINAL_ZONE / 2.0;
+        assert!(!is_outside(Shape::Circle, &last, &Node { x: 500.0 + r * 0.7, y: 300.0 - r * 0.7 }));
+        assert!(is_outside(Shape::Circle, &last, &Node { x: 500.0 + r * 0.8, y: 300.0 - r * 0.8 }));
+    }
+
+    #[test]
+    fn final_zones_fit_in_the_arena() {
+        for shape in [Shape::Rectangle, Shape::Circle] {
+            let map = Rect { top: 0.0, left: 0.0, right: 1000.0, bottom: 600.0 };
+            for _ in 0..200 {
+                let (x, y) = pick_center(shape, &map);
+                let final_zone = final_zone(&arena(shape, 1000.0, 600.0, (x, y)));
+                assert!((final_zone.right - final_zone.left - CONST::ROYALE_FINAL_ZONE).abs() < 1e-6);
+                assert!((final_zone.bottom - final_zone.top - CONST::ROYALE_FINAL_ZONE).abs() < 1e-6);
+                for (cx, cy) in [(final_zone.left, y), (final_zone.right, y), (x, final_zone.top), (x, final_zone.bottom)] {
+                    assert!(shape::contains(shape, &map, cx, cy, -1e-6));
+                }
+            }
+        }
+    }
+}
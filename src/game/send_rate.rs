@@ -0,0 +1,181 @@
+// Snapshot send rate: the simulation runs every GAME_LOOP_DELAY ms but each client
+// only gets a snapshot every SNAPSHOT_DELAY ms, slowed down further (up to
+// SNAPSHOT_MAX_DELAY) while the client falls behind acknowledging them.
+//
+// Every snapshot is numbered here, whatever its encoding, and acknowledged by the
+// client with "12,<seq>"; delta snapshots use the last acknowledged one as baseline.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::game::constants as CONST;
+
+pub struct SendRate {
+    // Game loop ticks between two snapshots
+    pub interval: u64,
+    pub next_tick: u64,
+    pub next_seq: u32,
+    pub acked: Option<u32>,
+}
+
+// Keyed by player index
+static RATES: Lazy<Mutex<HashMap<usize, SendRate>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn base_interval() -> u64 {
+    u64::max(1, (CONST::SNAPSHOT_DELAY / CONST::GAME_LOOP_DELAY) as u64)
+}
+
+fn max_interval() -> u64 {
+    u64::max(base_interval(), (CONST::SNAPSHOT_MAX_DELAY / CONST::GAME_LOOP_DELAY) as u64)
+}
+
+fn rate_of(rates: &mut HashMap<usize, SendRate>, player_id: usize, tick: u64) -> &mut SendRate {
+    rates.entry(player_id).or_insert_with(|| SendRate {
+        interval: base_interval(),
+        next_tick: tick,
+        next_seq: 1,
+        acked: None,
+    })
+}
+
+// Whether a player gets a snapshot on this tick; a new player gets one straight away
+pub fn is_due(player_id: usize, tick: u64) -> bool {
+    let mut rates = RATES.lock().unwrap();
+    let rate = rate_of(&mut rates, player_id, tick);
+
+    if tick >= rate.next_tick {
+        rate.next_tick = tick + rate.interval;
+        true
+    } else {
+        false
+    }
+}
+
+// Number the next snapshot of a player, along with the last one it acknowledged
+pub fn next_seq(player_id: usize) -> (u32, Option<u32>) {
+    let mut rates = RATES.lock().unwrap();
+    let rate = rate_of(&mut rates, player_id, 0);
+    let seq = rate.next_seq;
+    rate.next_seq = rate.next_seq.wrapping_add(1);
+    (seq, rate.acked)
+}
+
+// The client received a snapshot; older or duplicate acks are ignored
+pub fn ack(player_id: usize, seq: u32) {
+    let mut rates = RATES.lock().unwrap();
+    if let Some(rate) = rates.get_mut(&player_id) {
+        let newer = match rate.acked {
+            Some(acked) => seq.wrapping_sub(acked) as i32 > 0,
+            None => true,
+        };
+
+        // Never accept an ack for a snapshot that was not sent yet
+        if newer && rate.next_seq.wrapping_sub(seq) as i32 > 0 {
+            rate.acked = Some(seq);
+        }
+    }
+}
+
+// Snapshots sent since the last acknowledged one, None if the client never acknowledged any
+pub fn unacked(player_id: usize) -> Option<u32> {
+    let rates = RATES.lock().unwrap();
+    rates.get(&player_id)
+        .and_then(|rate| rate.acked.map(|acked| rate.next_seq.wrapping_sub(acked).wrapping_sub(1)))
+}
+
+// Adjust the interval after a snapshot from the number of snapshots the client has
+// not acknowledged yet: back off quickly when it lags, recover one tick at a time.
+// Clients that never acknowledge keep the base rate.
+pub fn adapt(player_id: usize) {
+    let unacked = unacked(player_id);
+    let mut rates = RATES.lock().unwrap();
+    if let (Some(rate), Some(unacked)) = (rates.get_mut(&player_id), unacked) {
+        if unacked > CONST::SNAPSHOT_LAG_THRESHOLD {
+            rate.interval = u64::min(rate.interval * 2, max_interval());
+        } else if unacked <= 1 && rate.interval > base_interval() {
+            rate.interval -= 1;
+        }
+    }
+}
+
+pub fn destroy(player_id: usize) {
+    RATES.lock().unwrap().remove(&player_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(player_id: usize) -> u64 {
+        RATES.lock().unwrap()[&player_id].interval
+    }
+
+    // Send a snapshot and have the client acknowledge the one `behind` snapshots older
+    fn send(player_id: usize, behind: u32) {
+        let (seq, _) = next_seq(player_id);
+        if seq > behind {
+            ack(player_id, seq - behind);
+        }
+        adapt(player_id);
+    }
+
+    #[test]
+    fn snapshots_are_due_every_interval() {
+        let player_id = usize::MAX;
+        assert!(is_due(player_id, 100));
+        for tick in 101..100 + base_interval() {
+            assert!(!is_due(player_id, tick));
+        }
+        assert!(is_due(player_id, 100 + base_interval()));
+        destroy(player_id);
+    }
+
+    #[test]
+    fn acks_count_the_snapshots_behind() {
+        let player_id = usize::MAX - 1;
+        assert_eq!(next_seq(player_id), (1, None));
+        assert_eq!(unacked(player_id), None);
+        next_seq(player_id);
+        next_seq(player_id);
+
+        ack(player_id, 2);
+        assert_eq!(next_seq(player_id), (4, Some(2)));
+        assert_eq!(unacked(player_id), Some(2));
+
+        // Older, duplicate and future acks change nothing
+        ack(player_id, 1);
+        ack(player_id, 2);
+        ack(player_id, 50);
+        assert_eq!(unacked(player_id), Some(2));
+        destroy(player_id);
+    }
+
+    #[test]
+    fn lagging_clients_are_slowed_down_then_recover() {
+        let player_id = usize::MAX - 2;
+        is_due(player_id, 0);
+
+        // Clients that never acknowledge keep the base rate
+        for _ in 0..20 {
+            send(player_id, u32::MAX);
+        }
+        assert_eq!(interval(player_id), base_interval());
+
+        // Falling behind doubles the interval up to the maximum
+        send(player_id, CONST::SNAPSHOT_LAG_THRESHOLD + 1);
+        assert_eq!(interval(player_id), u64::min(2 * base_interval(), max_interval()));
+        for _ in 0..10 {
+            send(player_id, CONST::SNAPSHOT_LAG_THRESHOLD + 1);
+        }
+        assert_eq!(interval(player_id), max_interval());
+
+        // Keeping up again brings it back one tick at a time
+        send(player_id, 0);
+        assert_eq!(interval(player_id), max_interval() - 1);
+        for _ in 0..max_interval() {
+            send(player_id, 0);
+        }
+        assert_eq!(interval(player_id), base_interval());
+        destroy(player_id);
+    }
+}
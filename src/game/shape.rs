@@ -0,0 +1,311 @@
+// Shape of the playable area of an arena: a rectangle of any aspect ratio filling
+// the map, or a circle inscribed in it, the classic slither layout. Spawning, bait
+// generation, keeping snakes inside and border deaths all go through here.
+
+use std::f64::consts::PI;
+use crate::game::collision::Rect;
+use crate::game::constants as CONST;
+use crate::models::snake::Node;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Rectangle,
+    Circle,
+}
+
+impl Shape {
+    pub fn parse(name: &str) -> Option<Shape> {
+        match name {
+            "rectangle" => Some(Shape::Rectangle),
+            "circle" => Some(Shape::Circle),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shape::Rectangle => "rectangle",
+            Shape::Circle => "circle",
+        }
+    }
+}
+
+// What happens to snakes reaching the edge of the map
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Border {
+    // Held back, sliding along the edge
+    Clamp,
+    // Held back, and dead on contact
+    Kill,
+    // Out on one side, back in on the other
+    Wrap,
+}
+
+impl Border {
+    pub fn parse(name: &str) -> Option<Border> {
+        match name {
+            "clamp" => Some(Border::Clamp),
+            "kill" => Some(Border::Kill),
+            "wrap" => Some(Border::Wrap),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Border::Clamp => "clamp",
+            Border::Kill => "kill",
+            Border::Wrap => "wrap",
+        }
+    }
+}
+
+fn center(map: &Rect) -> (f64, f64) {
+    ((map.left + map.right) / 2.0, (map.top + map.bottom) / 2.0)
+}
+
+fn radius(map: &Rect) -> f64 {
+    f64::min(map.right - map.left, map.bottom - map.top) / 2.0
+}
+
+// Smallest rectangle around the area: the map itself, or the square around the circle
+pub fn bounds(shape: Shape, map: &Rect) -> Rect {
+    match shape {
+        Shape::Rectangle => Rect {
+            top: map.top,
+            left: map.left,
+            right: map.right,
+            bottom: map.bottom,
+        },
+        Shape::Circle => {
+            let (cx, cy) = center(map);
+            let r = radius(map);
+            Rect {
+                top: cy - r,
+                left: cx - r,
+                right: cx + r,
+                bottom: cy + r,
+            }
+        }
+    }
+}
+
+pub fn area(shape: Shape, map: &Rect) -> f64 {
+    match shape {
+        Shape::Rectangle => (map.right - map.left) * (map.bottom - map.top),
+        Shape::Circle => PI * radius(map).powi(2),
+    }
+}
+
+// Random point at least margin away from the border
+pub fn random_point(shape: Shape, map: &Rect, margin: f64) -> (f64, f64) {
+    let (cx, cy) = center(map);
+    match shape {
+        Shape::Rectangle => {
+            let range = |low: f64, high: f64| if low < high { rand::random_range(low..high) } else { (low + high) / 2.0 };
+            (range(map.left + margin, map.right - margin), range(map.top + margin, map.bottom - margin))
+        }
+        Shape::Circle => {
+            // Uniform over the disc, not bunched up at the center
+            let r = f64::max(0.0, radius(map) - margin) * rand::random::<f64>().sqrt();
+            let angle = rand::random_range(0.0..2.0 * PI);
+            (cx + r * angle.cos(), cy + r * angle.sin())
+        }
+    }
+}
+
+// Where a snake spawns: away from the border, in the middle half of the area
+pub fn spawn_point(shape: Shape, map: &Rect) -> (f64, f64) {
+    match shape {
+        Shape::Rectangle => {
+            let (width, height) = (map.right - map.left, map.bottom - map.top);
+            (
+                rand::random_range(map.left + width / 4.0..map.right - width / 4.0),
+                rand::random_range(map.top + height / 4.0..map.bottom - height / 4.0),
+            )
+        }
+        Shape::Circle => random_point(shape, map, radius(map) / 2.0),
+    }
+}
+
+// Whether a point is at least margin away from the border
+pub fn contains(shape: Shape, map: &Rect, x: f64, y: f64, margin: f64) -> bool {
+    match shape {
+        Shape::Rectangle => {
+            x >= map.left + margin && x <= map.right - margin && y >= map.top + margin && y <= map.bottom - margin
+        }
+        Shape::Circle => {
+            let (cx, cy) = center(map);
+            ((x - cx).powi(2) + (y - cy).powi(2)).sqrt() <= radius(map) - margin
+        }
+    }
+}
+
+// Hold a node back inside the area
+pub fn clamp(shape: Shape, map: &Rect, node: &mut Node) {
+    let half = CONST::SNAKE_INITIAL_SIZE / 2.0;
+    match shape {
+        Shape::Rectangle => {
+            node.x = node.x.clamp(map.left + half, map.right - half);
+            node.y = node.y.clamp(map.top + half, map.bottom - half);
+        }
+        Shape::Circle => {
+            let (cx, cy) = center(map);
+            let (dx, dy) = (node.x - cx, node.y - cy);
+            let distance = (dx * dx + dy * dy).sqrt();
+            let max = radius(map) - half;
+            if distance > max {
+                node.x = cx + dx / distance * max;
+                node.y = cy + dy / distance * max;
+            }
+        }
+    }
+}
+
+// Whether a node touches the border
+pub fn touches_border(shape: Shape, map: &Rect, node: &Node) -> bool {
+    !contains(shape, map, node.x, node.y, CONST::SNAKE_INITIAL_SIZE / 2.0 + 0.001)
+}
+
+// The parts of the border closer than distance to a point: how close each one is and
+// the direction pointing away from it, into the area
+pub fn borders_near(shape: Shape, map: &Rect, x: f64, y: f64, distance: f64) -> Vec<(f64, f64, f64)> {
+    match shape {
+        Shape::Rectangle => [
+            (x - map.left, 1.0, 0.0),
+            (map.right - x, -1.0, 0.0),
+            (y - map.top, 0.0, 1.0),
+            (map.bottom - y, 0.0, -1.0),
+        ]
+            .into_iter()
+            .filter(|(d, _, _)| *d < distance)
+            .collect(),
+        Shape::Circle => {
+            let (cx, cy) = center(map);
+            let (dx, dy) = (cx - x, cy - y);
+            let from_center = (dx * dx + dy * dy).sqrt();
+            let d = radius(map) - from_center;
+            if d >= distance || from_center == 0.0 {
+                Vec::new()
+            } else {
+                vec![(d, dx / from_center, dy / from_center)]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1000 wide, 600 high: its circle has a radius of 300 around (1000, 600)
+    fn map() -> Rect {
+        Rect {
+            top: 300.0,
+            left: 500.0,
+            right: 1500.0,
+            bottom: 900.0,
+        }
+    }
+
+    fn distance_from_center(x: f64, y: f64) -> f64 {
+        ((x - 1000.0).powi(2) + (y - 600.0).powi(2)).sqrt()
+    }
+
+    #[test]
+    fn names_are_parsed_back() {
+        for shape in [Shape::Rectangle, Shape::Circle] {
+            assert!(Shape::parse(shape.name()) == Some(shape));
+        }
+        for border in [Border::Clamp, Border::Kill, Border::Wrap] {
+            assert!(Border::parse(border.name()) == Some(border));
+        }
+        assert!(Shape::parse("Circle").is_none() && Border::parse("").is_none());
+    }
+
+    #[test]
+    fn circles_fit_in_the_smaller_side_of_the_map() {
+        let map = map();
+        assert_eq!(area(Shape::Rectangle, &map), 600_000.0);
+        assert!((area(Shape::Circle, &map) - PI * 90_000.0).abs() < 1e-6);
+
+        let square = bounds(Shape::Circle, &map);
+        assert_eq!([square.left, square.top, square.right, square.bottom], [700.0, 300.0, 1300.0, 900.0]);
+        let same = bounds(Shape::Rectangle, &map);
+        assert_eq!([same.left, same.top, same.right, same.bottom], [500.0, 300.0, 1500.0, 900.0]);
+    }
+
+    #[test]
+    fn points_are_inside_with_a_margin() {
+        let map = map();
+        assert!(contains(Shape::Rectangle, &map, 510.0, 310.0, 10.0));
+        assert!(!contains(Shape::Rectangle, &map, 509.0, 600.0, 10.0));
+        assert!(!contains(Shape::Rectangle, &map, 1000.0, 891.0, 10.0));
+
+        // The corners of the map are outside the circle
+        assert!(contains(Shape::Rectangle, &map, 520.0, 320.0, 0.0));
+        assert!(!contains(Shape::Circle, &map, 520.0, 320.0, 0.0));
+        assert!(contains(Shape::Circle, &map, 1000.0, 310.0, 10.0));
+        assert!(!contains(Shape::Circle, &map, 1000.0, 310.0, 11.0));
+        assert!(contains(Shape::Circle, &map, 1200.0, 800.0, 0.0));
+        assert!(!contains(Shape::Circle, &map, 1220.0, 820.0, 0.0));
+    }
+
+    #[test]
+    fn nodes_are_clamped_to_the_shape() {
+        let map = map();
+        let half = CONST::SNAKE_INITIAL_SIZE / 2.0;
+
+        let mut node = Node { x: 2000.0, y: 0.0 };
+        clamp(Shape::Rectangle, &map, &mut node);
+        assert_eq!((node.x, node.y), (1500.0 - half, 300.0 + half));
+        assert!(touches_border(Shape::Rectangle, &map, &node));
+
+        // Back towards the center, keeping the direction it went out in
+        let mut node = Node { x: 1400.0, y: 900.0 };
+        clamp(Shape::Circle, &map, &mut node);
+        assert!((distance_from_center(node.x, node.y) - (300.0 - half)).abs() < 1e-9);
+        assert!(((node.y - 600.0) / (node.x - 1000.0) - 300.0 / 400.0).abs() < 1e-9);
+        assert!(touches_border(Shape::Circle, &map, &node));
+
+        // Inside, nothing changes
+        let mut node = Node { x: 1100.0, y: 700.0 };
+        clamp(Shape::Circle, &map, &mut node);
+        assert_eq!((node.x, node.y), (1100.0, 700.0));
+        assert!(!touches_border(Shape::Circle, &map, &node));
+    }
+
+    #[test]
+    fn random_points_stay_inside() {
+        let map = map();
+        for _ in 0..500 {
+            let (x, y) = random_point(Shape::Circle, &map, 50.0);
+            assert!(distance_from_center(x, y) <= 250.0 + 1e-9);
+            let (x, y) = random_point(Shape::Rectangle, &map, 50.0);
+            assert!(contains(Shape::Rectangle, &map, x, y, 50.0));
+
+            // Spawns keep to the middle of the area
+            let (x, y) = spawn_point(Shape::Circle, &map);
+            assert!(distance_from_center(x, y) <= 150.0 + 1e-9);
+            let (x, y) = spawn_point(Shape::Rectangle, &map);
+            assert!((750.0..=1250.0).contains(&x) && (450.0..=750.0).contains(&y));
+        }
+
+        // A margin too large for the map leaves its center
+        assert_eq!(random_point(Shape::Rectangle, &map, 400.0).1, 600.0);
+        assert_eq!(random_point(Shape::Circle, &map, 400.0), (1000.0, 600.0));
+    }
+
+    #[test]
+    fn borders_near_point_back_into_the_area() {
+        let map = map();
+        let near = borders_near(Shape::Rectangle, &map, 520.0, 880.0, 50.0);
+        assert_eq!(near, vec![(20.0, 1.0, 0.0), (20.0, 0.0, -1.0)]);
+        assert!(borders_near(Shape::Rectangle, &map, 1000.0, 600.0, 50.0).is_empty());
+
+        let near = borders_near(Shape::Circle, &map, 1000.0, 320.0, 50.0);
+        assert_eq!(near, vec![(20.0, 0.0, 1.0)]);
+        assert!(borders_near(Shape::Circle, &map, 1000.0, 600.0, 50.0).is_empty());
+        assert!(borders_near(Shape::Circle, &map, 1000.0, 400.0, 50.0).is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+// Team mode: an arena created with teams splits its players, bots included, into
+// that many teams as they join. Teammates pass through each other unless
+// TEAM_FRIENDLY_FIRE is set, wear their team's color instead of their own skin, and
+// the lengths of their snakes add up to a team score broadcast to the arena.
+
+use crate::game::arena;
+use crate::game::constants as CONST;
+use crate::models::player::{self, Player};
+
+// Team for a player joining an arena: the one with the fewest players, or none
+// outside team mode
+pub fn pick(arena_id: usize) -> Option<usize> {
+    let teams = arena::read(arena_id)?.teams;
+    if teams == 0 {
+        return None;
+    }
+
+    let mut sizes = vec![0; teams];
+    for player in player::keys_in(arena_id).into_iter().filter_map(player::read) {
+        if let Some(team) = player.team.filter(|&team| team < teams) {
+            sizes[team] += 1;
+        }
+    }
+    (0..teams).min_by_key(|&team| sizes[team])
+}
+
+pub fn color(team: usize) -> i32 {
+    CONST::TEAM_COLORS[team % CONST::TEAM_COLORS.len()]
+}
+
+// Whether two players are on the same team
+pub fn allies(a: &Player, b: &Player) -> bool {
+    a.team.is_some() && a.team == b.team
+}
+
+// Whether the head of one player kills another one running into its body
+pub fn can_kill(a: &Player, b: &Player) -> bool {
+    CONST::TEAM_FRIENDLY_FIRE || !allies(a, b)
+}
+
+// "$29,player,team,skin" for a player of a team, empty otherwise
+pub fn team_message(player_id: usize, player: &Player) -> String {
+    match player.team {
+        Some(team) => format!(
+            "{}{}{},{},{}",
+            CONST::COMM_START_NEW_MESS,
+            CONST::COMM_TEAM,
+            player_id,
+            team,
+            player.snake.skin
+        ),
+        None => String::new(),
+    }
+}
+
+// "$32,score,score,..." with the total length of each team's snakes, for arenas in
+// team mode
+pub fn scores_message(arena_id: usize) -> Option<String> {
+    let teams = arena::read(arena_id)?.teams;
+    if teams == 0 {
+        return None;
+    }
+
+    let mut scores = vec![0; teams];
+    for player in player::keys_in(arena_id).into_iter().filter_map(player::read) {
+        if let Some(team) = player.team.filter(|&team| team < teams) {
+            scores[team] += player.snake.nodes.len();
+        }
+    }
+    let scores: Vec<String> = scores.iter().map(|score| score.to_string()).collect();
+    Some(format!("{}{}{}", CONST::COMM_START_NEW_MESS, CONST::COMM_TEAM_SCORES, scores.join(",")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::snake;
+    use crate::network::transport::Peer;
+
+    fn team_arena(teams: usize) -> usize {
+        let mut settings = arena::room_settings();
+        settings.teams = teams;
+        arena::create("teams test", &settings, false)
+    }
+
+    // A player of the arena on a team, with a snake of the given length
+    fn join(arena_id: usize, peer: Peer, team: Option<usize>, length: usize) -> usize {
+        let arena = arena::read(arena_id).unwrap();
+        let mut snake = snake::create(length as f64, 0, CONST::SNAKE_SPEED, &arena);
+        while snake.nodes.len() < length {
+            snake::grow(&mut snake);
+        }
+        player::create(String::new(), String::new(), 0, String::new(), snake, peer, arena_id);
+        let id = player::find_id_by_addr(&peer).unwrap();
+        player::update_player_team(id, team);
+        id
+    }
+
+    #[test]
+    fn players_join_the_smallest_team() {
+        let arena_id = team_arena(3);
+        assert_eq!(pick(arena_id), Some(0));
+        let mut players = Vec::new();
+        for (k, expected) in [0, 1, 2, 0, 1].into_iter().enumerate() {
+            let team = pick(arena_id);
+            assert_eq!(team, Some(expected));
+            players.push(join(arena_id, Peer::Memory(u64::MAX - 300 - k as u64), team, 5));
+        }
+
+        // Teams that lost players get the next ones
+        player::destroy(players[2]);
+        assert_eq!(pick(arena_id), Some(2));
+        player::destroy(players[0]);
+        player::destroy(players[3]);
+        assert_eq!(pick(arena_id), Some(0));
+
+        // No teams outside team mode, nor for arenas that are gone
+        let free_for_all = team_arena(0);
+        assert_eq!(pick(free_for_all), None);
+        arena::close(free_for_all);
+        arena::close(arena_id);
+        assert_eq!(pick(arena_id), None);
+        players.into_iter().for_each(player::destroy);
+    }
+
+    #[test]
+    fn teammates_cannot_kill_each_other() {
+        let arena_id = team_arena(2);
+        let ids = [
+            join(arena_id, Peer::Memory(u64::MAX - 310), Some(0), 5),
+            join(arena_id, Peer::Memory(u64::MAX - 311), Some(0), 5),
+            join(arena_id, Peer::Memory(u64::MAX - 312), Some(1), 5),
+            join(arena_id, Peer::Memory(u64::MAX - 313), None, 5),
+            join(arena_id, Peer::Memory(u64::MAX - 314), None, 5),
+        ];
+        let [a, b, c, loner, other_loner] = ids.map(|id| player::read(id).unwrap());
+
+        assert!(allies(&a, &b) && !allies(&a, &c));
+        assert_eq!(can_kill(&a, &b), CONST::TEAM_FRIENDLY_FIRE);
+        assert!(can_kill(&a, &c) && can_kill(&c, &a));
+        assert!(can_kill(&a, &loner) && can_kill(&loner, &a));
+        // Players without a team are nobody's allies, not even each other's
+        assert!(!allies(&loner, &other_loner) && can_kill(&loner, &other_loner));
+
+        ids.into_iter().for_each(player::destroy);
+        arena::close(arena_id);
+    }
+
+    #[test]
+    fn team_scores_add_up_the_lengths_of_their_snakes() {
+        let arena_id = team_arena(3);
+        let ids = [
+            join(arena_id, Peer::Memory(u64::MAX - 320), Some(0), 5),
+            join(arena_id, Peer::Memory(u64::MAX - 321), Some(0), 12),
+            join(arena_id, Peer::Memory(u64::MAX - 322), Some(2), 7),
+            // Not counted anywhere
+            join(arena_id, Peer::Memory(u64::MAX - 323), None, 30),
+        ];
+        assert_eq!(scores_message(arena_id), Some("$32,17,0,7".to_string()));
+
+        let player = player::read(ids[2]).unwrap();
+        assert_eq!(team_message(ids[2], &player), format!("$29,{},2,{}", ids[2], player.snake.skin));
+        assert_eq!(team_message(ids[3], &player::read(ids[3]).unwrap()), "");
+        assert_eq!(color(1), CONST::TEAM_COLORS[1]);
+
+        ids.into_iter().for_each(player::destroy);
+        arena::close(arena_id);
+        assert_eq!(scores_message(arena_id), None);
+    }
+}
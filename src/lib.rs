@@ -6,7 +6,35 @@ pub mod models {
 
 pub mod game {
     pub mod constants;
+    pub mod clock;
+    pub mod input;
     pub mod collision;
+    pub mod shape;
+    pub mod interest;
+    pub mod delta;
+    pub mod send_rate;
+    pub mod arena;
+    pub mod teams;
+    pub mod rounds;
+    pub mod royale;
+    pub mod bots;
     pub mod game_server;
     pub mod listen_server;
-} 
\ No newline at end of file
+}
+
+pub mod network {
+    pub mod reliable;
+    pub mod fragment;
+    pub mod compression;
+    pub mod crypto;
+    pub mod rate_limit;
+    pub mod outbound;
+    pub mod transport;
+    pub mod udp;
+    pub mod websocket;
+    pub mod memory;
+}
+
+pub mod client {
+    pub mod headless;
+}
@@ -1,7 +1,5 @@
-use slither_io_server::models::{bait, snake};
 use slither_io_server::game::listen_server;
 use std::env;
-use std::future::IntoFuture;
 
 
 fn main() {
@@ -1,7 +1,10 @@
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use once_cell::sync::Lazy;
 
 pub struct Bait {
+    pub id: usize,
+    pub arena: usize,
     pub x: f64,
     pub y: f64,
     pub color: String,
@@ -10,8 +13,14 @@ pub struct Bait {
 
 static BAITS: Lazy<Mutex<Vec<Bait>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
-pub fn create(x: f64, y: f64, color: String, size: f64) -> Bait {
+// Positions in the vector shift when a bait is destroyed, so baits are looked up
+// by a stable id instead
+static NEXT_BAIT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn create(arena: usize, x: f64, y: f64, color: String, size: f64) -> Bait {
     let new_bait = Bait {
+        id: NEXT_BAIT_ID.fetch_add(1, Ordering::Relaxed),
+        arena,
         x,
         y,
         color,
@@ -23,32 +32,70 @@ pub fn create(x: f64, y: f64, color: String, size: f64) -> Bait {
 }
 
 pub fn read(id: usize) -> Option<Bait> {
-    BAITS.lock().unwrap().get(id).cloned()
+    BAITS.lock().unwrap().iter().find(|bait| bait.id == id).cloned()
 }
 
 pub fn destroy(id: usize) {
     let mut baits = BAITS.lock().unwrap();
-    if id < baits.len() {
-        baits.remove(id);
+    if let Some(index) = baits.iter().position(|bait| bait.id == id) {
+        baits.remove(index);
     }
 }
 
-pub fn keys() -> Vec<usize> {
+// Remove every bait of an arena
+pub fn destroy_in(arena: usize) {
+    BAITS.lock().unwrap().retain(|bait| bait.arena != arena);
+}
+
+pub fn all_in(arena: usize) -> Vec<Bait> {
     let baits = BAITS.lock().unwrap();
-    (0..baits.len()).collect()
+    baits.iter().filter(|bait| bait.arena == arena).cloned().collect()
 }
 
-pub fn length() -> usize {
-    BAITS.lock().unwrap().len()
+pub fn length_in(arena: usize) -> usize {
+    let baits = BAITS.lock().unwrap();
+    baits.iter().filter(|bait| bait.arena == arena).count()
 }
 
 impl Clone for Bait {
     fn clone(&self) -> Self {
         Bait {
+            id: self.id,
+            arena: self.arena,
             x: self.x,
             y: self.y,
             color: self.color.clone(),
             size: self.size,
         }
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn baits_are_kept_apart_per_arena() {
+        let (first, second) = (usize::MAX, usize::MAX - 1);
+        let a = create(first, 1.0, 2.0, "#fff".to_string(), 1.0);
+        let b = create(first, 3.0, 4.0, "#fff".to_string(), 2.0);
+        let c = create(second, 5.0, 6.0, "#fff".to_string(), 3.0);
+        assert!(a.id != b.id && b.id != c.id);
+
+        let ids = |arena: usize| all_in(arena).iter().map(|bait| bait.id).collect::<Vec<usize>>();
+        assert_eq!(ids(first), vec![a.id, b.id]);
+        assert_eq!(ids(second), vec![c.id]);
+        assert_eq!((length_in(first), length_in(second)), (2, 1));
+
+        // Ids stay valid as the baits before them go
+        destroy(a.id);
+        assert!(read(a.id).is_none());
+        assert_eq!(read(b.id).unwrap().x, 3.0);
+        destroy(a.id);
+        assert_eq!(ids(first), vec![b.id]);
+
+        destroy_in(first);
+        assert!(all_in(first).is_empty());
+        assert_eq!(ids(second), vec![c.id]);
+        destroy_in(second);
+    }
+}
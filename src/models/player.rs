@@ -1,8 +1,8 @@
+use std::net::IpAddr;
 use std::sync::Mutex;
-use std::net::SocketAddr;
 use once_cell::sync::Lazy;
 use crate::models::snake::Snake;
-use tokio::sync::Mutex as TokioMutex;
+use crate::network::transport::Peer;
 
 pub struct Player {
     pub id: String,
@@ -10,12 +10,20 @@ pub struct Player {
     pub score: i32,
     pub current_rank: String,
     pub snake: Snake,
-    pub addr: SocketAddr,
+    pub addr: Peer,
+    pub arena: usize,
+    pub team: Option<usize>,
+    pub kills: u32,         // snakes that ran into this one during the current round
+    pub dead: bool,         // died during the current round
     pub move_x: f64,
     pub move_y: f64,
     pub window_w: f64,
     pub window_h: f64,
     pub last_seen: std::time::Instant,
+    pub last_input_seq: u32,
+    pub rtt: f64,           // smoothed round trip time in ms, 0 until the first pong
+    pub rtt_jitter: f64,    // smoothed deviation of the round trip time in ms
+    pub lost: bool,         // timed out, removed unless heard from within the grace period
 }
 
 impl Clone for Player {
@@ -27,11 +35,19 @@ impl Clone for Player {
             current_rank: self.current_rank.clone(),
             snake: self.snake.clone(),
             addr: self.addr,
+            arena: self.arena,
+            team: self.team,
+            kills: self.kills,
+            dead: self.dead,
             move_x: self.move_x,
             move_y: self.move_y,
             window_w: self.window_w,
             window_h: self.window_h,
             last_seen: self.last_seen,
+            last_input_seq: self.last_input_seq,
+            rtt: self.rtt,
+            rtt_jitter: self.rtt_jitter,
+            lost: self.lost,
         }
     }
 }
@@ -39,7 +55,7 @@ impl Clone for Player {
 // Use pub here to make it accessible from game_server.rs
 pub static PLAYERS: Lazy<Mutex<Vec<Option<Player>>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
-pub fn create(id: String, name: String, score: i32, current_rank: String, snake: Snake, addr: SocketAddr) -> Player {
+pub fn create(id: String, name: String, score: i32, current_rank: String, snake: Snake, addr: Peer, arena: usize) -> Player {
     let player = Player {
         id,
         name,
@@ -47,18 +63,25 @@ pub fn create(id: String, name: String, score: i32, current_rank: String, snake:
         current_rank,
         snake,
         addr,
+        arena,
+        team: None,
+        kills: 0,
+        dead: false,
         move_x: 0.0,
         move_y: 0.0,
         window_w: 0.0,
         window_h: 0.0,
         last_seen: std::time::Instant::now(),
+        last_input_seq: 0,
+        rtt: 0.0,
+        rtt_jitter: 0.0,
+        lost: false,
     };
     
     // Add player to collection
     let mut players = PLAYERS.lock().unwrap();
     players.push(Some(player.clone()));
     
-    println!("{}:{}", addr.ip(), addr.port());
     player
 }
 
@@ -80,9 +103,12 @@ pub fn read(id: usize) -> Option<Player> {
     }
 }
 
+// Leave an empty slot behind so the indices of the other players stay valid
 pub fn destroy(id: usize) {
     let mut players = PLAYERS.lock().unwrap();
-    players.remove(id);
+    if id < players.len() {
+        players[id] = None;
+    }
 }
 
 pub fn keys() -> Vec<usize> {
@@ -98,6 +124,16 @@ pub fn length() -> usize {
     keys().len()
 }
 
+// Players of an arena
+pub fn keys_in(arena: usize) -> Vec<usize> {
+    let players = PLAYERS.lock().unwrap();
+    players.iter()
+        .enumerate()
+        .filter(|(_, player)| player.as_ref().is_some_and(|player| player.arena == arena))
+        .map(|(i, _)| i)
+        .collect()
+}
+
 pub fn update_xy(player: &mut Player, x: f64, y: f64) {
     player.move_x = x;
     player.move_y = y;
@@ -116,6 +152,24 @@ pub fn update_player_xy(id: usize, x: f64, y: f64, window_w: f64, window_h: f64)
     }
 }
 
+// Record the sequence number of an input, returns false if the input is stale or
+// a duplicate and must be dropped. Inputs without a sequence number are always applied.
+pub fn accept_input(id: usize, seq: Option<u32>) -> bool {
+    let mut players = PLAYERS.lock().unwrap();
+    if let Some(Some(player)) = players.get_mut(id) {
+        player.last_seen = std::time::Instant::now();
+        
+        if let Some(seq) = seq {
+            if seq.wrapping_sub(player.last_input_seq) as i32 <= 0 {
+                return false;
+            }
+            player.last_input_seq = seq;
+        }
+        return true;
+    }
+    false
+}
+
 pub fn update_player_name(id: usize, name: String) {
     let mut players = PLAYERS.lock().unwrap();
     if id < players.len() && players[id].is_some() {
@@ -126,6 +180,13 @@ pub fn update_player_name(id: usize, name: String) {
     }
 }
 
+pub fn update_player_team(id: usize, team: Option<usize>) {
+    let mut players = PLAYERS.lock().unwrap();
+    if let Some(Some(player)) = players.get_mut(id) {
+        player.team = team;
+    }
+}
+
 pub fn update_player_acceleration(id: usize, accelerate: bool) {
     let mut players = PLAYERS.lock().unwrap();
     if id < players.len() && players[id].is_some() {
@@ -136,6 +197,32 @@ pub fn update_player_acceleration(id: usize, accelerate: bool) {
     }
 }
 
+// Mark a player dead and return whether it was still alive
+pub fn mark_dead(id: usize) -> bool {
+    let mut players = PLAYERS.lock().unwrap();
+    match players.get_mut(id) {
+        Some(Some(player)) => !std::mem::replace(&mut player.dead, true),
+        _ => false,
+    }
+}
+
+pub fn add_kill(id: usize) {
+    let mut players = PLAYERS.lock().unwrap();
+    if let Some(Some(player)) = players.get_mut(id) {
+        player.kills += 1;
+    }
+}
+
+// Give a player a new snake and a clean record for a new round
+pub fn respawn(id: usize, new_snake: Snake) {
+    let mut players = PLAYERS.lock().unwrap();
+    if let Some(Some(player)) = players.get_mut(id) {
+        player.snake = new_snake;
+        player.kills = 0;
+        player.dead = false;
+    }
+}
+
 pub fn update_player_snake(id: usize, new_snake: Snake) {
     let mut players = PLAYERS.lock().unwrap();
     if id < players.len() && players[id].is_some() {
@@ -154,7 +241,22 @@ pub fn grow_player_snake(id: usize) {
     }
 }
 
-pub fn find_id_by_addr(addr: &SocketAddr) -> Option<usize> {
+// Fold a new round trip sample into the smoothed RTT and jitter (as TCP does, RFC 6298)
+pub fn update_player_rtt(id: usize, sample: f64) {
+    let mut players = PLAYERS.lock().unwrap();
+    if let Some(Some(player)) = players.get_mut(id) {
+        if player.rtt == 0.0 {
+            player.rtt = sample;
+            player.rtt_jitter = sample / 2.0;
+        } else {
+            player.rtt_jitter = 0.75 * player.rtt_jitter + 0.25 * (player.rtt - sample).abs();
+            player.rtt = 0.875 * player.rtt + 0.125 * sample;
+        }
+        player.last_seen = std::time::Instant::now();
+    }
+}
+
+pub fn find_id_by_addr(addr: &Peer) -> Option<usize> {
     let players = PLAYERS.lock().unwrap();
     for (i, player_opt) in players.iter().enumerate() {
         if let Some(player) = player_opt {
@@ -166,6 +268,15 @@ pub fn find_id_by_addr(addr: &SocketAddr) -> Option<usize> {
     None
 }
 
+// Number of players connected from an address
+pub fn count_by_ip(ip: IpAddr) -> usize {
+    let players = PLAYERS.lock().unwrap();
+    players.iter()
+        .flatten()
+        .filter(|player| player.addr.ip() == Some(ip))
+        .count()
+}
+
 pub fn update_last_seen(id: usize) {
     let mut players = PLAYERS.lock().unwrap();
     if id < players.len() && players[id].is_some() {
@@ -175,27 +286,85 @@ pub fn update_last_seen(id: usize) {
     }
 }
 
-// Remove players that haven't been seen in a while (UDP connection management)
-pub fn clean_inactive_players(timeout_secs: u64) -> Vec<usize> {
+// Find players that haven't been seen in a while (UDP connection management).
+// A player silent for more than timeout_secs is marked as lost and stops boosting,
+// it is returned for removal once it stayed silent for grace_secs more.
+pub fn clean_inactive_players(timeout_secs: u64, grace_secs: u64) -> Vec<usize> {
     let mut inactive_ids = Vec::new();
     let mut players = PLAYERS.lock().unwrap();
     
-    for (i, player_opt) in players.iter().enumerate() {
+    for (i, player_opt) in players.iter_mut().enumerate() {
         if let Some(player) = player_opt {
             let elapsed = player.last_seen.elapsed().as_secs();
-            if elapsed > timeout_secs {
+            if elapsed > timeout_secs + grace_secs {
                 inactive_ids.push(i);
+            } else if elapsed > timeout_secs {
+                if !player.lost {
+                    println!("Player {} timed out, removing in {} second(s)", i, grace_secs);
+                    player.lost = true;
+                    player.snake.accelerate = false;
+                }
+            } else if player.lost {
+                println!("Player {} is back", i);
+                player.lost = false;
             }
         }
     }
     
-    // Remove inactive players
-    for id in &inactive_ids {
-        // if *id < players.len() {
-        //     players[*id] = None;
-        // }
-        players.remove(*id);
-    }
-    
     inactive_ids
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use crate::models::snake::Node;
+
+    fn new_player(peer: Peer) -> usize {
+        let snake = Snake {
+            length: 5.0,
+            skin: 0,
+            speed: 0.0,
+            current_speed_sec: 0.0,
+            nodes: vec![Node { x: 1000.0, y: 1000.0 }],
+            current_angle: 0.0,
+            rotate_angle: 0.0,
+            is_dead: false,
+            accelerate: false,
+            accelerate_time: 0.0,
+        };
+        create("id".to_string(), "test".to_string(), 0, "id".to_string(), snake, peer, 0);
+        find_id_by_addr(&peer).unwrap()
+    }
+
+    fn silent_for(id: usize, seconds: u64) {
+        let mut players = PLAYERS.lock().unwrap();
+        players[id].as_mut().unwrap().last_seen = Instant::now() - Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn silent_players_are_lost_then_removed_unless_they_come_back() {
+        let id = new_player(Peer::Memory(u64::MAX));
+        update_player_acceleration(id, true);
+        assert!(!clean_inactive_players(5, 10).contains(&id));
+        assert!(!read(id).unwrap().lost);
+
+        // Past the timeout: lost, and no longer boosting
+        silent_for(id, 6);
+        assert!(!clean_inactive_players(5, 10).contains(&id));
+        let player = read(id).unwrap();
+        assert!(player.lost);
+        assert!(!player.snake.accelerate);
+
+        // Heard from again within the grace period
+        update_last_seen(id);
+        assert!(!clean_inactive_players(5, 10).contains(&id));
+        assert!(!read(id).unwrap().lost);
+
+        // Silent past the grace period as well
+        silent_for(id, 16);
+        assert!(clean_inactive_players(5, 10).contains(&id));
+        destroy(id);
+        assert!(read(id).is_none());
+    }
+}
@@ -1,8 +1,9 @@
 use std::sync::Mutex;
-use std::f64::consts::PI;
 use once_cell::sync::Lazy;
-use rand::Rng;
+use crate::game::arena::Arena;
 use crate::game::constants as CONST;
+use crate::game::collision;
+use crate::game::shape::{self, Border};
 
 pub struct Node {
     pub x: f64,
@@ -50,11 +51,6 @@ impl Clone for Snake {
 
 static SNAKES: Lazy<Mutex<Vec<Option<Snake>>>> = Lazy::new(|| Mutex::new(Vec::new()));
 
-fn random(low: f64, high: f64) -> f64 {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(low..high)
-}
-
 fn create_first_five_nodes(initial_x: f64, initial_y: f64) -> Vec<Node> {
     let mut nodes = Vec::new();
     
@@ -73,19 +69,9 @@ fn create_first_five_nodes(initial_x: f64, initial_y: f64) -> Vec<Node> {
     nodes
 }
 
-pub fn create(length: f64, skin: i32, speed: f64) -> Snake {
-    let map_border_w = CONST::BORDER_WIDTH - CONST::MAP_WIDTH;
-    let map_border_h = CONST::BORDER_HEIGHT - CONST::MAP_HEIGHT;
-    
-    let initial_x = random(
-        map_border_w / 2.0 + 500.0,
-        map_border_w / 2.0 + CONST::MAP_WIDTH - 500.0
-    );
-    
-    let initial_y = random(
-        map_border_h / 2.0 + 500.0,
-        map_border_h / 2.0 + CONST::MAP_HEIGHT - 500.0
-    );
+// Create a snake somewhere in the middle of the arena, away from the borders
+pub fn create(length: f64, skin: i32, speed: f64, arena: &Arena) -> Snake {
+    let (initial_x, initial_y) = shape::spawn_point(arena.shape, &arena.map);
     
     let default_nodes = create_first_five_nodes(initial_x, initial_y);
     
@@ -169,7 +155,45 @@ pub fn rotate(snake: &mut Snake) {
     }
 }
 
-pub fn move_snake(snake: &mut Snake, to_x: f64, to_y: f64, center_x: f64, center_y: f64) {
+// Keep a node in the arena: held back at the edges, or brought in from the other
+// side when the map wraps around
+fn limit_to_map(node: &mut Node, arena: &Arena) {
+    let map = &arena.map;
+    if arena.border == Border::Wrap {
+        let (width, height) = (map.right - map.left, map.bottom - map.top);
+        node.x = map.left + (node.x - map.left).rem_euclid(width);
+        node.y = map.top + (node.y - map.top).rem_euclid(height);
+        return;
+    }
+    shape::clamp(arena.shape, map, node);
+}
+
+// Whether the head of a snake touches the edge of the arena
+pub fn touches_border(snake: &Snake, arena: &Arena) -> bool {
+    shape::touches_border(arena.shape, &arena.map, &snake.nodes[0])
+}
+
+// Index of the first node further than the neck from the head, measured along the
+// body: the nodes before it overlap the head while the snake is straight, and on a
+// fresh snake they are all stacked on the spawn point
+pub fn neck_end(snake: &Snake, arena: &Arena) -> usize {
+    let mut length = 0.0;
+    for k in 1..snake.nodes.len() {
+        let (prev, node) = (&snake.nodes[k - 1], &snake.nodes[k]);
+        let (x, y) = if arena.border == Border::Wrap {
+            collision::nearest_image(node.x, node.y, prev.x, prev.y, &arena.map)
+        } else {
+            (node.x, node.y)
+        };
+        length += ((x - prev.x).powi(2) + (y - prev.y).powi(2)).sqrt();
+        if length > CONST::SNAKE_SELF_COLLISION_NECK {
+            return k;
+        }
+    }
+    snake.nodes.len()
+}
+
+pub fn move_snake(snake: &mut Snake, to_x: f64, to_y: f64, center_x: f64, center_y: f64, arena: &Arena) {
     if CONST::SERVER_CURRENT_UPDATE_PLAYER_METHOD == 1 {
         let n = snake.nodes.len();
         
@@ -189,29 +213,24 @@ pub fn move_snake(snake: &mut Snake, to_x: f64, to_y: f64, center_x: f64, center
         let vel_x = norm_x * CONST::SNAKE_SPEED;
         let vel_y = norm_y * CONST::SNAKE_SPEED;
         
-        snake.nodes[0].x = snake.nodes[0].x + vel_x;
-        snake.nodes[0].y = snake.nodes[0].y + vel_y;
+        snake.nodes[0].x += vel_x;
+        snake.nodes[0].y += vel_y;
         
         // Limit by MAP_BORDER
-        if snake.nodes[0].x - CONST::SNAKE_INITIAL_SIZE / 2.0 < CONST::OFFSET_X {
-            snake.nodes[0].x = CONST::OFFSET_X + CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
-        if snake.nodes[0].y - CONST::SNAKE_INITIAL_SIZE / 2.0 < CONST::OFFSET_Y {
-            snake.nodes[0].y = CONST::OFFSET_Y + CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
-        if snake.nodes[0].x + CONST::SNAKE_INITIAL_SIZE / 2.0 > CONST::TRUE_MAP_WIDTH {
-            snake.nodes[0].x = CONST::TRUE_MAP_WIDTH - CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
-        if snake.nodes[0].y + CONST::SNAKE_INITIAL_SIZE / 2.0 > CONST::TRUE_MAP_HEIGHT {
-            snake.nodes[0].y = CONST::TRUE_MAP_HEIGHT - CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
+        limit_to_map(&mut snake.nodes[0], arena);
     } else if CONST::SERVER_CURRENT_UPDATE_PLAYER_METHOD == 2 {
         // new method
         let n = snake.nodes.len();
         
         for i in (1..n).rev() {
-            let dx = snake.nodes[i - 1].x - snake.nodes[i].x;
-            let dy = snake.nodes[i - 1].y - snake.nodes[i].y;
+            // Across the edge of a wrapping map, the previous node is just over the edge
+            let (prev_x, prev_y) = if arena.border == Border::Wrap {
+                collision::nearest_image(snake.nodes[i - 1].x, snake.nodes[i - 1].y, snake.nodes[i].x, snake.nodes[i].y, &arena.map)
+            } else {
+                (snake.nodes[i - 1].x, snake.nodes[i - 1].y)
+            };
+            let dx = prev_x - snake.nodes[i].x;
+            let dy = prev_y - snake.nodes[i].y;
             let dist = (dx * dx + dy * dy).sqrt();
             let node_dist = dist / CONST::SNAKE_NODE_INITIAL_DISTANCE;
             
@@ -230,18 +249,7 @@ pub fn move_snake(snake: &mut Snake, to_x: f64, to_y: f64, center_x: f64, center
             snake.nodes[i].y += vel_y;
             
             // Limit by MAP_BORDER
-            if snake.nodes[i].x - CONST::SNAKE_INITIAL_SIZE / 2.0 < CONST::OFFSET_X {
-                snake.nodes[i].x = CONST::OFFSET_X + CONST::SNAKE_INITIAL_SIZE / 2.0;
-            }
-            if snake.nodes[i].y - CONST::SNAKE_INITIAL_SIZE / 2.0 < CONST::OFFSET_Y {
-                snake.nodes[i].y = CONST::OFFSET_Y + CONST::SNAKE_INITIAL_SIZE / 2.0;
-            }
-            if snake.nodes[i].x + CONST::SNAKE_INITIAL_SIZE / 2.0 > CONST::TRUE_MAP_WIDTH {
-                snake.nodes[i].x = CONST::TRUE_MAP_WIDTH - CONST::SNAKE_INITIAL_SIZE / 2.0;
-            }
-            if snake.nodes[i].y + CONST::SNAKE_INITIAL_SIZE / 2.0 > CONST::TRUE_MAP_HEIGHT {
-                snake.nodes[i].y = CONST::TRUE_MAP_HEIGHT - CONST::SNAKE_INITIAL_SIZE / 2.0;
-            }
+            limit_to_map(&mut snake.nodes[i], arena);
         }
         
         let dx = to_x - center_x / 2.0;
@@ -263,22 +271,11 @@ pub fn move_snake(snake: &mut Snake, to_x: f64, to_y: f64, center_x: f64, center
             CONST::SNAKE_SPEED
         };
         
-        snake.nodes[0].x = snake.nodes[0].x + vel_x;
-        snake.nodes[0].y = snake.nodes[0].y + vel_y;
+        snake.nodes[0].x += vel_x;
+        snake.nodes[0].y += vel_y;
         
         // Limit by MAP_BORDER
-        if snake.nodes[0].x - CONST::SNAKE_INITIAL_SIZE / 2.0 < CONST::OFFSET_X {
-            snake.nodes[0].x = CONST::OFFSET_X + CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
-        if snake.nodes[0].y - CONST::SNAKE_INITIAL_SIZE / 2.0 < CONST::OFFSET_Y {
-            snake.nodes[0].y = CONST::OFFSET_Y + CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
-        if snake.nodes[0].x + CONST::SNAKE_INITIAL_SIZE / 2.0 > CONST::TRUE_MAP_WIDTH {
-            snake.nodes[0].x = CONST::TRUE_MAP_WIDTH - CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
-        if snake.nodes[0].y + CONST::SNAKE_INITIAL_SIZE / 2.0 > CONST::TRUE_MAP_HEIGHT {
-            snake.nodes[0].y = CONST::TRUE_MAP_HEIGHT - CONST::SNAKE_INITIAL_SIZE / 2.0;
-        }
+        limit_to_map(&mut snake.nodes[0], arena);
     }
 }
 
@@ -286,4 +283,114 @@ pub fn shorter(snake: &mut Snake) {
     if !snake.nodes.is_empty() {
         snake.nodes.pop();
     }
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::arena;
+    use crate::game::shape::Shape;
+
+    // An arena with the given border and shape, as it is when the test starts
+    fn test_arena(shape: Shape, border: Border) -> Arena {
+        let mut settings = arena::room_settings();
+        settings.shape = shape;
+        settings.border = border;
+        let id = arena::create("snake test", &settings, false);
+        let arena = arena::read(id).unwrap();
+        arena::close(id);
+        arena
+    }
+
+    fn snake_of(points: &[(f64, f64)]) -> Snake {
+        Snake {
+            length: points.len() as f64,
+            skin: 0,
+            speed: CONST::SNAKE_SPEED,
+            current_speed_sec: 0.0,
+            nodes: points.iter().map(|&(x, y)| Node { x, y }).collect(),
+            current_angle: 0.0,
+            rotate_angle: 0.0,
+            is_dead: false,
+            accelerate: false,
+            accelerate_time: 0.0,
+        }
+    }
+
+    #[test]
+    fn clamp_and_kill_borders_hold_snakes_back() {
+        let half = CONST::SNAKE_INITIAL_SIZE / 2.0;
+        for border in [Border::Clamp, Border::Kill] {
+            let arena = test_arena(Shape::Rectangle, border);
+            let map = &arena.map;
+            let mut node = Node { x: map.right + 50.0, y: map.top - 50.0 };
+            limit_to_map(&mut node, &arena);
+            assert_eq!((node.x, node.y), (map.right - half, map.top + half));
+            assert!(touches_border(&snake_of(&[(node.x, node.y)]), &arena));
+
+            let mut inside = Node { x: map.left + 100.0, y: map.bottom - 100.0 };
+            limit_to_map(&mut inside, &arena);
+            assert_eq!((inside.x, inside.y), (map.left + 100.0, map.bottom - 100.0));
+            assert!(!touches_border(&snake_of(&[(inside.x, inside.y)]), &arena));
+        }
+    }
+
+    #[test]
+    fn wrap_borders_bring_snakes_back_on_the_other_side() {
+        let arena = test_arena(Shape::Rectangle, Border::Wrap);
+        let map = &arena.map;
+        let (width, height) = (map.right - map.left, map.bottom - map.top);
+        let mut node = Node { x: map.right + 30.0, y: map.top - 20.0 };
+        limit_to_map(&mut node, &arena);
+        assert!((node.x - (map.left + 30.0)).abs() < 1e-9);
+        assert!((node.y - (map.bottom - 20.0)).abs() < 1e-9);
+
+        // Several times around comes back at the same place
+        let mut node = Node { x: map.left + 10.0 - 3.0 * width, y: map.top + 10.0 + 2.0 * height };
+        limit_to_map(&mut node, &arena);
+        assert!((node.x - (map.left + 10.0)).abs() < 1e-6 && (node.y - (map.top + 10.0)).abs() < 1e-6);
+
+        // The body follows the head across the edge rather than across the map
+        let y = (map.top + map.bottom) / 2.0;
+        let mut snake = snake_of(&[(map.right - 1.0, y), (map.right - 10.0, y), (map.right - 20.0, y)]);
+        for _ in 0..20 {
+            move_snake(&mut snake, 1000.0, 500.0, 1000.0, 1000.0, &arena);
+        }
+        assert!(snake.nodes[0].x < map.left + 100.0);
+        for pair in snake.nodes.windows(2) {
+            let (x, y) = collision::nearest_image(pair[1].x, pair[1].y, pair[0].x, pair[0].y, map);
+            assert!((x - pair[0].x).powi(2) + (y - pair[0].y).powi(2) < 30.0 * 30.0);
+        }
+    }
+
+    #[test]
+    fn the_neck_is_measured_along_a_folded_body() {
+        let arena = test_arena(Shape::Rectangle, Border::Clamp);
+        let step = CONST::SNAKE_SELF_COLLISION_NECK / 4.5;
+        let (x, y) = (1600.0, 1600.0);
+
+        // Two steps back, one aside and back again: the fifth node lies right next
+        // to the head, but four and a half steps from it along the body
+        let folded = snake_of(&[
+            (x, y),
+            (x - step, y),
+            (x - 2.0 * step, y),
+            (x - 2.0 * step, y + step),
+            (x - step, y + step),
+            (x, y + step),
+            (x + step, y + step),
+        ]);
+        assert_eq!(neck_end(&folded, &arena), 5);
+
+        // All of a fresh snake is neck, stacked on its spawn point
+        let stacked = snake_of(&[(x, y); 6]);
+        assert_eq!(neck_end(&stacked, &arena), 6);
+
+        // Across a wrapping edge the body is as short as it looks
+        let wrap = test_arena(Shape::Rectangle, Border::Wrap);
+        let (left, right) = (wrap.map.left, wrap.map.right);
+        let across = snake_of(&[(left + 1.0, y), (right - step + 1.0, y), (right - 2.0 * step + 1.0, y), (right - 5.0 * step, y)]);
+        assert_eq!(neck_end(&across, &wrap), 3);
+        assert_eq!(neck_end(&across, &arena), 1);
+    }
+}
@@ -0,0 +1,139 @@
+// Optional compression of outgoing messages, requested by a client when it connects
+// with "0,compress=lz4".
+//
+// A compressed message starts with "~" followed by the LZ4 block of the original
+// message, with its size prepended (4 bytes, little endian) and DICTIONARY as the
+// shared dictionary. Messages that would not get smaller are sent as they are, so
+// clients tell them apart by their first byte.
+//
+// Compression comes before the framing of the transport, so over UDP the reliable
+// header stays readable: a compressed reliable packet is "$30,<seq>~" followed by
+// the LZ4 block, and a large one is then split into "$31" fragments like any other.
+// Clients put the fragments back together and strip the reliable header first, then
+// decompress what follows it if it starts with "~".
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use once_cell::sync::Lazy;
+use crate::network::transport::Peer;
+
+pub const MARKER: u8 = b'~';
+
+// Pieces of typical snapshots ($20 header, own snake, enemies) and events, so even
+// the first occurrence of a command or a coordinate prefix compresses well. It is
+// written by hand from the message formats, not trained on recorded traffic.
+// Clients must use exactly the same bytes.
+pub const DICTIONARY: &[u8] = b"\
+$3,1000.$3,1500.$3,2000.$3,2500.$3,3000.$4,1000.$4,1500.$4,2000.$4,2500.$4,3000.\
+$5,Unnamed,$7,$8$9,$22$25,$26,$30,$31,$62,$61,\
+$6,1,1000.0000,1000.0000,1500.0000,1500.0000,2000.0000,2000.0000,2500.0000,2500.0000,\
+$63,1,0,0,1100.0000,1200.0000$63,2,0,1,1300.0000,1400.0000\
+$2,1600.0000,1700.0000,1800.0000,1900.0000,2100.0000,2200.0000,2300.0000,2400.0000,\
+$23,0,0,2600.0000,2700.0000$20,1,0,1000,0,10000$20,2,1,2000,1,20000";
+
+// Clients that asked for compressed messages
+static PEERS: Lazy<Mutex<HashSet<Peer>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Bytes of the messages before and after compression, since the last stats were taken
+static RAW_BYTES: AtomicU64 = AtomicU64::new(0);
+static SENT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// Whether a codec requested by a client is supported
+pub fn supports(codec: &str) -> bool {
+    codec == "lz4"
+}
+
+pub fn enable(peer: Peer) {
+    PEERS.lock().unwrap().insert(peer);
+}
+
+pub fn disable(peer: &Peer) {
+    PEERS.lock().unwrap().remove(peer);
+}
+
+// Compress a message for a client, if it asked for it and the message gets smaller
+pub fn compress(peer: &Peer, data: Vec<u8>) -> Vec<u8> {
+    if !PEERS.lock().unwrap().contains(peer) {
+        return data;
+    }
+
+    let mut compressed = vec![MARKER];
+    compressed.extend(lz4_flex::block::compress_prepend_size_with_dict(&data, DICTIONARY));
+
+    RAW_BYTES.fetch_add(data.len() as u64, Ordering::Relaxed);
+    if compressed.len() < data.len() {
+        SENT_BYTES.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        compressed
+    } else {
+        SENT_BYTES.fetch_add(data.len() as u64, Ordering::Relaxed);
+        data
+    }
+}
+
+// Bytes before and after compression of the messages sent since the previous call
+pub fn take_stats() -> (u64, u64) {
+    (RAW_BYTES.swap(0, Ordering::Relaxed), SENT_BYTES.swap(0, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use crate::network::reliable;
+
+    fn peer(port: u16) -> Peer {
+        Peer::Udp(SocketAddr::from(([10, 3, 0, 1], port)))
+    }
+
+    fn decompress(data: &[u8]) -> Vec<u8> {
+        assert_eq!(data[0], MARKER);
+        lz4_flex::block::decompress_size_prepended_with_dict(&data[1..], DICTIONARY).unwrap()
+    }
+
+    fn snapshot() -> Vec<u8> {
+        let mut message = b"$20,41,40,1000,12,50000$2,".to_vec();
+        for i in 0..40 {
+            message.extend(format!("{}.2500,{}.7500,", 1500 + i, 2000 + i).bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn messages_round_trip_through_the_dictionary() {
+        let peer = peer(1);
+        enable(peer);
+        let message = snapshot();
+        let compressed = compress(&peer, message.clone());
+        assert!(compressed.len() < message.len());
+        assert_eq!(decompress(&compressed), message);
+        disable(&peer);
+    }
+
+    #[test]
+    fn messages_that_do_not_shrink_are_sent_raw() {
+        let peer = peer(2);
+        enable(peer);
+        assert_eq!(compress(&peer, b"$7,1".to_vec()), b"$7,1");
+        disable(&peer);
+
+        // Nor are messages to clients that did not ask for compression
+        assert_eq!(compress(&peer, snapshot()), snapshot());
+    }
+
+    #[test]
+    fn reliable_packets_keep_their_header_readable() {
+        let peer = peer(3);
+        let Peer::Udp(addr) = peer else { unreachable!() };
+        enable(peer);
+        let message = snapshot();
+        let wire = reliable::wrap(addr, compress(&peer, message.clone()));
+
+        // "$30,<seq>" then the compressed message
+        let header = b"$30,1";
+        assert_eq!(&wire[..header.len()], header);
+        assert_eq!(decompress(&wire[header.len()..]), message);
+        reliable::destroy(&addr);
+        disable(&peer);
+    }
+}
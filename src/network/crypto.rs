@@ -0,0 +1,321 @@
+// Optional encrypted UDP sessions.
+//
+// A client asks for one by connecting with "0,key=<its X25519 public key in hex>".
+// The server answers "$27,<its own public key in hex>" in clear, and from then on
+// every datagram both ways is sealed with ChaCha20-Poly1305:
+//
+//     "!" <counter: 8 bytes, big endian> <ciphertext and tag>
+//
+// The key of each direction is SHA-256(shared secret, client key, server key, "c2s"
+// or "s2c"), the nonce is the counter on 12 bytes and the first 9 bytes are the
+// associated data. Counters start at 0 and grow by one per datagram; a counter seen
+// before, or too old to tell, is a replay and the datagram is dropped. Once a client
+// has a session, its address only accepts sealed datagrams.
+//
+// Sessions are capped like players, overall and per IP, and a session that still has
+// no player after SESSION_SETUP_TIMEOUT is dropped.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::game::constants as CONST;
+
+pub const MARKER: u8 = b'!';
+const HEADER_SIZE: usize = 9;
+const TAG_SIZE: usize = 16;
+// Bytes a sealed datagram has on top of its content
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+// Counters older than this many behind the latest one are treated as replays
+const REPLAY_WINDOW: u64 = 64;
+
+pub struct Session {
+    pub send_cipher: ChaCha20Poly1305,
+    pub recv_cipher: ChaCha20Poly1305,
+    pub send_counter: u64,
+    // Latest counter received, and which of the REPLAY_WINDOW counters before it were seen
+    pub recv_highest: Option<u64>,
+    pub recv_seen: u64,
+    pub started: Instant,
+}
+
+static SESSIONS: Lazy<Mutex<HashMap<SocketAddr, Session>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Datagrams dropped because they failed authentication, were replayed or were not sealed
+pub static REJECTED_DATAGRAMS: AtomicU64 = AtomicU64::new(0);
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<[u8; 32]> {
+    let text = text.trim();
+    if text.len() != 64 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn derive_cipher(shared: &[u8], client_key: &[u8], server_key: &[u8], direction: &[u8]) -> ChaCha20Poly1305 {
+    let mut hasher = Sha256::new();
+    hasher.update(shared);
+    hasher.update(client_key);
+    hasher.update(server_key);
+    hasher.update(direction);
+    let key: [u8; 32] = hasher.finalize().into();
+    ChaCha20Poly1305::new(&Key::from(key))
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+// Start a session with the client's public key, given in hex, and return the
+// server's public key in hex, or why there is no session
+pub fn handshake(addr: SocketAddr, client_key_hex: &str) -> Result<String, &'static str> {
+    let client_key = PublicKey::from(from_hex(client_key_hex).ok_or("invalid key")?);
+
+    // Checked before the key exchange, which is the expensive part
+    {
+        let sessions = SESSIONS.lock().unwrap();
+        if !sessions.contains_key(&addr) {
+            if sessions.len() >= CONST::MAX_PLAYERS {
+                return Err("too many sessions");
+            }
+            if sessions.keys().filter(|other| other.ip() == addr.ip()).count() >= CONST::MAX_PLAYERS_PER_IP {
+                return Err("too many sessions from this address");
+            }
+        }
+    }
+
+    let mut secret = [0u8; 32];
+    rand::rng().fill(&mut secret);
+    let secret = StaticSecret::from(secret);
+    let server_key = PublicKey::from(&secret);
+
+    let shared = secret.diffie_hellman(&client_key);
+    if !shared.was_contributory() {
+        return Err("invalid key");
+    }
+
+    let session = Session {
+        send_cipher: derive_cipher(shared.as_bytes(), client_key.as_bytes(), server_key.as_bytes(), b"s2c"),
+        recv_cipher: derive_cipher(shared.as_bytes(), client_key.as_bytes(), server_key.as_bytes(), b"c2s"),
+        send_counter: 0,
+        recv_highest: None,
+        recv_seen: 0,
+        started: Instant::now(),
+    };
+    SESSIONS.lock().unwrap().insert(addr, session);
+    Ok(to_hex(server_key.as_bytes()))
+}
+
+pub fn has_session(addr: &SocketAddr) -> bool {
+    SESSIONS.lock().unwrap().contains_key(addr)
+}
+
+// Seal a datagram for a client with a session, others get it as it is
+pub fn seal(addr: &SocketAddr, data: Vec<u8>) -> Vec<u8> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let Some(session) = sessions.get_mut(addr) else {
+        return data;
+    };
+
+    let counter = session.send_counter;
+    session.send_counter += 1;
+
+    let mut datagram = vec![MARKER];
+    datagram.extend_from_slice(&counter.to_be_bytes());
+    let payload = Payload { msg: &data, aad: &datagram };
+    match session.send_cipher.encrypt(&nonce(counter), payload) {
+        Ok(ciphertext) => {
+            datagram.extend(ciphertext);
+            datagram
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+fn reject(addr: &SocketAddr, reason: &str) -> Option<Vec<u8>> {
+    REJECTED_DATAGRAMS.fetch_add(1, Ordering::Relaxed);
+    println!("Rejected datagram from {}: {}", addr, reason);
+    None
+}
+
+// Open a datagram received from a client. Returns its content, or None if it must
+// be dropped: unsealed while the client has a session, forged or replayed.
+pub fn open(addr: &SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let Some(session) = sessions.get_mut(addr) else {
+        if data.first() == Some(&MARKER) {
+            return reject(addr, "sealed datagram without a session");
+        }
+        return Some(data.to_vec());
+    };
+
+    if data.len() < OVERHEAD || data[0] != MARKER {
+        return reject(addr, "unsealed datagram in an encrypted session");
+    }
+
+    let counter = u64::from_be_bytes(data[1..HEADER_SIZE].try_into().ok()?);
+    let age = session.recv_highest.map(|highest| highest as i128 - counter as i128);
+    let replayed = match age {
+        Some(age) if age >= REPLAY_WINDOW as i128 => true,
+        Some(age) if age >= 0 => session.recv_seen & (1 << age) != 0,
+        _ => false,
+    };
+    if replayed {
+        return reject(addr, "replayed datagram");
+    }
+
+    let payload = Payload { msg: &data[HEADER_SIZE..], aad: &data[..HEADER_SIZE] };
+    let Ok(message) = session.recv_cipher.decrypt(&nonce(counter), payload) else {
+        return reject(addr, "datagram failed authentication");
+    };
+
+    // Only authentic datagrams move the replay window
+    match age {
+        Some(age) if age >= 0 => session.recv_seen |= 1 << age,
+        Some(age) => {
+            let shift = (-age) as u64;
+            session.recv_seen = if shift >= REPLAY_WINDOW { 0 } else { session.recv_seen << shift };
+            session.recv_seen |= 1;
+            session.recv_highest = Some(counter);
+        }
+        None => {
+            session.recv_seen = 1;
+            session.recv_highest = Some(counter);
+        }
+    }
+    Some(message)
+}
+
+// Sessions started longer ago than max_age
+pub fn started_before(max_age: Duration) -> Vec<SocketAddr> {
+    let sessions = SESSIONS.lock().unwrap();
+    sessions.iter()
+        .filter(|(_, session)| session.started.elapsed() > max_age)
+        .map(|(addr, _)| *addr)
+        .collect()
+}
+
+pub fn destroy(addr: &SocketAddr) {
+    SESSIONS.lock().unwrap().remove(addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The client side of a session: its key and the ciphers of both directions
+    fn client_session(addr: SocketAddr) -> (ChaCha20Poly1305, ChaCha20Poly1305) {
+        let secret = StaticSecret::from([7u8; 32]);
+        let client_key = PublicKey::from(&secret);
+        let server_key = PublicKey::from(from_hex(&handshake(addr, &to_hex(client_key.as_bytes())).unwrap()).unwrap());
+        let shared = secret.diffie_hellman(&server_key);
+        (
+            derive_cipher(shared.as_bytes(), client_key.as_bytes(), server_key.as_bytes(), b"c2s"),
+            derive_cipher(shared.as_bytes(), client_key.as_bytes(), server_key.as_bytes(), b"s2c"),
+        )
+    }
+
+    fn client_seal(cipher: &ChaCha20Poly1305, counter: u64, message: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![MARKER];
+        datagram.extend_from_slice(&counter.to_be_bytes());
+        let sealed = cipher.encrypt(&nonce(counter), Payload { msg: message, aad: &datagram }).unwrap();
+        datagram.extend(sealed);
+        datagram
+    }
+
+    #[test]
+    fn datagrams_round_trip_both_ways() {
+        let addr: SocketAddr = "10.0.1.1:1000".parse().unwrap();
+        let (send, recv) = client_session(addr);
+
+        assert_eq!(open(&addr, &client_seal(&send, 0, b"2,1,2,3,4")), Some(b"2,1,2,3,4".to_vec()));
+
+        let sealed = seal(&addr, b"$1,2".to_vec());
+        assert_eq!(sealed.len(), 4 + OVERHEAD);
+        let message = recv.decrypt(&nonce(0), Payload { msg: &sealed[HEADER_SIZE..], aad: &sealed[..HEADER_SIZE] });
+        assert_eq!(message.unwrap(), b"$1,2");
+        destroy(&addr);
+    }
+
+    #[test]
+    fn replayed_forged_and_clear_datagrams_are_dropped() {
+        let addr: SocketAddr = "10.0.1.2:1000".parse().unwrap();
+        let (send, _) = client_session(addr);
+
+        let first = client_seal(&send, 5, b"16");
+        assert!(open(&addr, &first).is_some());
+        assert!(open(&addr, &first).is_none());
+
+        // Older but within the window, once only
+        let older = client_seal(&send, 3, b"16");
+        assert!(open(&addr, &older).is_some());
+        assert!(open(&addr, &older).is_none());
+        assert!(open(&addr, &client_seal(&send, 5 + REPLAY_WINDOW, b"16")).is_some());
+        assert!(open(&addr, &client_seal(&send, 4, b"16")).is_none());
+
+        let mut forged = client_seal(&send, 100, b"16");
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(open(&addr, &forged).is_none());
+        assert!(open(&addr, b"16").is_none());
+        destroy(&addr);
+    }
+
+    #[test]
+    fn invalid_keys_are_refused() {
+        let addr: SocketAddr = "10.0.1.3:1000".parse().unwrap();
+        assert_eq!(handshake(addr, "not a key"), Err("invalid key"));
+        assert_eq!(handshake(addr, &"zz".repeat(32)), Err("invalid key"));
+        // A low order point gives an all-zero shared secret
+        assert_eq!(handshake(addr, &"00".repeat(32)), Err("invalid key"));
+        assert!(!has_session(&addr));
+    }
+
+    #[test]
+    fn sessions_are_capped_per_ip() {
+        let key = to_hex(PublicKey::from(&StaticSecret::from([9u8; 32])).as_bytes());
+        let addrs: Vec<SocketAddr> = (0..=CONST::MAX_PLAYERS_PER_IP)
+            .map(|port| format!("10.0.1.4:{}", 2000 + port).parse().unwrap())
+            .collect();
+
+        for addr in &addrs[..CONST::MAX_PLAYERS_PER_IP] {
+            assert!(handshake(*addr, &key).is_ok());
+        }
+        let last = addrs[CONST::MAX_PLAYERS_PER_IP];
+        assert_eq!(handshake(last, &key), Err("too many sessions from this address"));
+
+        // Starting over on an address that has a session takes no new place
+        assert!(handshake(addrs[0], &key).is_ok());
+        for addr in &addrs {
+            destroy(addr);
+        }
+    }
+
+    #[test]
+    fn sessions_without_a_player_can_be_found_by_age() {
+        let addr: SocketAddr = "10.0.1.5:1000".parse().unwrap();
+        client_session(addr);
+
+        assert!(!started_before(Duration::from_secs(60)).contains(&addr));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(started_before(Duration::from_millis(1)).contains(&addr));
+        destroy(&addr);
+    }
+}
@@ -0,0 +1,247 @@
+// Framing of messages too large for one datagram.
+//
+// A message larger than FRAGMENT_MTU is split into numbered fragments, each sent as
+// "$31,<message id>,<index>,<count>," followed by its part of the message. Clients
+// fragment their own large messages the same way, without the leading "$", and the
+// server puts them back together before processing them.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use crate::game::constants as CONST;
+
+// Fragments received so far for one message of a client
+pub struct PartialMessage {
+    pub fragments: Vec<Option<Vec<u8>>>,
+    pub received: usize,
+    pub size: usize,
+    pub started_at: Instant,
+}
+
+static NEXT_MESSAGE_ID: AtomicU32 = AtomicU32::new(1);
+static PARTIAL_MESSAGES: Lazy<Mutex<HashMap<(SocketAddr, u32), PartialMessage>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Client datagrams dropped because they were too large, malformed or over the memory limits
+pub static REJECTED_DATAGRAMS: AtomicU64 = AtomicU64::new(0);
+
+// Split a message into datagrams no larger than FRAGMENT_MTU
+pub fn split(data: Vec<u8>) -> Vec<Vec<u8>> {
+    if data.len() <= CONST::FRAGMENT_MTU {
+        return vec![data];
+    }
+
+    let message_id = NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed);
+
+    // The header grows with the number of fragments, so size the chunks for the largest one
+    let max_header = format!(
+        "{}{}{},{},{},",
+        CONST::COMM_START_NEW_MESS,
+        CONST::COMM_FRAGMENT,
+        message_id,
+        data.len(),
+        data.len()
+    ).len();
+    let chunk_size = CONST::FRAGMENT_MTU - max_header;
+    let count = data.len().div_ceil(chunk_size);
+
+    data.chunks(chunk_size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = format!(
+                "{}{}{},{},{},",
+                CONST::COMM_START_NEW_MESS,
+                CONST::COMM_FRAGMENT,
+                message_id,
+                index,
+                count
+            ).into_bytes();
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect()
+}
+
+fn reject(addr: &SocketAddr, reason: &str) -> Option<Vec<u8>> {
+    REJECTED_DATAGRAMS.fetch_add(1, Ordering::Relaxed);
+    println!("Rejected datagram from {}: {}", addr, reason);
+    None
+}
+
+// Parse "31,<message id>,<index>,<count>," and return the header values and the payload
+fn parse_header(data: &[u8]) -> Option<(u32, usize, usize, &[u8])> {
+    let fields: Vec<&[u8]> = data.splitn(5, |&b| b == b',').collect();
+    if fields.len() < 5 {
+        return None;
+    }
+
+    let message_id = std::str::from_utf8(fields[1]).ok()?.parse().ok()?;
+    let index = std::str::from_utf8(fields[2]).ok()?.parse().ok()?;
+    let count = std::str::from_utf8(fields[3]).ok()?.parse().ok()?;
+    Some((message_id, index, count, fields[4]))
+}
+
+// Handle a datagram received from a client. Returns the message to process: the
+// datagram itself, or the whole message once its last fragment has arrived.
+pub fn receive(addr: SocketAddr, data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() > CONST::MAX_CLIENT_DATAGRAM {
+        return reject(&addr, "datagram too large");
+    }
+
+    if !data.starts_with(CONST::COMM_FRAGMENT.as_bytes()) {
+        return Some(data.to_vec());
+    }
+
+    let Some((message_id, index, count, payload)) = parse_header(data) else {
+        return reject(&addr, "malformed fragment");
+    };
+
+    if count == 0 || index >= count || count > CONST::FRAGMENT_MAX_COUNT {
+        return reject(&addr, "bad fragment numbering");
+    }
+
+    let mut partial_messages = PARTIAL_MESSAGES.lock().unwrap();
+
+    // Forget messages that did not complete in time
+    partial_messages.retain(|(from, id), partial| {
+        let expired = partial.started_at.elapsed() > Duration::from_millis(CONST::FRAGMENT_TIMEOUT);
+        if expired {
+            println!("Dropped incomplete message {} from {}", id, from);
+        }
+        !expired
+    });
+
+    let pending_total: usize = partial_messages.values().map(|partial| partial.size).sum();
+    let pending_peer: usize = partial_messages.iter()
+        .filter(|((from, _), _)| *from == addr)
+        .map(|(_, partial)| partial.size)
+        .sum();
+    if pending_total + payload.len() > CONST::FRAGMENT_MAX_PENDING
+        || pending_peer + payload.len() > CONST::FRAGMENT_MAX_PENDING_PER_PEER {
+        return reject(&addr, "too many pending fragments");
+    }
+
+    let partial = partial_messages.entry((addr, message_id)).or_insert_with(|| PartialMessage {
+        fragments: vec![None; count],
+        received: 0,
+        size: 0,
+        started_at: Instant::now(),
+    });
+
+    if partial.fragments.len() != count {
+        partial_messages.remove(&(addr, message_id));
+        return reject(&addr, "fragment count changed");
+    }
+
+    if partial.fragments[index].is_some() {
+        // Duplicate fragment
+        return None;
+    }
+
+    if partial.size + payload.len() > CONST::FRAGMENT_MAX_MESSAGE {
+        partial_messages.remove(&(addr, message_id));
+        return reject(&addr, "message too large");
+    }
+
+    partial.fragments[index] = Some(payload.to_vec());
+    partial.received += 1;
+    partial.size += payload.len();
+
+    if partial.received < count {
+        return None;
+    }
+
+    let partial = partial_messages.remove(&(addr, message_id))?;
+    Some(partial.fragments.into_iter().flatten().flatten().collect())
+}
+
+pub fn destroy(addr: &SocketAddr) {
+    PARTIAL_MESSAGES.lock().unwrap().retain(|(from, _), _| from != addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(size: usize) -> Vec<u8> {
+        (0..size).map(|i| b'a' + (i % 26) as u8).collect()
+    }
+
+    // Clients send their fragments without the leading "$"
+    fn client_fragments(data: Vec<u8>) -> Vec<Vec<u8>> {
+        split(data).into_iter().map(|fragment| fragment[1..].to_vec()).collect()
+    }
+
+    #[test]
+    fn small_messages_are_left_alone() {
+        let addr: SocketAddr = "10.0.3.1:1000".parse().unwrap();
+        assert_eq!(split(Vec::from("2,1,2")), vec![b"2,1,2".to_vec()]);
+        assert_eq!(receive(addr, b"2,1,2"), Some(b"2,1,2".to_vec()));
+        assert_eq!(receive(addr, &message(CONST::MAX_CLIENT_DATAGRAM + 1)), None);
+    }
+
+    #[test]
+    fn fragments_arriving_out_of_order_are_put_back_together() {
+        let addr: SocketAddr = "10.0.3.2:1000".parse().unwrap();
+        let data = message(3000);
+        let mut fragments = client_fragments(data.clone());
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.len() < CONST::FRAGMENT_MTU));
+
+        fragments.reverse();
+        assert_eq!(receive(addr, &fragments[0]), None);
+        assert_eq!(receive(addr, &fragments[1]), None);
+        assert_eq!(receive(addr, &fragments[2]), Some(data));
+    }
+
+    #[test]
+    fn duplicate_fragments_are_ignored() {
+        let addr: SocketAddr = "10.0.3.3:1000".parse().unwrap();
+        let data = message(2000);
+        let fragments = client_fragments(data.clone());
+
+        assert_eq!(receive(addr, &fragments[0]), None);
+        assert_eq!(receive(addr, &fragments[0]), None);
+        assert_eq!(receive(addr, &fragments[1]), Some(data));
+
+        // A copy arriving after the message completed starts a message that never will
+        assert_eq!(receive(addr, &fragments[1]), None);
+        destroy(&addr);
+    }
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let addr: SocketAddr = "10.0.3.4:1000".parse().unwrap();
+        let fragments = client_fragments(message(2000));
+        assert_eq!(receive(addr, &fragments[0]), None);
+
+        let key = *PARTIAL_MESSAGES.lock().unwrap().keys().find(|(from, _)| *from == addr).unwrap();
+        PARTIAL_MESSAGES.lock().unwrap().get_mut(&key).unwrap().started_at =
+            Instant::now() - Duration::from_millis(CONST::FRAGMENT_TIMEOUT + 1);
+
+        // The last fragment comes too late and starts over
+        assert_eq!(receive(addr, &fragments[1]), None);
+        assert_eq!(PARTIAL_MESSAGES.lock().unwrap()[&key].received, 1);
+        destroy(&addr);
+        assert!(!PARTIAL_MESSAGES.lock().unwrap().contains_key(&key));
+    }
+
+    #[test]
+    fn badly_numbered_fragments_are_rejected() {
+        let addr: SocketAddr = "10.0.3.5:1000".parse().unwrap();
+        assert_eq!(receive(addr, b"31,1,2,2,abc"), None);
+        assert_eq!(receive(addr, b"31,1,0,0,abc"), None);
+        assert_eq!(receive(addr, format!("31,1,0,{},abc", CONST::FRAGMENT_MAX_COUNT + 1).as_bytes()), None);
+        assert_eq!(receive(addr, b"31,1,0"), None);
+
+        // The number of fragments of a message cannot change on the way, the
+        // fragments received before are dropped with it
+        assert_eq!(receive(addr, b"31,2,0,3,abc"), None);
+        assert_eq!(receive(addr, b"31,2,1,2,def"), None);
+        assert_eq!(receive(addr, b"31,2,1,3,def"), None);
+        assert_eq!(receive(addr, b"31,2,2,3,ghi"), None);
+        destroy(&addr);
+    }
+}
@@ -0,0 +1,88 @@
+// In-memory transport: clients are channels in the same process, so a whole
+// server can run in tests without binding any port
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::sync::mpsc;
+use tokio::sync::Mutex as TokioMutex;
+use crate::network::transport::{Peer, Transport};
+
+pub struct MemoryTransport {
+    next_client_id: AtomicU64,
+    inbox_tx: mpsc::UnboundedSender<(Peer, Vec<u8>)>,
+    inbox_rx: TokioMutex<mpsc::UnboundedReceiver<(Peer, Vec<u8>)>>,
+    clients: Mutex<HashMap<u64, mpsc::UnboundedSender<Vec<u8>>>>,
+}
+
+// The client side of an in-memory connection
+pub struct MemoryClient {
+    pub peer: Peer,
+    to_server: mpsc::UnboundedSender<(Peer, Vec<u8>)>,
+    from_server: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+        MemoryTransport {
+            next_client_id: AtomicU64::new(1),
+            inbox_tx,
+            inbox_rx: TokioMutex::new(inbox_rx),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn connect(&self) -> MemoryClient {
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        self.clients.lock().unwrap().insert(id, client_tx);
+
+        MemoryClient {
+            peer: Peer::Memory(id),
+            to_server: self.inbox_tx.clone(),
+            from_server: client_rx,
+        }
+    }
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        MemoryTransport::new()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn owns(&self, peer: &Peer) -> bool {
+        matches!(peer, Peer::Memory(_))
+    }
+
+    fn send(&self, peer: Peer, data: Vec<u8>, _reliable: bool) -> BoxFuture<'_, ()> {
+        async move {
+            if let Peer::Memory(id) = peer {
+                if let Some(client) = self.clients.lock().unwrap().get(&id) {
+                    let _ = client.send(data);
+                }
+            }
+        }.boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<(Peer, Vec<u8>)>> {
+        async move {
+            self.inbox_rx.lock().await.recv().await
+        }.boxed()
+    }
+}
+
+impl MemoryClient {
+    pub fn send(&self, data: &[u8]) {
+        let _ = self.to_server.send((self.peer, data.to_vec()));
+    }
+
+    // Wait for the next message from the server, None once the server is gone
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.from_server.recv().await
+    }
+}
@@ -0,0 +1,248 @@
+// Per-client outbound queues. The game queues packets without waiting, and the
+// sender task takes them one client at a time, events before snapshots, so one
+// client's burst cannot delay everyone else or stall the game loop.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::Notify;
+use crate::game::constants as CONST;
+use crate::game::game_server::{PacketKind, UdpPacket};
+use crate::network::transport::Peer;
+
+pub struct PeerQueue {
+    pub events: VecDeque<UdpPacket>,
+    // Only the latest snapshot is worth sending, a newer one replaces it
+    pub snapshot: Option<UdpPacket>,
+    pub dropped_events: u64,
+    pub superseded_snapshots: u64,
+}
+
+pub struct Outbound {
+    queues: Mutex<HashMap<Peer, PeerQueue>>,
+    // Clients with packets waiting, in the order they are served
+    ready: Mutex<VecDeque<Peer>>,
+    // Clients whose queue filled up with reliable events, until they are disconnected
+    failed: Mutex<Vec<Peer>>,
+    notify: Notify,
+}
+
+// Events dropped because a client's queue was full, over all clients
+pub static DROPPED_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+impl Outbound {
+    pub fn new() -> Outbound {
+        Outbound {
+            queues: Mutex::new(HashMap::new()),
+            ready: Mutex::new(VecDeque::new()),
+            failed: Mutex::new(Vec::new()),
+            notify: Notify::new(),
+        }
+    }
+
+    // Queue a packet for its client without waiting
+    pub fn send(&self, packet: UdpPacket) {
+        let peer = packet.addr;
+        // Bots have nobody to send to
+        if peer.is_bot() || self.failed.lock().unwrap().contains(&peer) {
+            return;
+        }
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues.entry(peer).or_insert_with(|| PeerQueue {
+            events: VecDeque::new(),
+            snapshot: None,
+            dropped_events: 0,
+            superseded_snapshots: 0,
+        });
+        let was_empty = queue.events.is_empty() && queue.snapshot.is_none();
+
+        match packet.kind {
+            PacketKind::Snapshot => {
+                if queue.snapshot.replace(packet).is_some() {
+                    queue.superseded_snapshots += 1;
+                }
+            }
+            PacketKind::Event => {
+                if queue.events.len() >= CONST::OUTBOUND_QUEUE_SIZE {
+                    // Make room by dropping the oldest event that is not reliable, or
+                    // this one if it is not. A queue full of reliable events cannot
+                    // lose any of them: the client is given up.
+                    queue.dropped_events += 1;
+                    DROPPED_EVENTS.fetch_add(1, Ordering::Relaxed);
+                    if queue.dropped_events.is_power_of_two() {
+                        println!(
+                            "Outbound queue of {} overflowed: {} event(s) dropped, {} snapshot(s) superseded",
+                            peer,
+                            queue.dropped_events,
+                            queue.superseded_snapshots
+                        );
+                    }
+
+                    match queue.events.iter().position(|queued| !queued.reliable) {
+                        Some(oldest) => {
+                            queue.events.remove(oldest);
+                        }
+                        None if !packet.reliable => return,
+                        None => {
+                            println!("Outbound queue of {} is full of reliable events, disconnecting", peer);
+                            queues.remove(&peer);
+                            self.failed.lock().unwrap().push(peer);
+                            return;
+                        }
+                    }
+                }
+                queue.events.push_back(packet);
+            }
+        }
+
+        if was_empty {
+            self.ready.lock().unwrap().push_back(peer);
+            self.notify.notify_one();
+        }
+    }
+
+    // Wait for the next packet to send
+    pub async fn next(&self) -> UdpPacket {
+        loop {
+            if let Some(packet) = self.pop() {
+                return packet;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn pop(&self) -> Option<UdpPacket> {
+        let mut queues = self.queues.lock().unwrap();
+        let mut ready = self.ready.lock().unwrap();
+
+        while let Some(peer) = ready.pop_front() {
+            let Some(queue) = queues.get_mut(&peer) else { continue };
+            let packet = queue.events.pop_front().or_else(|| queue.snapshot.take());
+
+            if !queue.events.is_empty() || queue.snapshot.is_some() {
+                ready.push_back(peer);
+            }
+            if packet.is_some() {
+                return packet;
+            }
+        }
+        None
+    }
+
+    // Clients given up since the previous call, to be disconnected
+    pub fn take_failed(&self) -> Vec<Peer> {
+        std::mem::take(&mut *self.failed.lock().unwrap())
+    }
+
+    // Forget a client's queue, along with everything still waiting in it
+    pub fn remove(&self, peer: &Peer) {
+        self.queues.lock().unwrap().remove(peer);
+    }
+}
+
+impl Default for Outbound {
+    fn default() -> Self {
+        Outbound::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(peer: Peer, data: &str, reliable: bool, kind: PacketKind) -> UdpPacket {
+        UdpPacket {
+            addr: peer,
+            data: data.as_bytes().to_vec(),
+            reliable,
+            kind,
+        }
+    }
+
+    fn event(peer: Peer, data: &str) -> UdpPacket {
+        packet(peer, data, false, PacketKind::Event)
+    }
+
+    fn snapshot(peer: Peer, data: &str) -> UdpPacket {
+        packet(peer, data, false, PacketKind::Snapshot)
+    }
+
+    fn drain(outbound: &Outbound) -> Vec<(Peer, String)> {
+        std::iter::from_fn(|| outbound.pop())
+            .map(|packet| (packet.addr, String::from_utf8(packet.data).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn events_go_before_snapshots() {
+        let outbound = Outbound::new();
+        let peer = Peer::Memory(1);
+        outbound.send(snapshot(peer, "$20,1"));
+        outbound.send(event(peer, "$5,a"));
+        outbound.send(event(peer, "$7,b"));
+
+        let sent: Vec<String> = drain(&outbound).into_iter().map(|(_, data)| data).collect();
+        assert_eq!(sent, ["$5,a", "$7,b", "$20,1"]);
+    }
+
+    #[test]
+    fn newer_snapshots_replace_waiting_ones() {
+        let outbound = Outbound::new();
+        let peer = Peer::Memory(1);
+        outbound.send(snapshot(peer, "$20,1"));
+        outbound.send(snapshot(peer, "$20,2"));
+
+        assert_eq!(drain(&outbound), [(peer, "$20,2".to_string())]);
+        assert_eq!(outbound.queues.lock().unwrap()[&peer].superseded_snapshots, 1);
+    }
+
+    #[test]
+    fn clients_take_turns() {
+        let outbound = Outbound::new();
+        let (first, second) = (Peer::Memory(1), Peer::Memory(2));
+        outbound.send(event(first, "1"));
+        outbound.send(event(first, "2"));
+        outbound.send(event(second, "3"));
+        outbound.send(event(Peer::Bot(1), "4"));
+
+        let sent: Vec<String> = drain(&outbound).into_iter().map(|(_, data)| data).collect();
+        assert_eq!(sent, ["1", "3", "2"]);
+
+        outbound.send(event(first, "5"));
+        outbound.remove(&first);
+        assert!(drain(&outbound).is_empty());
+    }
+
+    #[test]
+    fn full_queues_drop_unreliable_events_first() {
+        let outbound = Outbound::new();
+        let peer = Peer::Memory(1);
+        outbound.send(packet(peer, "reliable", true, PacketKind::Event));
+        for i in 1..CONST::OUTBOUND_QUEUE_SIZE {
+            outbound.send(event(peer, &i.to_string()));
+        }
+        outbound.send(event(peer, "last"));
+
+        let sent: Vec<String> = drain(&outbound).into_iter().map(|(_, data)| data).collect();
+        assert_eq!(sent.len(), CONST::OUTBOUND_QUEUE_SIZE);
+        assert_eq!(sent[0], "reliable");
+        assert_eq!(sent[1], "2");
+        assert_eq!(sent[sent.len() - 1], "last");
+    }
+
+    #[test]
+    fn clients_with_a_queue_full_of_reliable_events_are_given_up() {
+        let outbound = Outbound::new();
+        let peer = Peer::Memory(1);
+        for i in 0..CONST::OUTBOUND_QUEUE_SIZE {
+            outbound.send(packet(peer, &i.to_string(), true, PacketKind::Event));
+        }
+
+        // Unreliable events are dropped, a reliable one gives the client up
+        outbound.send(event(peer, "unreliable"));
+        assert!(outbound.take_failed().is_empty());
+        outbound.send(packet(peer, "reliable", true, PacketKind::Event));
+        assert_eq!(outbound.take_failed(), vec![peer]);
+        assert!(drain(&outbound).is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+// Token bucket limits of the messages clients send, for each message type, both per
+// session and per source IP so opening many sessions does not get around them.
+// Messages over a limit are dropped and counted.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use once_cell::sync::Lazy;
+use crate::game::constants as CONST;
+use crate::network::transport::Peer;
+
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated: Instant,
+    pub rate: f64,
+    pub burst: f64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Source {
+    Session(Peer),
+    Ip(IpAddr),
+}
+
+static BUCKETS: Lazy<Mutex<HashMap<(Source, &'static str), Bucket>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Messages dropped per message type since the last report
+static DROPPED: Lazy<Mutex<HashMap<&'static str, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Message type of a command, with its session and IP limits
+fn limits(command: &str) -> (&'static str, (f64, f64), (f64, f64)) {
+    match command {
+        "0" => ("connect", CONST::RATE_CONNECT, CONST::RATE_CONNECT_PER_IP),
+        "2" => ("move", CONST::RATE_MOVE, CONST::RATE_MOVE_PER_IP),
+        "9" => ("name", CONST::RATE_NAME, CONST::RATE_NAME_PER_IP),
+        "10" | "11" => ("boost", CONST::RATE_BOOST, CONST::RATE_BOOST_PER_IP),
+        "12" | "13" | "14" | "15" | "16" | "17" => ("control", CONST::RATE_CONTROL, CONST::RATE_CONTROL_PER_IP),
+        _ => ("other", CONST::RATE_OTHER, CONST::RATE_OTHER_PER_IP),
+    }
+}
+
+// Refill a bucket for the time elapsed and take a token from it if there is one
+fn take(buckets: &mut HashMap<(Source, &'static str), Bucket>, key: (Source, &'static str), (rate, burst): (f64, f64), now: Instant) -> bool {
+    let bucket = buckets.entry(key).or_insert(Bucket {
+        tokens: burst,
+        updated: now,
+        rate,
+        burst,
+    });
+    bucket.tokens = f64::min(burst, bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        true
+    } else {
+        false
+    }
+}
+
+// Whether a message from a peer is within the limits of its type. A message over
+// the limit of its session does not use up a token of its IP, so one spamming
+// session does not starve the others behind the same address.
+pub fn allow(peer: &Peer, command: &str) -> bool {
+    check(peer, limits(command))
+}
+
+// Whether a peer may start an encrypted session, which costs a key exchange
+pub fn allow_handshake(peer: &Peer) -> bool {
+    check(peer, ("handshake", CONST::RATE_HANDSHAKE, CONST::RATE_HANDSHAKE_PER_IP))
+}
+
+fn check(peer: &Peer, (kind, session_limit, ip_limit): (&'static str, (f64, f64), (f64, f64))) -> bool {
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+
+    let allowed = take(&mut buckets, (Source::Session(*peer), kind), session_limit, now)
+        && peer.ip().is_none_or(|ip| take(&mut buckets, (Source::Ip(ip), kind), ip_limit, now));
+
+    if !allowed {
+        drop_message(kind);
+    }
+    allowed
+}
+
+// Count a message dropped for going over a limit
+pub fn drop_message(kind: &'static str) {
+    *DROPPED.lock().unwrap().entry(kind).or_insert(0) += 1;
+}
+
+// Messages dropped per message type since the previous call
+pub fn take_dropped() -> Vec<(&'static str, u64)> {
+    let mut dropped: Vec<_> = DROPPED.lock().unwrap().drain().collect();
+    dropped.sort();
+    dropped
+}
+
+// Forget the buckets that refilled completely, they are the same as new ones
+pub fn prune() {
+    let now = Instant::now();
+    BUCKETS.lock().unwrap().retain(|_, bucket| {
+        bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * bucket.rate < bucket.burst
+    });
+}
+
+pub fn destroy(peer: &Peer) {
+    BUCKETS.lock().unwrap().retain(|(source, _), _| *source != Source::Session(*peer));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn udp(addr: &str) -> Peer {
+        Peer::Udp(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn buckets_refill_with_time_up_to_their_burst() {
+        let mut buckets = HashMap::new();
+        let key = (Source::Session(Peer::Memory(1)), "test");
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(take(&mut buckets, key, (2.0, 3.0), start));
+        }
+        assert!(!take(&mut buckets, key, (2.0, 3.0), start));
+
+        // Half a second gives one token back at 2 per second
+        let later = start + Duration::from_millis(500);
+        assert!(take(&mut buckets, key, (2.0, 3.0), later));
+        assert!(!take(&mut buckets, key, (2.0, 3.0), later));
+
+        // A long pause never gives more than the burst
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(take(&mut buckets, key, (2.0, 3.0), much_later));
+        }
+        assert!(!take(&mut buckets, key, (2.0, 3.0), much_later));
+    }
+
+    #[test]
+    fn messages_over_the_session_limit_are_dropped() {
+        let peer = udp("10.0.4.1:1000");
+        let burst = CONST::RATE_NAME.1 as usize;
+        for _ in 0..burst {
+            assert!(allow(&peer, "9"));
+        }
+        assert!(!allow(&peer, "9"));
+
+        // Each message type has its own bucket
+        assert!(allow(&peer, "2"));
+        assert!(take_dropped().contains(&("name", 1)));
+        destroy(&peer);
+        assert!(allow(&peer, "9"));
+        destroy(&peer);
+    }
+
+    #[test]
+    fn sessions_of_one_address_share_its_limit() {
+        let ip_burst = CONST::RATE_CONNECT_PER_IP.1 as usize;
+        let session_burst = CONST::RATE_CONNECT.1 as usize;
+        let peers: Vec<Peer> = (0..ip_burst).map(|port| udp(&format!("10.0.4.2:{}", 2000 + port))).collect();
+
+        let allowed = peers.iter()
+            .flat_map(|peer| std::iter::repeat_n(peer, session_burst))
+            .filter(|peer| allow(peer, "0"))
+            .count();
+        assert_eq!(allowed, ip_burst);
+
+        // Another address is not affected
+        assert!(allow(&udp("10.0.4.3:1000"), "0"));
+    }
+
+    #[test]
+    fn handshakes_have_their_own_limit() {
+        let peer = udp("10.0.4.4:1000");
+        for _ in 0..CONST::RATE_HANDSHAKE.1 as usize {
+            assert!(allow_handshake(&peer));
+        }
+        assert!(!allow_handshake(&peer));
+        assert!(allow(&peer, "0"));
+    }
+}
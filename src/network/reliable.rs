@@ -0,0 +1,181 @@
+// Reliable ordered channel for event messages over UDP.
+//
+// A reliable packet is sent as "$30,<seq>" followed by its messages. The client
+// acknowledges it with "17,<seq>" and hands packets to the game in sequence order,
+// buffering the ones that arrive early. Until it is acknowledged, a packet is sent
+// again with an exponential backoff. A peer that leaves a packet unacknowledged
+// after RELIABLE_MAX_RETRIES has missed an event for good and is disconnected.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use crate::game::constants as CONST;
+
+pub struct PendingPacket {
+    pub data: Vec<u8>,
+    pub sent_at: Instant,
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+pub struct ReliableChannel {
+    pub next_seq: u32,
+    pub pending: BTreeMap<u32, PendingPacket>,
+}
+
+static CHANNELS: Lazy<Mutex<HashMap<SocketAddr, ReliableChannel>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Peers that never acknowledged a packet, until they are disconnected
+static FAILED: Lazy<Mutex<Vec<SocketAddr>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Number a packet for a peer and keep it until it is acknowledged,
+// returns the data to put on the wire
+pub fn wrap(addr: SocketAddr, data: Vec<u8>) -> Vec<u8> {
+    let mut channels = CHANNELS.lock().unwrap();
+    let channel = channels.entry(addr).or_insert_with(|| ReliableChannel {
+        next_seq: 1,
+        pending: BTreeMap::new(),
+    });
+
+    let seq = channel.next_seq;
+    channel.next_seq = channel.next_seq.wrapping_add(1);
+
+    let mut wire = format!("{}{}{}", CONST::COMM_START_NEW_MESS, CONST::COMM_RELIABLE, seq).into_bytes();
+    wire.extend_from_slice(&data);
+
+    channel.pending.insert(seq, PendingPacket {
+        data: wire.clone(),
+        sent_at: Instant::now(),
+        timeout: Duration::from_millis(CONST::RELIABLE_TIMEOUT),
+        retries: 0,
+    });
+
+    wire
+}
+
+pub fn ack(addr: &SocketAddr, seq: u32) {
+    let mut channels = CHANNELS.lock().unwrap();
+    if let Some(channel) = channels.get_mut(addr) {
+        channel.pending.remove(&seq);
+    }
+}
+
+// Packets whose acknowledgement is overdue, to be sent again. The channel of a peer
+// that ran out of retries is dropped, and the peer reported by take_failed.
+pub fn due_retransmits() -> Vec<(SocketAddr, Vec<u8>)> {
+    let mut channels = CHANNELS.lock().unwrap();
+    let mut due = Vec::new();
+    let mut failed = Vec::new();
+
+    for (addr, channel) in channels.iter_mut() {
+        let mut given_up = false;
+        channel.pending.retain(|seq, packet| {
+            if given_up || packet.sent_at.elapsed() < packet.timeout {
+                return true;
+            }
+
+            if packet.retries >= CONST::RELIABLE_MAX_RETRIES {
+                println!("Reliable packet {} to {} was never acknowledged", seq, addr);
+                given_up = true;
+                return true;
+            }
+
+            packet.retries += 1;
+            packet.sent_at = Instant::now();
+            packet.timeout = Duration::min(packet.timeout * 2, Duration::from_millis(CONST::RELIABLE_MAX_TIMEOUT));
+            due.push((*addr, packet.data.clone()));
+            true
+        });
+        if given_up {
+            failed.push(*addr);
+        }
+    }
+
+    for addr in &failed {
+        channels.remove(addr);
+        due.retain(|(due_addr, _)| due_addr != addr);
+    }
+    FAILED.lock().unwrap().extend(failed);
+    due
+}
+
+// Peers that ran out of retries since the previous call
+pub fn take_failed() -> Vec<SocketAddr> {
+    std::mem::take(&mut *FAILED.lock().unwrap())
+}
+
+pub fn destroy(addr: &SocketAddr) {
+    CHANNELS.lock().unwrap().remove(addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Retransmits go through all channels, so tests take turns
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    // Make the packets of a peer overdue
+    fn expire(addr: &SocketAddr) {
+        let mut channels = CHANNELS.lock().unwrap();
+        for packet in channels.get_mut(addr).unwrap().pending.values_mut() {
+            packet.sent_at = Instant::now() - packet.timeout;
+        }
+    }
+
+    fn due_to(addr: &SocketAddr) -> Vec<Vec<u8>> {
+        due_retransmits().into_iter()
+            .filter(|(due_addr, _)| due_addr == addr)
+            .map(|(_, data)| data)
+            .collect()
+    }
+
+    #[test]
+    fn packets_are_sent_again_until_acknowledged() {
+        let _serial = SERIAL.lock().unwrap();
+        let addr: SocketAddr = "10.0.2.1:1000".parse().unwrap();
+        let first = wrap(addr, b"$7,1".to_vec());
+        let second = wrap(addr, b"$7,2".to_vec());
+        assert_eq!(first, b"$30,1$7,1");
+        assert_eq!(second, b"$30,2$7,2");
+        assert!(due_to(&addr).is_empty());
+
+        expire(&addr);
+        assert_eq!(due_to(&addr), vec![first.clone(), second.clone()]);
+        assert!(due_to(&addr).is_empty());
+        let timeout = CHANNELS.lock().unwrap()[&addr].pending[&1].timeout;
+        assert_eq!(timeout, Duration::from_millis(2 * CONST::RELIABLE_TIMEOUT));
+
+        ack(&addr, 1);
+        expire(&addr);
+        assert_eq!(due_to(&addr), vec![second]);
+
+        ack(&addr, 2);
+        expire(&addr);
+        assert!(due_to(&addr).is_empty());
+        destroy(&addr);
+    }
+
+    #[test]
+    fn peers_that_never_acknowledge_are_given_up() {
+        let _serial = SERIAL.lock().unwrap();
+        let addr: SocketAddr = "10.0.2.2:1000".parse().unwrap();
+        wrap(addr, b"$7,1".to_vec());
+
+        for _ in 0..CONST::RELIABLE_MAX_RETRIES {
+            expire(&addr);
+            assert_eq!(due_to(&addr).len(), 1);
+            assert!(!take_failed().contains(&addr));
+        }
+        let timeout = CHANNELS.lock().unwrap()[&addr].pending[&1].timeout;
+        assert_eq!(timeout, Duration::from_millis(CONST::RELIABLE_MAX_TIMEOUT));
+
+        expire(&addr);
+        assert!(due_to(&addr).is_empty());
+        assert_eq!(take_failed(), vec![addr]);
+        assert!(!CHANNELS.lock().unwrap().contains_key(&addr));
+        assert!(take_failed().is_empty());
+    }
+}
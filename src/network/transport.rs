@@ -0,0 +1,62 @@
+// Transports carry messages between the game and its clients. The game only sees
+// peers and messages: UDP, WebSocket and in-memory clients all look the same to it.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use futures_util::future::{self, BoxFuture};
+use futures_util::FutureExt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Udp(SocketAddr),
+    // Id of the connection given by the WebSocket listener, and the client's address
+    WebSocket(u64, IpAddr),
+    // Id of a client of an in-memory transport
+    Memory(u64),
+    // Id of a snake played by the server, see game::bots. No transport owns it.
+    Bot(u64),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Udp(addr) => write!(f, "udp://{}", addr),
+            Peer::WebSocket(id, _) => write!(f, "ws#{}", id),
+            Peer::Memory(id) => write!(f, "memory#{}", id),
+            Peer::Bot(id) => write!(f, "bot#{}", id),
+        }
+    }
+}
+
+impl Peer {
+    // Address the peer connects from, if it has one
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Udp(addr) => Some(addr.ip()),
+            Peer::WebSocket(_, ip) => Some(*ip),
+            Peer::Memory(_) | Peer::Bot(_) => None,
+        }
+    }
+
+    pub fn is_bot(&self) -> bool {
+        matches!(self, Peer::Bot(_))
+    }
+}
+
+pub trait Transport: Send + Sync {
+    // Whether the peer is a client of this transport
+    fn owns(&self, peer: &Peer) -> bool;
+
+    // Send a message to a peer. Reliable messages must reach the peer even if
+    // the transport itself may lose them.
+    fn send(&self, peer: Peer, data: Vec<u8>, reliable: bool) -> BoxFuture<'_, ()>;
+
+    // Wait for the next message from any peer, None once the transport is closed
+    fn recv(&self) -> BoxFuture<'_, Option<(Peer, Vec<u8>)>>;
+
+    // Wait for the next peer whose connection closed, after its last message was
+    // returned by recv. Transports without connections never report any.
+    fn closed(&self) -> BoxFuture<'_, Option<Peer>> {
+        future::pending().boxed()
+    }
+}
@@ -0,0 +1,134 @@
+// UDP transport: reliable messages go through the reliable channel, messages too
+// large for one datagram are fragmented both ways, and clients that asked for it
+// get their datagrams sealed, see network::crypto
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex as TokioMutex;
+use tokio::time::{self, Duration};
+use crate::game::constants as CONST;
+use crate::network::crypto;
+use crate::network::fragment;
+use crate::network::rate_limit;
+use crate::network::reliable;
+use crate::network::transport::{Peer, Transport};
+
+pub struct UdpTransport {
+    socket: Arc<UdpSocket>,
+    // One byte more than the largest datagram accepted, so larger ones are detected instead of truncated
+    buf: TokioMutex<Vec<u8>>,
+}
+
+impl UdpTransport {
+    pub async fn bind(addr: &str) -> io::Result<UdpTransport> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+
+        // Resend the reliable packets that were not acknowledged in time
+        let resend_socket = socket.clone();
+        tokio::spawn(async move {
+            let mut resend_interval = time::interval(Duration::from_millis(CONST::RELIABLE_RESEND_CHECK_DELAY));
+            loop {
+                resend_interval.tick().await;
+                for (addr, data) in reliable::due_retransmits() {
+                    for datagram in fragment::split(data) {
+                        let _ = resend_socket.send_to(&crypto::seal(&addr, datagram), addr).await;
+                    }
+                }
+            }
+        });
+
+        Ok(UdpTransport {
+            socket,
+            buf: TokioMutex::new(vec![0u8; CONST::MAX_CLIENT_DATAGRAM + crypto::OVERHEAD + 1]),
+        })
+    }
+
+    // Start an encrypted session if a connect message carries a "key=" option. The
+    // server key is sent in clear, before the game answers the connect message.
+    // Returns false if the client asked for a session it cannot have, and the
+    // message must be dropped rather than played in clear.
+    async fn handshake(&self, addr: SocketAddr, data: &[u8]) -> bool {
+        let message = String::from_utf8_lossy(data);
+        let mut splitted = message.split(',');
+        if splitted.next() != Some("0") {
+            return true;
+        }
+        let Some(client_key) = splitted.find_map(|option| option.trim().strip_prefix("key=")) else {
+            return true;
+        };
+        if !rate_limit::allow_handshake(&Peer::Udp(addr)) {
+            return false;
+        }
+
+        match crypto::handshake(addr, client_key) {
+            Ok(server_key) => {
+                let reply = format!("{}{}{}", CONST::COMM_START_NEW_MESS, CONST::COMM_SESSION_KEY, server_key);
+                let _ = self.socket.send_to(reply.as_bytes(), addr).await;
+                println!("Encrypted session started with {}", addr);
+                true
+            }
+            Err(reason) => {
+                println!("Refused encrypted session from {}: {}", addr, reason);
+                false
+            }
+        }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn owns(&self, peer: &Peer) -> bool {
+        matches!(peer, Peer::Udp(_))
+    }
+
+    fn send(&self, peer: Peer, data: Vec<u8>, reliable: bool) -> BoxFuture<'_, ()> {
+        async move {
+            if let Peer::Udp(addr) = peer {
+                let data = if reliable {
+                    reliable::wrap(addr, data)
+                } else {
+                    data
+                };
+                for datagram in fragment::split(data) {
+                    let _ = self.socket.send_to(&crypto::seal(&addr, datagram), addr).await;
+                }
+            }
+        }.boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<(Peer, Vec<u8>)>> {
+        async move {
+            let mut buf = self.buf.lock().await;
+            loop {
+                let (size, addr) = match self.socket.recv_from(&mut buf).await {
+                    Ok(received) => received,
+                    Err(e) => {
+                        println!("Error: failed to receive packet: {}", e);
+                        continue;
+                    }
+                };
+
+                if size == 0 {
+                    println!("Error: no data received.");
+                    continue;
+                }
+
+                let had_session = crypto::has_session(&addr);
+                let Some(data) = crypto::open(&addr, &buf[..size]) else {
+                    continue;
+                };
+
+                if !had_session && !self.handshake(addr, &data).await {
+                    continue;
+                }
+
+                if let Some(message) = fragment::receive(addr, &data) {
+                    return Some((Peer::Udp(addr), message));
+                }
+            }
+        }.boxed()
+    }
+}
@@ -0,0 +1,274 @@
+// WebSocket transport for browser clients, which cannot speak raw UDP. Every WebSocket
+// message is handled like a UDP datagram, and the game sends back the same messages
+// it sends UDP clients, one per WebSocket message. A closed connection is reported
+// as closed, so the player leaves at once instead of timing out. A client too slow
+// to take the messages sent to it is closed rather than losing some of them, since
+// the reliable events go through the same connection.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tokio::sync::Mutex as TokioMutex;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use crate::game::constants as CONST;
+use crate::network::transport::{Peer, Transport};
+
+struct Connection {
+    // Outgoing messages
+    messages: mpsc::Sender<Vec<u8>>,
+    // Stops reading from the client when it is closed by the server
+    closing: Arc<Notify>,
+}
+
+// Open connections, keyed by connection id
+type Connections = Arc<Mutex<HashMap<u64, Connection>>>;
+
+// Messages received from the clients, None once a connection closed
+type Inbox = mpsc::UnboundedSender<(Peer, Option<Vec<u8>>)>;
+
+pub struct WebSocketTransport {
+    local_addr: SocketAddr,
+    connections: Connections,
+    inbox: TokioMutex<mpsc::UnboundedReceiver<(Peer, Option<Vec<u8>>)>>,
+    closed_tx: mpsc::UnboundedSender<Peer>,
+    closed_rx: TokioMutex<mpsc::UnboundedReceiver<Peer>>,
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, connections: Connections, inbox: Inbox) {
+    // Client messages are no larger than a reassembled UDP message
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(CONST::FRAGMENT_MAX_MESSAGE))
+        .max_frame_size(Some(CONST::FRAGMENT_MAX_MESSAGE));
+    let ws_stream = match tokio_tungstenite::accept_async_with_config(stream, Some(config)).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            println!("WebSocket handshake failed: {}", e);
+            return;
+        }
+    };
+
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let peer = Peer::WebSocket(id, addr.ip());
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(CONST::WEBSOCKET_QUEUE_SIZE);
+    let closing = Arc::new(Notify::new());
+    connections.lock().unwrap().insert(id, Connection {
+        messages: out_tx,
+        closing: closing.clone(),
+    });
+    println!("WebSocket client {} connected", peer);
+
+    // Forward the game's messages to the client
+    let writer = tokio::spawn(async move {
+        while let Some(data) = out_rx.recv().await {
+            let message = match String::from_utf8(data) {
+                Ok(text) => Message::text(text),
+                Err(e) => Message::binary(e.into_bytes()),
+            };
+            if ws_sender.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = ws_sender.close().await;
+    });
+
+    loop {
+        let message = tokio::select! {
+            message = ws_receiver.next() => message,
+            _ = closing.notified() => {
+                println!("WebSocket client {} fell too far behind", peer);
+                break;
+            }
+        };
+        let Some(message) = message else { break };
+        match message {
+            Ok(Message::Text(text)) => {
+                let _ = inbox.send((peer, Some(text.as_bytes().to_vec())));
+            }
+            Ok(Message::Binary(data)) => {
+                let _ = inbox.send((peer, Some(data.to_vec())));
+            }
+            Ok(Message::Close(_)) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+
+    // A closed connection is a player leaving, no need to wait for the timeout
+    connections.lock().unwrap().remove(&id);
+    writer.abort();
+    let _ = inbox.send((peer, None));
+    println!("WebSocket client {} disconnected", peer);
+}
+
+impl WebSocketTransport {
+    // Start accepting WebSocket clients
+    pub async fn bind(addr: &str) -> io::Result<WebSocketTransport> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let connections: Connections = Arc::new(Mutex::new(HashMap::new()));
+        let (inbox_tx, inbox_rx) = mpsc::unbounded_channel();
+        let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+
+        let listener_connections = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        tokio::spawn(handle_connection(stream, addr, listener_connections.clone(), inbox_tx.clone()));
+                    }
+                    Err(e) => println!("Error: failed to accept WebSocket client: {}", e),
+                }
+            }
+        });
+
+        Ok(WebSocketTransport {
+            local_addr,
+            connections,
+            inbox: TokioMutex::new(inbox_rx),
+            closed_tx,
+            closed_rx: TokioMutex::new(closed_rx),
+        })
+    }
+
+    // Address the listener is bound to, with the port picked if it was bound to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn owns(&self, peer: &Peer) -> bool {
+        matches!(peer, Peer::WebSocket(..))
+    }
+
+    // WebSocket is reliable and keeps messages whole already. A client too far
+    // behind is closed rather than stalling everyone else.
+    fn send(&self, peer: Peer, data: Vec<u8>, _reliable: bool) -> BoxFuture<'_, ()> {
+        async move {
+            if let Peer::WebSocket(id, _) = peer {
+                let mut connections = self.connections.lock().unwrap();
+                if let Some(connection) = connections.get(&id) {
+                    if connection.messages.try_send(data).is_err() {
+                        connection.closing.notify_one();
+                        connections.remove(&id);
+                    }
+                }
+            }
+        }.boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<(Peer, Vec<u8>)>> {
+        async move {
+            let mut inbox = self.inbox.lock().await;
+            loop {
+                match inbox.recv().await? {
+                    (peer, Some(data)) => return Some((peer, data)),
+                    (peer, None) => {
+                        let _ = self.closed_tx.send(peer);
+                    }
+                }
+            }
+        }.boxed()
+    }
+
+    fn closed(&self) -> BoxFuture<'_, Option<Peer>> {
+        async move {
+            self.closed_rx.lock().await.recv().await
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{timeout, Duration};
+
+    async fn wait<T>(future: impl std::future::Future<Output = T>) -> T {
+        timeout(Duration::from_secs(5), future).await.expect("timed out")
+    }
+
+    #[tokio::test]
+    async fn messages_are_framed_one_per_websocket_message() {
+        let transport = WebSocketTransport::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", transport.local_addr());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        client.send(Message::text("2,1000,1000")).await.unwrap();
+        client.send(Message::binary(b"16".to_vec())).await.unwrap();
+        let (peer, first) = wait(transport.recv()).await.unwrap();
+        let (same_peer, second) = wait(transport.recv()).await.unwrap();
+        assert!(matches!(peer, Peer::WebSocket(..)) && transport.owns(&peer));
+        assert_eq!(same_peer, peer);
+        assert_eq!(first, b"2,1000,1000");
+        assert_eq!(second, b"16");
+
+        // Text goes back as text, anything else as binary
+        transport.send(peer, b"$1,a$5,b".to_vec(), true).await;
+        transport.send(peer, vec![b'~', 0xff], false).await;
+        assert_eq!(wait(client.next()).await.unwrap().unwrap(), Message::text("$1,a$5,b"));
+        assert_eq!(wait(client.next()).await.unwrap().unwrap(), Message::binary(vec![b'~', 0xff]));
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_reported_after_their_messages() {
+        let transport = WebSocketTransport::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", transport.local_addr());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        client.send(Message::text("15")).await.unwrap();
+        client.close(None).await.unwrap();
+        let (peer, last) = wait(transport.recv()).await.unwrap();
+        assert_eq!(last, b"15");
+
+        // The close is only seen once recv gets past it
+        tokio::select! {
+            _ = transport.recv() => panic!("no message after the close"),
+            closed = transport.closed() => assert_eq!(closed, Some(peer)),
+        }
+
+        // Messages for a closed connection are dropped
+        assert!(transport.connections.lock().unwrap().is_empty());
+        transport.send(peer, b"$1,a".to_vec(), false).await;
+    }
+
+    #[tokio::test]
+    async fn clients_too_far_behind_are_closed() {
+        let transport = WebSocketTransport::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", transport.local_addr());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        client.send(Message::text("16")).await.unwrap();
+        let (peer, _) = wait(transport.recv()).await.unwrap();
+
+        // The writer gets no chance to run in between, so the queue fills up
+        for _ in 0..=CONST::WEBSOCKET_QUEUE_SIZE {
+            transport.send(peer, b"$7,1".to_vec(), true).await;
+        }
+        assert!(transport.connections.lock().unwrap().is_empty());
+        tokio::select! {
+            _ = transport.recv() => panic!("no message after the close"),
+            closed = transport.closed() => assert_eq!(closed, Some(peer)),
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_close_the_connection() {
+        let transport = WebSocketTransport::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", transport.local_addr());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        client.send(Message::text("9,".repeat(CONST::FRAGMENT_MAX_MESSAGE))).await.unwrap();
+        tokio::select! {
+            _ = transport.recv() => panic!("the message must not get through"),
+            closed = wait(transport.closed()) => assert!(matches!(closed, Some(Peer::WebSocket(..)))),
+        }
+    }
+}
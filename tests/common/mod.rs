@@ -0,0 +1,128 @@
+// Test harness: runs the game server on an in-memory transport in a background
+// thread and gives tests fake clients to talk to it
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::thread;
+use once_cell::sync::Lazy;
+use slither_io_server::game::{bots, game_server};
+use slither_io_server::network::compression;
+use slither_io_server::network::memory::{MemoryClient, MemoryTransport};
+use slither_io_server::network::transport::{Peer, Transport};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{self, Duration, Instant};
+
+pub struct TestServer {
+    transport: Arc<MemoryTransport>,
+    // The game state is global, so tests sharing the server take turns
+    lock: Mutex<()>,
+}
+
+pub struct TestClient {
+    client: MemoryClient,
+    // Messages received compressed so far
+    pub compressed: usize,
+    // Messages that came in the same datagram as the last one returned
+    pending: VecDeque<String>,
+}
+
+static SERVER: Lazy<TestServer> = Lazy::new(|| {
+    // Tests add the bots they need
+    bots::set_target_population(0);
+
+    let transport = Arc::new(MemoryTransport::new());
+    let server_transport: Arc<dyn Transport> = transport.clone();
+
+    thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(game_server::serve(vec![server_transport]));
+    });
+
+    TestServer {
+        transport,
+        lock: Mutex::new(()),
+    }
+});
+
+// The server shared by all the tests of a test binary
+pub fn server() -> &'static TestServer {
+    &SERVER
+}
+
+impl TestServer {
+    // Hold the returned guard for the duration of a test
+    pub async fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().await
+    }
+
+    pub fn connect(&self) -> TestClient {
+        TestClient {
+            client: self.transport.connect(),
+            compressed: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // Connect n clients and create a player for each of them
+    pub async fn spawn_players(&self, n: usize) -> Vec<TestClient> {
+        let mut clients = Vec::new();
+        for _ in 0..n {
+            let mut client = self.connect();
+            client.send("0");
+            client.expect("1,").await;
+            clients.push(client);
+        }
+        clients
+    }
+}
+
+impl TestClient {
+    pub fn send(&self, message: &str) {
+        self.client.send(message.as_bytes());
+    }
+
+    // Wait for a message starting with the given command (without the "$"),
+    // skipping everything else, and return it without the "$"
+    pub async fn wait_for(&mut self, command: &str, timeout: Duration) -> Option<String> {
+        while let Some(message) = self.pending.pop_front() {
+            if message.starts_with(command) {
+                return Some(message);
+            }
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut data = time::timeout_at(deadline, self.client.recv()).await.ok()??;
+            if data.first() == Some(&compression::MARKER) {
+                data = lz4_flex::block::decompress_size_prepended_with_dict(&data[1..], compression::DICTIONARY)
+                    .expect("invalid compressed message");
+                self.compressed += 1;
+            }
+            let text = String::from_utf8_lossy(&data).to_string();
+            let mut messages = text.split('$');
+            if let Some(message) = messages.find(|message| message.starts_with(command)) {
+                self.pending.extend(messages.map(str::to_string));
+                return Some(message.to_string());
+            }
+        }
+    }
+
+    // Like wait_for, but fails the test if the message does not come within 2 seconds
+    pub async fn expect(&mut self, command: &str) -> String {
+        match self.wait_for(command, Duration::from_secs(2)).await {
+            Some(message) => message,
+            None => panic!("no ${} message received", command),
+        }
+    }
+
+    pub fn peer(&self) -> Peer {
+        self.client.peer
+    }
+
+    // Show the whole map to this client and keep its snake still
+    pub fn see_everything(&self) {
+        self.send("2,5000,5000,10000,10000");
+    }
+}
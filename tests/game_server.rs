@@ -0,0 +1,603 @@
+mod common;
+
+use std::collections::HashMap;
+use common::server;
+use slither_io_server::game::{arena, bots};
+use slither_io_server::game::constants as CONST;
+use slither_io_server::models::{bait, player};
+use tokio::time::{self, Duration};
+
+#[tokio::test]
+async fn new_player_gets_its_snake_and_snapshots() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut client = server.connect();
+    client.send("0");
+
+    let snake = client.expect("1,").await;
+    assert_eq!(snake.split(',').count(), 1 + 2 * 5);
+
+    let header = client.expect("20,").await;
+    assert_eq!(header.split(',').count(), 6);
+    // Full snapshots are numbered too, the send rate depends on their acks
+    assert_ne!(header.split(',').nth(1), Some("0"));
+    client.expect("2,").await;
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn players_see_each_other_join_and_leave() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut clients = server.spawn_players(2).await;
+    clients[0].see_everything();
+    clients[1].see_everything();
+
+    let joined = clients[0].expect("5,").await;
+    assert_eq!(joined.split(',').count(), 3 + 2 * 5);
+    clients[1].expect("5,").await;
+
+    clients[1].send("15");
+    let left = clients[0].expect("7,").await;
+    assert_eq!(left.split(',').count(), 2);
+
+    clients[0].send("15");
+}
+
+#[tokio::test]
+async fn snapshots_report_the_last_input_applied() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut clients = server.spawn_players(1).await;
+    let client = &mut clients[0];
+    client.send("2,5000,5000,10000,10000,7");
+    // Stale input, must be dropped
+    client.send("2,5000,5000,10000,10000,3");
+
+    let mut last_input = None;
+    for _ in 0..10 {
+        let header = client.expect("20,").await;
+        last_input = header.split(',').nth(4).map(|seq| seq.to_string());
+        if last_input.as_deref() == Some("7") {
+            break;
+        }
+    }
+    assert_eq!(last_input.as_deref(), Some("7"));
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn ping_is_answered_with_the_server_time() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut clients = server.spawn_players(1).await;
+    clients[0].send("13,42");
+
+    let pong = clients[0].expect("25,").await;
+    let fields: Vec<&str> = pong.split(',').collect();
+    assert_eq!(fields[1], "42");
+    assert!(fields[2].parse::<u64>().is_ok());
+
+    clients[0].send("15");
+}
+
+#[tokio::test]
+async fn compression_is_used_when_asked_for() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut client = server.connect();
+    client.send("0,compress=lz4");
+    client.expect("1,").await;
+    client.expect("20,").await;
+    client.expect("2,").await;
+    assert!(client.compressed > 0);
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn invalid_inputs_are_rejected() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut clients = server.spawn_players(1).await;
+    let client = &mut clients[0];
+    client.send("2,NaN,5000,10000,10000,8");
+    client.send("2,5000,inf,10000,10000,9");
+    client.send("2,5000,5000,1e300,10000,10");
+    client.send("2,5000,5000,10000,10000,x");
+    // Accepted only if none of the inputs above was
+    client.send("2,5000,5000,10000,10000,5");
+
+    let mut last_input = None;
+    for _ in 0..10 {
+        let header = client.expect("20,").await;
+        last_input = header.split(',').nth(4).map(|seq| seq.to_string());
+        if last_input.as_deref() != Some("0") {
+            break;
+        }
+    }
+    assert_eq!(last_input.as_deref(), Some("5"));
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn names_are_validated_before_being_broadcast() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut clients = server.spawn_players(2).await;
+    clients[1].send("9,Bad,Name");
+    clients[1].send("9,$4,1");
+    clients[1].send("9,Good Name");
+
+    let name = clients[0].expect("9,").await;
+    assert!(name.ends_with(",Good Name"));
+
+    clients[0].send("15");
+    clients[1].send("15");
+}
+
+#[tokio::test]
+async fn bots_fill_the_arena_and_leave_when_not_needed() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut clients = server.spawn_players(1).await;
+    clients[0].see_everything();
+    bots::set_target_population(3);
+
+    let bot = clients[0].expect("5,").await;
+    assert!(CONST::BOT_NAMES.iter().any(|name| bot.split(',').nth(2) == Some(*name)));
+
+    bots::set_target_population(0);
+    clients[0].expect("7,").await;
+    for _ in 0..100 {
+        if bots::count() == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(bots::count(), 0);
+
+    clients[0].send("15");
+}
+
+#[tokio::test]
+async fn rooms_are_separate_arenas() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut lobby = server.spawn_players(1).await;
+    lobby[0].see_everything();
+
+    let mut room = Vec::new();
+    for _ in 0..2 {
+        let mut client = server.connect();
+        client.send("0,room=test-room");
+        client.expect("1,").await;
+        let arena = client.expect("28,").await;
+        assert_eq!(arena.split(',').nth(1), Some("test-room"));
+        client.see_everything();
+        room.push(client);
+    }
+    room[0].expect("5,").await;
+    room[1].expect("5,").await;
+
+    // Nobody joined the public arena
+    assert_eq!(lobby[0].wait_for("5,", Duration::from_millis(500)).await, None);
+
+    // Room names are checked like any other field
+    let mut client = server.connect();
+    client.send("0,room=no$way");
+    assert_eq!(client.wait_for("1,", Duration::from_millis(500)).await, None);
+
+    room[0].send("15");
+    room[1].send("15");
+    lobby[0].send("15");
+}
+
+#[tokio::test]
+async fn private_rooms_need_their_code_and_close_when_empty() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut owner = server.connect();
+    owner.send("0,create,password=s3cret,width=800,height=800,players=2,baits=50");
+    owner.expect("1,").await;
+    let arena = owner.expect("28,").await;
+    let fields: Vec<&str> = arena.split(',').collect();
+    let code = fields[1].to_string();
+    assert_eq!(code.len(), CONST::ROOM_CODE_LENGTH);
+    let width: f64 = fields[4].parse::<f64>().unwrap() - fields[2].parse::<f64>().unwrap();
+    assert_eq!(width, 800.0);
+    owner.see_everything();
+
+    // Neither the room name nor a wrong password get in
+    for attempt in [format!("0,room={}", code), format!("0,code={},password=guess", code)] {
+        let mut intruder = server.connect();
+        intruder.send(&attempt);
+        assert_eq!(intruder.wait_for("1,", Duration::from_millis(500)).await, None);
+    }
+
+    let mut guest = server.connect();
+    guest.send(&format!("0,code={},password=s3cret", code.to_lowercase()));
+    guest.expect("1,").await;
+    guest.see_everything();
+    owner.expect("5,").await;
+
+    owner.send("15");
+    guest.send("15");
+    time::sleep(Duration::from_millis(2 * CONST::HOUSEKEEPING_DELAY as u64)).await;
+
+    let mut late = server.connect();
+    late.send(&format!("0,code={},password=s3cret", code));
+    assert_eq!(late.wait_for("1,", Duration::from_millis(500)).await, None);
+}
+
+#[tokio::test]
+async fn eating_a_bait_leaves_the_baits_of_other_arenas_alone() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut rooms = Vec::new();
+    for _ in 0..2 {
+        let mut client = server.connect();
+        client.send("0,create,baits=0");
+        client.expect("1,").await;
+        let code = client.expect("28,").await.split(',').nth(1).unwrap().to_string();
+        client.see_everything();
+        rooms.push((client, arena::find_by_code(&code).unwrap()));
+    }
+    let (eater, other) = (rooms[0].1, rooms[1].1);
+    time::sleep(Duration::from_millis(100)).await;
+
+    // Two baits under the head of the first snake, and two more at the same spot in
+    // the other arena, stored after them
+    let head = player::read(player::keys_in(eater)[0]).unwrap().snake.nodes[0].clone();
+    for arena_id in [eater, eater, other, other] {
+        bait::create(arena_id, head.x, head.y, "0".to_string(), 10.0);
+    }
+
+    for _ in 0..100 {
+        if bait::length_in(eater) == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(bait::length_in(eater), 0);
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(bait::length_in(other), 2);
+
+    for (client, _) in &rooms {
+        client.send("15");
+    }
+}
+
+#[tokio::test]
+async fn bots_give_up_their_place_in_a_full_private_room() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    bots::set_target_population(10);
+    let mut owner = server.connect();
+    owner.send("0,create,players=2");
+    owner.expect("1,").await;
+    let code = owner.expect("28,").await.split(',').nth(1).unwrap().to_string();
+    let arena_id = arena::find_by_code(&code).unwrap();
+
+    // A bot takes the second place until someone with the code comes
+    for _ in 0..100 {
+        if player::keys_in(arena_id).len() == 2 {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(player::keys_in(arena_id).len(), 2);
+
+    let mut guest = server.connect();
+    guest.send(&format!("0,code={}", code));
+    guest.expect("1,").await;
+    for _ in 0..100 {
+        if player::keys_in(arena_id).len() == 2 {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(player::keys_in(arena_id).len(), 2);
+    assert_eq!(arena::humans_in(arena_id), 2);
+
+    // Full of players now
+    let mut late = server.connect();
+    late.send(&format!("0,code={}", code));
+    assert_eq!(late.wait_for("1,", Duration::from_millis(500)).await, None);
+
+    bots::set_target_population(0);
+    owner.send("15");
+    guest.send("15");
+    for _ in 0..100 {
+        if bots::count() == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(bots::count(), 0);
+}
+
+#[tokio::test]
+async fn team_mode_splits_players_and_reports_team_scores() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut owner = server.connect();
+    // No baits, so the snakes keep their length
+    owner.send("0,create,teams=2,baits=0");
+    owner.expect("1,").await;
+    let code = owner.expect("28,").await.split(',').nth(1).unwrap().to_string();
+    let own_team = owner.expect("29,").await;
+    owner.see_everything();
+
+    let mut guest = server.connect();
+    guest.send(&format!("0,code={}", code));
+    guest.expect("1,").await;
+    let guest_team = guest.expect("29,").await;
+
+    // Teams are balanced and each wears its own color
+    let fields = |message: &str| message.split(',').skip(2).map(str::to_string).collect::<Vec<String>>();
+    assert_ne!(fields(&own_team), fields(&guest_team));
+
+    // The owner learns the team of the guest along with its snake
+    owner.expect("5,").await;
+    assert_eq!(fields(&owner.expect("29,").await), fields(&guest_team));
+
+    let scores = owner.expect("32,").await;
+    let scores: Vec<usize> = scores.split(',').skip(1).map(|score| score.parse().unwrap()).collect();
+    assert_eq!(scores, vec![CONST::SNAKE_INITIAL_LENGTH; 2]);
+
+    owner.send("15");
+    guest.send("15");
+}
+
+#[tokio::test]
+async fn timed_rounds_end_with_results_and_a_new_world() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut client = server.connect();
+    client.send(&format!("0,create,round={}", CONST::ROUND_MIN_DURATION));
+    client.expect("1,").await;
+    client.send("9,Winner");
+
+    let countdown = client.expect("33,").await;
+    let seconds: u64 = countdown.split(',').nth(1).unwrap().parse().unwrap();
+    assert!(seconds <= CONST::ROUND_COUNTDOWN);
+
+    let timeout = Duration::from_secs(CONST::ROUND_MIN_DURATION + 2);
+    let results = client.wait_for("34,", timeout).await.expect("no round results");
+    let fields: Vec<&str> = results.split(',').collect();
+    assert_eq!(fields[1], "1");
+    assert_eq!(fields[6], "1");
+    assert_eq!(fields[8], "Winner");
+
+    let start = client.expect("35,").await;
+    assert_eq!(start.split(',').nth(1), Some("2"));
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn battle_royale_zone_shrinks_and_is_broadcast() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut client = server.connect();
+    client.send(&format!("0,create,royale={}", CONST::ROYALE_MIN_SHRINK));
+    client.expect("1,").await;
+    let map: Vec<f64> = client.expect("28,").await.split(',').skip(2).take(4).map(|v| v.parse().unwrap()).collect();
+
+    let zone = |message: String| message.split(',').skip(1).map(|v| v.parse().unwrap()).collect::<Vec<f64>>();
+    let first = zone(client.expect("36,").await);
+    let second = zone(client.expect("36,").await);
+
+    // The zone stays on the map and shrinks towards a final zone inside it
+    assert!(first[0] >= map[0] && first[1] >= map[1] && first[2] <= map[2] && first[3] <= map[3]);
+    assert!(second[2] - second[0] < first[2] - first[0]);
+    assert!((second[6] - second[4] - CONST::ROYALE_FINAL_ZONE).abs() < 0.01);
+    assert!(second[4] >= second[0] && second[6] <= second[2]);
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn border_kills_or_wraps_snakes_heading_out() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut killed = server.connect();
+    killed.send("0,create,border=kill,width=400,height=400");
+    killed.expect("1,").await;
+    assert_eq!(killed.expect("28,").await.split(',').nth(6), Some("kill"));
+
+    let mut wrapped = server.connect();
+    wrapped.send("0,create,border=wrap,width=400,height=400");
+    wrapped.expect("1,").await;
+    assert_eq!(wrapped.expect("28,").await.split(',').nth(6), Some("wrap"));
+
+    // Both head straight right
+    killed.send("2,10000,5000,10000,10000");
+    wrapped.send("2,10000,5000,10000,10000");
+
+    assert!(killed.wait_for("8", Duration::from_secs(5)).await.is_some());
+
+    // The wrapping snake comes back in on the left instead, alive
+    let mut last_x = f64::MIN;
+    let mut came_back = false;
+    for _ in 0..200 {
+        let Some(own) = wrapped.wait_for("2,", Duration::from_secs(1)).await else {
+            break;
+        };
+        let x: f64 = own.split(',').nth(1).unwrap().parse().unwrap();
+        if x < last_x - 200.0 {
+            came_back = true;
+            break;
+        }
+        last_x = x;
+    }
+    assert!(came_back);
+    assert_eq!(wrapped.wait_for("8", Duration::from_millis(100)).await, None);
+
+    killed.send("15");
+    wrapped.send("15");
+}
+
+#[tokio::test]
+async fn circular_arenas_keep_snakes_inside_the_circle() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    // A wrapping border only makes sense on a rectangle
+    let mut refused = server.connect();
+    refused.send("0,create,shape=circle,border=wrap");
+    assert_eq!(refused.wait_for("1,", Duration::from_millis(500)).await, None);
+
+    let mut client = server.connect();
+    client.send("0,create,shape=circle,width=400,height=400");
+    let spawned = client.expect("1,").await;
+    let arena = client.expect("28,").await;
+    let fields: Vec<&str> = arena.split(',').collect();
+    assert_eq!(fields[7], "circle");
+    let bounds: Vec<f64> = fields[2..6].iter().map(|v| v.parse().unwrap()).collect();
+    let (center_x, center_y) = ((bounds[0] + bounds[2]) / 2.0, (bounds[1] + bounds[3]) / 2.0);
+    let radius = (bounds[2] - bounds[0]) / 2.0;
+    let distance = |message: &str| {
+        let head: Vec<f64> = message.split(',').skip(1).take(2).map(|v| v.parse().unwrap()).collect();
+        ((head[0] - center_x).powi(2) + (head[1] - center_y).powi(2)).sqrt()
+    };
+
+    // Spawned in the middle half of the circle
+    assert!(distance(&spawned) <= radius / 2.0);
+
+    // Heading right into the corner of the bounding box, held back on the circle
+    client.send("2,10000,0,10000,10000");
+    let mut furthest: f64 = 0.0;
+    for _ in 0..100 {
+        let Some(own) = client.wait_for("2,", Duration::from_secs(1)).await else {
+            break;
+        };
+        furthest = furthest.max(distance(&own));
+    }
+    assert!(furthest > radius * 0.9);
+    assert!(furthest <= radius);
+
+    client.send("15");
+}
+
+#[tokio::test]
+async fn self_collision_spares_straight_snakes_and_kills_those_turning_back() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut idle = server.connect();
+    idle.send("0,create,self_collision");
+    idle.expect("1,").await;
+
+    let mut classic = server.connect();
+    classic.send("0,create,self_collision");
+    classic.expect("1,").await;
+    let code = classic.expect("28,").await.split(',').nth(1).unwrap().to_string();
+
+    let mut usual = server.connect();
+    usual.send("0,create");
+    usual.expect("1,").await;
+    let usual_code = usual.expect("28,").await.split(',').nth(1).unwrap().to_string();
+
+    // Fresh snakes are stacked on their spawn point, then stretch out heading right
+    for client in [&mut classic, &mut usual] {
+        client.send("2,10000,5000,10000,10000");
+    }
+    assert_eq!(idle.wait_for("8", Duration::from_millis(1000)).await, None);
+    assert_eq!(classic.wait_for("8", Duration::from_millis(100)).await, None);
+
+    // A body as short as the neck folds up behind a head turning back, so grow one
+    // long enough to run into
+    for code in [&code, &usual_code] {
+        let player_id = player::keys_in(arena::find_by_code(code).unwrap())[0];
+        for _ in 0..10 {
+            player::grow_player_snake(player_id);
+        }
+    }
+    assert_eq!(classic.wait_for("8", Duration::from_millis(500)).await, None);
+
+    // Then turn right back into the body
+    for client in [&mut classic, &mut usual] {
+        client.send("2,0,5000,10000,10000");
+    }
+    assert!(classic.wait_for("8", Duration::from_secs(2)).await.is_some());
+    assert_eq!(usual.wait_for("8", Duration::from_millis(500)).await, None);
+
+    idle.send("15");
+    classic.send("15");
+    usual.send("15");
+}
+
+#[tokio::test]
+async fn delta_snapshots_rebuild_the_snake() {
+    let server = server();
+    let _guard = server.lock().await;
+
+    let mut client = server.connect();
+    client.send("0");
+    client.expect("1,").await;
+    let player_id = player::find_id_by_addr(&client.peer()).unwrap();
+
+    // Rebuild the own snake from every snapshot, acknowledging them: moving right
+    // for a while, then still until the body settled
+    let mut snapshots: HashMap<u32, Vec<(f64, f64)>> = HashMap::new();
+    let mut deltas = 0;
+    let mut rebuilt = Vec::new();
+    client.send("2,7000,5000,10000,10000");
+    for round in 0..80 {
+        if round == 40 {
+            client.see_everything();
+        }
+        let header = client.expect("20,").await;
+        let fields: Vec<&str> = header.split(',').collect();
+        let (seq, baseline): (u32, u32) = (fields[1].parse().unwrap(), fields[2].parse().unwrap());
+
+        let snake = client.expect("2").await;
+        let values: Vec<f64> = snake.split(',').skip(1).map(|value| value.parse().unwrap()).collect();
+        rebuilt = if snake.starts_with("23,") {
+            deltas += 1;
+            let base = &snapshots[&baseline];
+            values[1..].chunks(2)
+                .enumerate()
+                .map(|(i, pair)| match base.get(i) {
+                    Some(&(x, y)) => (x + pair[0] * CONST::DELTA_PRECISION, y + pair[1] * CONST::DELTA_PRECISION),
+                    None => (pair[0], pair[1]),
+                })
+                .collect()
+        } else {
+            assert!(snake.starts_with("2,"));
+            values.chunks(2).map(|pair| (pair[0], pair[1])).collect()
+        };
+        snapshots.insert(seq, rebuilt.clone());
+        client.send(&format!("12,{}", seq));
+    }
+    assert!(deltas > 60);
+
+    let nodes = player::read(player_id).unwrap().snake.nodes;
+    assert_eq!(rebuilt.len(), nodes.len());
+    for (&(x, y), node) in rebuilt.iter().zip(&nodes) {
+        assert!((x - node.x).abs() < 0.01 && (y - node.y).abs() < 0.01);
+    }
+
+    client.send("15");
+}